@@ -4,6 +4,28 @@ port = 11001
 # Whether to report details about errors to the client; default false
 expose_errors = true
 
+# Cross-origin resource sharing policy; can be overridden per bridge and per API. Default none, i.e. same-origin only
+cors {
+  # origins allowed to make credentialed requests
+  allowed_origins = [ "https://app.example.com" ]
+
+  # regular expressions matching whole allowed origins; default []
+  allowed_origin_patterns = [ "https://[a-z]+\\.example\\.com" ]
+
+  # allowed methods and request headers; default [], i.e. any
+  allowed_methods = [ "GET", "POST", "PUT", "DELETE" ]
+  allowed_headers = [ "content-type", "if-match", "x-csrf-token", "x-requested-with" ]
+
+  # response headers readable by scripts of other origins; default []
+  exposed_headers = [ "x-csrf-token" ]
+
+  # time in seconds browsers may cache preflight responses; default 3600
+  max_age = 3600
+
+  # allow ANY origin to make credentialed requests, for development only; default false
+  dev_mode = false
+}
+
 # Cryptographic keys for cookies in base64.
 key "1" {
   # Like everything in this file, this can be templated from environment variables.
@@ -20,6 +42,67 @@ key "2" {
 # Minimal time in seconds an access token needs to still be valid for without getting refreshed; default 30
 clock_skew = 60
 
+# Expose Prometheus metrics at /metrics; default unset
+# metrics {
+#   # separate port for the metrics; default unset, i.e. the port above
+#   port = 9090
+# }
+
+# Export traces by OTLP over HTTP; default unset
+# tracing {
+#   # collector, which receives traces at /v1/traces below
+#   endpoint = "http://localhost:4318"
+#   # default "token-handler"
+#   service_name = "token-handler"
+#   # share of the traces starting here which are recorded; default 1.0
+#   sample_ratio = 0.1
+# }
+
+# How logs are written; default as below
+# logging {
+#   # "text" or "json"
+#   format = "text"
+#   # log [redacted] in place of usernames
+#   redact_usernames = false
+#   # log the names of query parameters, but not their values
+#   redact_queries = false
+# }
+
+# What /ready checks; default as below
+# readiness {
+#   # checks which have to pass for /ready to answer 200: "discovery", "jwks" and "upstreams"
+#   gates = [ "discovery", "jwks" ]
+#   # seconds to wait for each check
+#   timeout = 2
+#   # seconds for which the outcome of the checks is reused
+#   cache_ttl = 5
+#   # whether /ready tells how each check went, and not only whether it is ready
+#   details = false
+# }
+
+# Ids of requests, which are logged, passed on to the IDP and backends, and echoed in responses; default as below
+# request_id {
+#   header = "x-request-id"
+#   # whose ids are taken over: "any", "trusted_proxies" or "never"; all other requests get a new one
+#   trust = "any"
+# }
+
+# Listener for operators, with /config, /keys, /bridges, /ready, /build and /reload; default unset
+# admin {
+#   address = "127.0.0.1:9000"
+#   # to be presented as "Authorization: Bearer <token>"; at least 16 characters
+#   token = "change-me-to-something-long"
+#   # HTTPS for clients with a certificate issued by client_ca only
+#   tls {
+#     certificate = "/etc/token-handler/admin.pem"
+#     key = "/etc/token-handler/admin.key"
+#     client_ca = "/etc/token-handler/clients.pem"
+#   }
+# }
+
+# Reverse proxies in front of the token handler, whose forwarding headers are passed on; default []
+# trusted_proxies = [ "10.0.0.0/8" ]
+
 # A token handler can have an arbitrary number of bridges. This bridge will have endpoints
 # * /bridge/b1/login
 # * /bridge/b1/login2
@@ -40,12 +123,246 @@ bridge "b1" {
   # scopes to request; default "openid"
   scope = "openid profile email"
 
+  # on logout, the refresh token gets revoked at the IDP. Also revoke the access token; default false
+  revoke_access_token = true
+
+  # what to do when revocation fails: "continue" (log and log out anyway) or "fail"; default "continue"
+  revocation_failure = "continue"
+
+  # whether to send the ID token as id_token_hint to the IDP on logout; default true
+  logout_id_token_hint = false
+
+  # only forget the session locally instead of redirecting to the IDP's end session endpoint; default false
+  local_logout = false
+
+  # where logouts may redirect to when the IDP isn't involved, besides the token handler's own origin; default []
+  # post_logout_redirect_uris = [ "https://app.example.com/bye" ]
+
+  # absolute maximum lifetime of a session in seconds; default unlimited
+  session_max_age = 43200
+
+  # maximum time in seconds a session may go unused; default unlimited
+  session_idle_timeout = 1800
+
+  # minimal time in seconds between re-issues of the session cookie to track idle times; default 60
+  session_refresh_interval = 60
+
+  # allow persistent "remember me" sessions with this Max-Age in seconds; default unset
+  persistent_session_max_age = 604800
+
+  # attributes of the session cookie
+  cookie {
+    # name of the cookie; default "bff-session"
+    name = "b1-session"
+
+    # prefix the name with __Host-, which requires path "/" and no domain; default false
+    host_prefix = false
+
+    # Domain attribute of the cookie; default unset, i.e. only the exact host of the token handler
+    # domain = "example.com"
+
+    # Path attribute of the cookie; default "/bridge/{id}", or "/" with host_prefix
+    # path = "/bridge/b1"
+
+    # SameSite attribute of the session cookie: "strict", "lax" or "none"; default "strict"
+    same_site = "strict"
+  }
+
+  # protection of proxied requests with unsafe methods against cross-site request forgery; default none
+  csrf {
+    # require this request header; default unset
+    header = "X-Requested-With"
+
+    # require the Origin (or Sec-Fetch-Site) to be our own or one of allowed_origins; default false
+    check_origin = true
+    allowed_origins = [ "https://app.example.com" ]
+
+    # issue a token in a cookie readable by scripts, which must be echoed in a request header; default false
+    double_submit = true
+
+    # name of the cookie and request header for the token; defaults "bff-csrf" and "x-csrf-token"
+    token_cookie = "bff-csrf"
+    token_header = "x-csrf-token"
+  }
+
+  # timeouts, retries and circuit breaking of the calls to the IDP, cf. the API's
+  # idp_timeouts {
+  #   connect = 10
+  #   response_headers = 60
+  # }
+  # idp_retry {
+  #   attempts = 2
+  # }
+
   # each bridge can route an arbitrary number of backends. This one will be available under /bridge/b1/proxy/api/**
   api "api" {
     # uri where the real backend can be found
     backend = "http://localhost:11000/api"
 
+    # alternatively, several backends to balance requests among
+    # upstream {
+    #   targets = [ "http://10.0.0.1:11000/api", "http://10.0.0.2:11000/api" ]
+    #   # "round_robin", "least_connections" or "consistent_hash" by the user's sub; default "round_robin"
+    #   strategy = "round_robin"
+    #   # take targets out after failures in a row (connection errors and 5xx); default unset, i.e. never
+    #   ejection {
+    #     consecutive_failures = 5
+    #     duration = 30
+    #   }
+    #   # only use targets which answer the probe with 2xx; default unset, i.e. all are healthy
+    #   health_check {
+    #     path = "/health"
+    #     interval = 10
+    #     timeout = 2
+    #     unhealthy_threshold = 2
+    #     healthy_threshold = 2
+    #   }
+    # }
+
+    # timeouts in seconds of requests to the backend, answered with 504; null to wait indefinitely
+    timeouts {
+      connect = 10
+      response_headers = 60
+      # until the whole response has arrived; default unset
+      # total = 300
+    }
+
+    # retry failed requests without body; always after connection errors, with idempotent methods also after timeouts
+    # and the listed statuses; default unset, i.e. no retries
+    # retry {
+    #   attempts = 2
+    #   initial_backoff_ms = 100
+    #   max_backoff_ms = 2000
+    #   statuses = [ 502, 503, 504 ]
+    #   # retries as a fraction of requests
+    #   budget = 0.2
+    # }
+
+    # answer with 503 right away after failures in a row, until a request may try again; default unset
+    # circuit_breaker {
+    #   failure_threshold = 5
+    #   open_duration = 30
+    # }
+
+    # limits of streamed responses like Server-Sent Events, in seconds; default unset
+    # streaming {
+    #   # end the response without data from the backend for this long
+    #   idle_timeout = 60
+    #   # end the response after this long
+    #   max_duration = 3600
+    #   # send a comment on text/event-stream responses without data from the backend for this long
+    #   heartbeat = 15
+    # }
+
+    # close proxied WebSockets after this many seconds, at the latest when their access token expires; default unset
+    # websocket_max_lifetime = 3600
+
+    # answer requests with larger bodies with 413; default unset
+    # max_body_size = 10485760
+    # answer requests with bodies of other media types with 415; default [], i.e. any
+    # allowed_content_types = [ "application/json", "multipart/form-data", "image/*" ]
+
+    # path below each target which /ready probes; default unset, i.e. the API isn't probed
+    # readiness_probe = "/health"
+
+    # tell the backend about the client and the URL it requested; default unset
+    # forwarded {
+    #   # "forwarded" for the header of RFC 7239, "x-forwarded" for X-Forwarded-For/-Proto/-Host/-Prefix; default both
+    #   headers = [ "forwarded", "x-forwarded" ]
+    # }
+
+    # routes are tried in order; the first one matching path and method picks the backend and path, others get a 404
+    # without any, every path goes to the backend above as it is
+    route "users" {
+      # matches /users and everything below; the remainder is available as ${captures.rest}
+      prefix = "/users"
+      # default the API's backend
+      backend = "http://localhost:11001/users"
+      # default unset, i.e. the requested path
+      rewrite = "/v1${captures.rest}"
+    }
+    route "orders" {
+      # a regular expression the path has to match as a whole, as an alternative to prefix
+      regex = "/orders/(?P<id>[0-9]+)"
+      # default [], i.e. all methods
+      methods = [ "get" ]
+      rewrite = "/orders/${captures.id}/details"
+    }
+    route "rest" {
+      prefix = "/"
+    }
+
     # list of http headers to proxy forward to the API; default [ "content-type" ]
     headers = [ "content-type", "if-match" ]
+
+    # rules for request headers, applied after the list above in the order remove, rename, set
+    request_headers {
+      # values may contain ${claims.<path>} from the access token and ${env.<NAME>}, which gets redacted in logs
+      set = {
+        "X-User-Id" = "${claims.sub}"
+        "X-Tenant" = "acme"
+      }
+      rename = {
+        "If-Match" = "X-If-Match"
+      }
+      remove = []
+    }
+
+    # send these claims as signed headers instead of the access token; default unset
+    # identity {
+    #   headers = {
+    #     "X-User-Id" = "sub"
+    #     "X-User-Roles" = "realm_access.roles"
+    #   }
+    #   # in base64, shared with this backend only; never the value of one of the keys above
+    #   secret = "c2lnbmluZyBzZWNyZXQgb2YgdGhpcyBBUEkgb25seSE="
+    #   signature_header = "X-Identity-Signature"
+    # }
+
+    # list of http headers to pass from the API back to the client; default unset, i.e. all
+    # response_headers = [ "content-type", "etag" ]
+
+    # list of http headers to never pass back to the client; default [ "server", "x-powered-by" ]
+    strip_response_headers = [ "server", "x-powered-by" ]
+
+    # claim-based access rules; check them offline with `token-handler authorize <bridge> <api> <token> "<request line>"`
+    authorization {
+      # decision for requests no rule matches, "allow" or "deny"; default "allow"
+      default = "allow"
+      # dot-separated path of the claim listing the user's roles; default "realm_access.roles"
+      roles_claim = "realm_access.roles"
+
+      # rules are tried in order; the first one matching method and path decides
+      rule "admin" {
+        # `*` matches within a path segment, `**` across segments; default unset, i.e. all paths
+        path = "/admin/**"
+        roles = [ "admin" ]
+      }
+      rule "writes" {
+        # default [], i.e. all methods
+        methods = [ "post", "put", "patch", "delete" ]
+        scopes = [ "profile" ]
+        # predicates with ==, != or contains and a JSON value, or `<claim path> exists`
+        claims = [ "email_verified == true" ]
+      }
+      # rule "frozen" {
+      #   path = "/archive/**"
+      #   deny = true
+      # }
+      # rule "catalogue" {
+      #   methods = [ "get" ]
+      #   path = "/catalogue/**"
+      #   # overrides auth below for matching requests
+      #   auth = "none"
+      # }
+    }
+
+    # whether requests need a session: "required", "optional" (anonymous without one) or "none"; default "required"
+    auth = "required"
+
+    # send anonymous requests with a token of the bridge's client instead of none; default unset
+    # client_credentials {
+    #   scope = "catalogue"
+    # }
   }
 }
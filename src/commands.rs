@@ -0,0 +1,34 @@
+//! Subcommands which work on the configuration without starting the server
+
+use std::str::FromStr;
+use actix_web::http::Method;
+use serde_json::Value;
+use crate::components::config::Config;
+use crate::components::policy::Decision;
+use crate::components::route;
+use crate::systems::token::claims;
+
+/// Decides on a request line like `GET /items/1` for an API, given the claims of an access token
+///
+/// The token is either a JWT, its JSON payload or `@<file>` containing one of those.
+pub fn authorize(config: &Config, bridge: &str, api: &str, token: &str, request: &str) -> Result<Decision, String> {
+    let bridge = config.bridges.get(bridge).ok_or_else(|| format!("unknown bridge '{bridge}'"))?;
+    let api = bridge.apis.get(api).ok_or_else(|| format!("unknown API '{api}'"))?;
+    let token = match token.strip_prefix('@') {
+        Some(file) => std::fs::read_to_string(file).map_err(|e| format!("unable to read '{file}': {e}"))?,
+        None => token.into(),
+    };
+    let token = token.trim();
+    let claims = match token.starts_with('{') {
+        true => serde_json::from_str::<Value>(token).map_err(|e| format!("invalid token payload: {e}"))?,
+        false => claims::<Value>(token).map_err(|_| "expected a JWT or its JSON payload")?,
+    };
+
+    let mut parts = request.split_whitespace();
+    let (method, target) = parts.next().zip(parts.next()).ok_or("expected a request line like `GET /items/1`")?;
+    let method = Method::from_str(&method.to_uppercase()).map_err(|_| format!("invalid method '{method}'"))?;
+    let path = target.split_once('?').map_or(target, |(path, _)| path);
+    let path = route::normalize(path);
+    api.destination(&method, &path).map_err(|e| e.to_string())?;
+    Ok(api.authorize(&method, &path, &claims))
+}
@@ -0,0 +1,358 @@
+//! HTTP clients with timeouts, retries and circuit breaking, one for each backend and IDP
+
+use std::sync::Mutex;
+use std::time::Duration;
+use log::warn;
+use rand::Rng;
+use reqwest::{Client, Method, RequestBuilder, Response};
+use crate::components::spec::{CircuitBreakerSpec, RetrySpec, TimeoutsSpec};
+use crate::error::{ApiError, ConfigError, Context};
+use crate::systems::{body, logging, telemetry};
+
+/// Retries which may be spent regardless of the budget, e.g. right after startup
+const RETRY_RESERVE: f64 = 10.0;
+
+pub struct HttpClient {
+    pub reqwest: Client,
+    timeouts: TimeoutsSpec,
+    retry: Option<RetrySpec>,
+    circuit_breaker: Option<CircuitBreakerSpec>,
+    budget: Mutex<f64>,
+    circuit: Mutex<Circuit>,
+}
+
+#[derive(Clone, Copy)]
+enum Circuit {
+    Closed { failures: u16 },
+    Open { until: i64 },
+    /// A single request is trying whether the other side is back; another one may try after `until`
+    HalfOpen { until: i64 },
+}
+
+enum Failure {
+    Reqwest(reqwest::Error),
+    Timeout,
+}
+
+impl HttpClient {
+    /// A client for the bridge or API `id`
+    pub fn new(id: &str, timeouts: &TimeoutsSpec, retry: Option<&RetrySpec>, circuit_breaker: Option<&CircuitBreakerSpec>) -> Result<Self, ConfigError> {
+        let invalid = |reason| ConfigError::InvalidResilience(id.into(), reason);
+        if retry.is_some_and(|retry| !(0.0..=1.0).contains(&retry.budget)) {
+            return Err(invalid("retry budget has to be from 0 to 1"));
+        }
+        if circuit_breaker.is_some_and(|breaker| breaker.failure_threshold == 0) {
+            return Err(invalid("circuit breaker needs a failure_threshold of at least 1"));
+        }
+        if circuit_breaker.is_some_and(|breaker| breaker.open_duration == 0) {
+            return Err(invalid("circuit breaker needs an open_duration of at least 1"));
+        }
+        let builder = Client::builder();
+        let builder = match timeouts.connect {
+            Some(connect) => builder.connect_timeout(Duration::from_secs(connect as u64)),
+            None => builder,
+        };
+        Ok(HttpClient {
+            reqwest: builder.build()?,
+            timeouts: timeouts.clone(),
+            retry: retry.cloned(),
+            circuit_breaker: circuit_breaker.cloned(),
+            budget: Mutex::new(RETRY_RESERVE),
+            circuit: Mutex::new(Circuit::Closed { failures: 0 }),
+        })
+    }
+
+    /// Sends a request which can be built again for every attempt
+    pub async fn execute(&self, label: &str, method: Method, build: impl Fn() -> RequestBuilder) -> Result<Response, ApiError> {
+        self.send(label, &method, true, || Ok((build(), ())), |_, _| {}).await.map(|(response, _)| response)
+    }
+
+    /// Sends a request under the client's timeouts, retry policy and circuit breaker
+    ///
+    /// `attempt` builds the request for every attempt, along with a context like the target it goes to, which `report`
+    /// learns the outcome for. Requests which aren't `replayable`, e.g. because of a streamed body, are never retried.
+    pub async fn send<T>(
+        &self,
+        label: &str,
+        method: &Method,
+        replayable: bool,
+        mut attempt: impl FnMut() -> Result<(RequestBuilder, T), ApiError>,
+        mut report: impl FnMut(&T, bool),
+    ) -> Result<(Response, T), ApiError> {
+        let idempotent = [Method::GET, Method::HEAD, Method::OPTIONS, Method::TRACE, Method::PUT, Method::DELETE].contains(method);
+        self.deposit();
+        let mut retries = 0;
+        loop {
+            self.admit(chrono::Utc::now().timestamp())?;
+            let (request, context) = attempt()?;
+            let request = match self.timeouts.total {
+                Some(total) => request.timeout(Duration::from_secs(total as u64)),
+                None => request,
+            };
+            let (client, request) = request.build_split();
+            let mut request = request?;
+            let span = telemetry::outgoing(&mut request, retries);
+            logging::inject_request_id(request.headers_mut());
+            let result = match self.timeouts.response_headers {
+                Some(timeout) => actix_web::rt::time::timeout(Duration::from_secs(timeout as u64), client.execute(request)).await
+                    .map_or(Err(Failure::Timeout), |r| r.map_err(Failure::Reqwest)),
+                None => client.execute(request).await.map_err(Failure::Reqwest),
+            };
+            telemetry::finish(&span, match result {
+                Ok(ref response) => Ok(response.status()),
+                Err(Failure::Timeout) => Err("timeout waiting for response headers".into()),
+                Err(Failure::Reqwest(ref e)) => Err(e.to_string()),
+            });
+            let success = result.as_ref().is_ok_and(|response| !response.status().is_server_error());
+            // a request body which broke off on the client's side says nothing about the other side
+            if !matches!(result, Err(Failure::Reqwest(ref e)) if body::cause(e).is_some()) {
+                self.record(label, success, chrono::Utc::now().timestamp());
+                report(&context, success);
+            }
+            let retry = self.retry.as_ref()
+                .filter(|retry| replayable && retries < retry.attempts)
+                .is_some_and(|retry| match &result {
+                    Ok(response) => idempotent && retry.statuses.contains(&response.status().as_u16()),
+                    Err(Failure::Reqwest(e)) if e.is_connect() => true,
+                    Err(_) => idempotent,
+                });
+            if !retry || !self.withdraw() {
+                return match result {
+                    Ok(response) => Ok((response, context)),
+                    Err(Failure::Timeout) => Err(ApiError::Timeout).context("waiting for response headers"),
+                    Err(Failure::Reqwest(e)) if e.is_timeout() => Err(ApiError::Timeout).context(e.to_string()),
+                    Err(Failure::Reqwest(e)) => Err(e.into()),
+                };
+            }
+            actix_web::rt::time::sleep(self.backoff(retries)).await;
+            retries += 1;
+        }
+    }
+
+    /// Exponential backoff with jitter
+    fn backoff(&self, retries: u16) -> Duration {
+        let Some(ref retry) = self.retry else { return Duration::ZERO };
+        let backoff = (retry.initial_backoff_ms as u64)
+            .saturating_mul(1 << retries.min(16))
+            .min(retry.max_backoff_ms as u64);
+        Duration::from_millis(rand::thread_rng().gen_range(backoff / 2..=backoff))
+    }
+
+    fn deposit(&self) {
+        if let Some(ref retry) = self.retry {
+            let mut budget = self.budget.lock().unwrap();
+            *budget = (*budget + retry.budget).min(RETRY_RESERVE);
+        }
+    }
+
+    fn withdraw(&self) -> bool {
+        let mut budget = self.budget.lock().unwrap();
+        match *budget >= 1.0 {
+            true => { *budget -= 1.0; true },
+            false => false,
+        }
+    }
+
+    /// Lets a request through unless the circuit is open
+    fn admit(&self, now: i64) -> Result<(), ApiError> {
+        let Some(ref breaker) = self.circuit_breaker else { return Ok(()) };
+        let mut circuit = self.circuit.lock().unwrap();
+        match *circuit {
+            Circuit::Closed { .. } => Ok(()),
+            Circuit::Open { until } | Circuit::HalfOpen { until } if until <= now => {
+                *circuit = Circuit::HalfOpen { until: now + breaker.open_duration as i64 };
+                Ok(())
+            },
+            Circuit::Open { .. } | Circuit::HalfOpen { .. } => Err(ApiError::CircuitOpen).context("circuit is open"),
+        }
+    }
+
+    fn record(&self, label: &str, success: bool, now: i64) {
+        let Some(ref breaker) = self.circuit_breaker else { return };
+        let mut circuit = self.circuit.lock().unwrap();
+        let open = Circuit::Open { until: now + breaker.open_duration as i64 };
+        *circuit = match (*circuit, success) {
+            (Circuit::HalfOpen { .. }, true) => {
+                warn!("[{label}] circuit closed");
+                Circuit::Closed { failures: 0 }
+            },
+            (Circuit::HalfOpen { .. }, false) => {
+                warn!("[{label}] circuit opened again for {}s", breaker.open_duration);
+                open
+            },
+            (Circuit::Closed { failures }, false) if failures + 1 >= breaker.failure_threshold => {
+                warn!("[{label}] circuit opened for {}s after {} failures", breaker.open_duration, failures + 1);
+                open
+            },
+            (Circuit::Closed { failures }, false) => Circuit::Closed { failures: failures + 1 },
+            (Circuit::Closed { .. }, true) => Circuit::Closed { failures: 0 },
+            // requests which were already under way when the circuit opened
+            (Circuit::Open { until }, _) => Circuit::Open { until },
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use super::*;
+
+    const NOW: i64 = 1_700_000_000;
+
+    fn client(retry: Option<&str>, circuit_breaker: Option<&str>) -> HttpClient {
+        let retry = retry.map(|retry| hcl::from_str::<RetrySpec>(retry).unwrap());
+        let circuit_breaker = circuit_breaker.map(|breaker| hcl::from_str::<CircuitBreakerSpec>(breaker).unwrap());
+        HttpClient::new("api", &TimeoutsSpec::default(), retry.as_ref(), circuit_breaker.as_ref()).unwrap()
+    }
+
+    fn breaker() -> HttpClient {
+        client(None, Some("failure_threshold = 3\nopen_duration = 30"))
+    }
+
+    fn rejected(admitted: Result<(), ApiError>) -> bool {
+        matches!(admitted, Err(ApiError::Context(e, _)) if matches!(*e, ApiError::CircuitOpen))
+    }
+
+    fn open(client: &HttpClient) {
+        (0..3).for_each(|_| client.record("api", false, NOW));
+    }
+
+    #[test]
+    fn circuit_opens_after_failure_threshold() {
+        let client = breaker();
+        client.record("api", false, NOW);
+        client.record("api", false, NOW);
+        assert!(client.admit(NOW).is_ok());
+        // a success in between starts the count anew
+        client.record("api", true, NOW);
+        client.record("api", false, NOW);
+        client.record("api", false, NOW);
+        assert!(client.admit(NOW).is_ok());
+        client.record("api", false, NOW);
+        assert!(rejected(client.admit(NOW)));
+    }
+
+    #[test]
+    fn open_circuit_rejects_until_open_duration_passed() {
+        let client = breaker();
+        open(&client);
+        assert!(rejected(client.admit(NOW + 29)));
+        // requests which were under way don't change that
+        client.record("api", true, NOW + 29);
+        assert!(rejected(client.admit(NOW + 29)));
+        assert!(client.admit(NOW + 30).is_ok());
+    }
+
+    #[test]
+    fn half_open_circuit_allows_one_trial() {
+        let client = breaker();
+        open(&client);
+        assert!(client.admit(NOW + 30).is_ok());
+        assert!(rejected(client.admit(NOW + 31)));
+        client.record("api", true, NOW + 31);
+        assert!(client.admit(NOW + 31).is_ok());
+        assert!(client.admit(NOW + 31).is_ok());
+    }
+
+    #[test]
+    fn failed_trial_opens_circuit_again() {
+        let client = breaker();
+        open(&client);
+        assert!(client.admit(NOW + 30).is_ok());
+        client.record("api", false, NOW + 31);
+        assert!(rejected(client.admit(NOW + 60)));
+        assert!(client.admit(NOW + 61).is_ok());
+    }
+
+    #[test]
+    fn stuck_trial_lets_another_one_try() {
+        let client = breaker();
+        open(&client);
+        assert!(client.admit(NOW + 30).is_ok());
+        assert!(rejected(client.admit(NOW + 59)));
+        assert!(client.admit(NOW + 60).is_ok());
+    }
+
+    #[test]
+    fn without_circuit_breaker_everything_is_admitted() {
+        let client = client(None, None);
+        (0..100).for_each(|_| client.record("api", false, NOW));
+        assert!(client.admit(NOW).is_ok());
+    }
+
+    #[test]
+    fn retry_budget_runs_dry_and_refills_per_request() {
+        let client = client(Some("budget = 0.5"), None);
+        assert!((0..RETRY_RESERVE as usize).all(|_| client.withdraw()));
+        assert!(!client.withdraw());
+        client.deposit();
+        assert!(!client.withdraw());
+        client.deposit();
+        assert!(client.withdraw());
+        assert!(!client.withdraw());
+    }
+
+    #[test]
+    fn retry_budget_is_capped_by_reserve() {
+        let client = client(Some("budget = 1"), None);
+        (0..100).for_each(|_| client.deposit());
+        assert!((0..RETRY_RESERVE as usize).all(|_| client.withdraw()));
+        assert!(!client.withdraw());
+    }
+
+    #[test]
+    fn backoff_is_exponential_with_jitter() {
+        let client = client(Some("initial_backoff_ms = 100\nmax_backoff_ms = 1000"), None);
+        for retries in 0..20u16 {
+            let backoff = (100u64 << retries.min(16)).min(1000);
+            for _ in 0..50 {
+                let ms = client.backoff(retries).as_millis() as u64;
+                assert!((backoff / 2..=backoff).contains(&ms), "{retries}: {ms}");
+            }
+        }
+        assert_eq!(self::client(None, None).backoff(3), Duration::ZERO);
+    }
+
+    /// A port nothing listens on
+    fn closed_port() -> u16 {
+        TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port()
+    }
+
+    /// A port which answers every request with `status`
+    fn answering(status: u16) -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        std::thread::spawn(move || for mut stream in listener.incoming().flatten() {
+            let _ = stream.read(&mut [0; 4096]);
+            let _ = write!(stream, "HTTP/1.1 {status} Status\r\ncontent-length: 0\r\nconnection: close\r\n\r\n");
+        });
+        port
+    }
+
+    /// How many attempts a request to `port` takes
+    async fn attempts(method: Method, port: u16) -> usize {
+        let client = client(Some("attempts = 2\ninitial_backoff_ms = 1\nmax_backoff_ms = 1\nstatuses = [503]"), None);
+        let mut attempts = 0;
+        let _ = client.send("api", &method, true, || {
+            attempts += 1;
+            Ok((client.reqwest.request(method.clone(), format!("http://127.0.0.1:{port}/")), ()))
+        }, |_, _| {}).await;
+        attempts
+    }
+
+    #[actix_web::test]
+    async fn idempotent_requests_are_retried_after_statuses_and_connect_errors() {
+        assert_eq!(attempts(Method::GET, closed_port()).await, 3);
+        assert_eq!(attempts(Method::PUT, answering(503)).await, 3);
+        assert_eq!(attempts(Method::GET, answering(500)).await, 1);
+        assert_eq!(attempts(Method::GET, answering(200)).await, 1);
+    }
+
+    #[actix_web::test]
+    async fn other_requests_are_only_retried_after_connect_errors() {
+        assert_eq!(attempts(Method::POST, closed_port()).await, 3);
+        assert_eq!(attempts(Method::POST, answering(503)).await, 1);
+        assert_eq!(attempts(Method::PATCH, answering(503)).await, 1);
+    }
+}
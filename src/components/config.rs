@@ -1,19 +1,34 @@
-/// Server config types
+//! Server config types
 
 use base64::Engine;
 use base64::engine::general_purpose;
 use std::collections::HashMap;
 use std::str::FromStr;
 use std::sync::{Arc, RwLock, Weak};
+use itertools::Itertools;
 use log::info;
-use reqwest::Client;
+use mime::Mime;
 use reqwest::header::{HeaderName, InvalidHeaderName};
 use serde::ser::SerializeSeq;
 use serde::Serializer;
 use serde_derive::Serialize;
 use url::Url;
-use crate::components::spec::{ApiSpec, BridgeSpec, Spec};
+use actix_web::cookie::SameSite;
+use actix_cors::Cors;
+use actix_web::http::Method;
+use regex::Regex;
+use serde::ser::SerializeMap;
+use serde_json::Value;
+use crate::components::client::HttpClient;
+use crate::components::network::{serialize_networks, Network};
+use crate::components::policy::{Decision, Policy};
+use crate::components::route::{backend_url, find, Destination, Route, Routing};
+use crate::components::upstream::Upstream;
+use crate::components::spec::{AdminSpec, AdminTlsSpec, ApiSpec, AuthMode, BridgeSpec, CircuitBreakerSpec, ClientCredentialsSpec, CookieSpec, CorsSpec, CsrfSpec, ForwardedSpec, IdentitySpec, LoggingSpec, MetricsSpec, ReadinessSpec, RequestHeadersSpec, RequestIdSpec, RequestIdTrust, RetrySpec, RevocationFailure, SameSitePolicy, Spec, StreamingSpec, TimeoutsSpec, TracingSpec};
+use crate::components::template::Template;
 use crate::components::types::OpenidConfiguration;
+use crate::systems::{metrics, readiness};
+use crate::systems::token::retrieve_client_token;
 use crate::error::{ApiError, ConfigError, Context};
 
 #[derive(Serialize)]
@@ -21,14 +36,30 @@ pub struct Config {
     pub port: u16,
     pub clock_skew: u16,
     pub expose_errors: bool,
+    #[serde(skip_serializing_if = "Option::is_none", serialize_with = "serialize_optional_block")]
+    pub cors: Option<CorsConfig>,
+    #[serde(serialize_with = "serialize_networks")]
+    pub trusted_proxies: Vec<Network>,
+    #[serde(skip_serializing_if = "Option::is_none", serialize_with = "serialize_optional_block")]
+    pub metrics: Option<MetricsSpec>,
+    #[serde(skip_serializing_if = "Option::is_none", serialize_with = "serialize_optional_block")]
+    pub tracing: Option<TracingSpec>,
+    #[serde(serialize_with = "hcl::ser::block")]
+    pub logging: LoggingSpec,
+    #[serde(serialize_with = "hcl::ser::block")]
+    pub request_id: RequestIdConfig,
+    #[serde(serialize_with = "hcl::ser::block")]
+    pub readiness: ReadinessSpec,
+    #[serde(skip_serializing)]
+    pub readiness_cache: readiness::Cache,
+    #[serde(skip_serializing_if = "Option::is_none", serialize_with = "serialize_optional_block")]
+    pub admin: Option<AdminConfig>,
     #[serde(rename = "key", serialize_with = "hcl::ser::labeled_block")]
     pub keys: HashMap<String, Key>,
     #[serde(skip_serializing)]
     pub active_keys: Vec<String>,
     #[serde(skip_serializing)]
     pub log_padding: usize,
-    #[serde(skip_serializing)]
-    pub reqwest: Client,
     #[serde(rename = "bridge", serialize_with = "hcl::ser::labeled_block")]
     pub bridges: HashMap<String, Arc<Bridge>>,
 }
@@ -55,9 +86,23 @@ impl TryFrom<&Spec> for Arc<Config> {
         if active_keys.is_empty() {
             return Err(ConfigError::NoActiveKey);
         }
+        let cors = value.cors.as_ref().map(CorsConfig::new).transpose()?;
+        if let Some(ref tracing) = value.tracing {
+            Url::parse(&tracing.endpoint).map_err(|e| ConfigError::InvalidUrl(tracing.endpoint.clone(), e))?;
+        }
+        let request_id = RequestIdConfig::new(&value.request_id)?;
+        let admin = value.admin.as_ref().map(AdminConfig::new).transpose()?;
+        let trusted_proxies = value.trusted_proxies.iter()
+            .map(|network| Network::from_str(network))
+            .collect::<Result<_, ConfigError>>()?;
         let bridges = value.bridges.iter()
             .map(|(id, bridge)| BridgeBuilder::new(id, bridge))
             .collect::<Result<Vec<_>, ConfigError>>()?;
+        for (a, b) in bridges.iter().tuple_combinations() {
+            if a.cookie.collides_with(&b.cookie) {
+                return Err(ConfigError::CookieCollision(a.id.clone(), b.id.clone()));
+            }
+        }
         Ok(Arc::new_cyclic(|me| {
             Config {
                 keys,
@@ -66,7 +111,15 @@ impl TryFrom<&Spec> for Arc<Config> {
                 port: value.port,
                 log_padding,
                 expose_errors: value.expose_errors,
-                reqwest: Client::default(),
+                cors,
+                trusted_proxies,
+                metrics: value.metrics.clone(),
+                tracing: value.tracing.clone(),
+                logging: value.logging.clone(),
+                request_id,
+                readiness: value.readiness.clone(),
+                readiness_cache: Default::default(),
+                admin,
                 bridges: bridges.into_iter().map(|bridge| (bridge.id.clone(), bridge.connect(me.clone()))).collect(),
             }
         }))
@@ -85,10 +138,37 @@ pub struct Bridge {
     #[serde(serialize_with = "serialize_asterisks")]
     pub secret: String,
     pub scope: String,
+    pub revoke_access_token: bool,
+    pub revocation_failure: RevocationFailure,
+    pub logout_id_token_hint: bool,
+    pub local_logout: bool,
+    pub post_logout_redirect_uris: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub session_max_age: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub session_idle_timeout: Option<u32>,
+    pub session_refresh_interval: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub persistent_session_max_age: Option<u32>,
+    #[serde(serialize_with = "hcl::ser::block")]
+    pub cookie: CookieConfig,
+    #[serde(skip_serializing_if = "Option::is_none", serialize_with = "serialize_optional_block")]
+    pub csrf: Option<CsrfConfig>,
+    #[serde(skip_serializing_if = "Option::is_none", serialize_with = "serialize_optional_block")]
+    pub cors: Option<CorsConfig>,
+    #[serde(serialize_with = "hcl::ser::block")]
+    pub idp_timeouts: TimeoutsSpec,
+    #[serde(skip_serializing_if = "Option::is_none", serialize_with = "serialize_optional_block")]
+    pub idp_retry: Option<RetrySpec>,
+    #[serde(skip_serializing_if = "Option::is_none", serialize_with = "serialize_optional_block")]
+    pub idp_circuit_breaker: Option<CircuitBreakerSpec>,
+    /// Client for the calls to the IDP
+    #[serde(skip_serializing)]
+    pub http: HttpClient,
     #[serde(rename = "api", serialize_with = "hcl::ser::labeled_block")]
     pub apis: HashMap<String, Arc<Api>>,
     #[serde(skip_serializing)]
-    idp_configuration: RwLock<Option<Arc<OpenidConfiguration>>>,
+    idp_configuration: RwLock<Option<(Arc<OpenidConfiguration>, i64)>>,
 }
 
 pub struct BridgeBuilder {
@@ -97,8 +177,24 @@ pub struct BridgeBuilder {
     pub client_id: String,
     pub client_secret: String,
     pub scope: String,
+    pub revoke_access_token: bool,
+    pub revocation_failure: RevocationFailure,
+    pub logout_id_token_hint: bool,
+    pub local_logout: bool,
+    pub post_logout_redirect_uris: Vec<String>,
+    pub session_max_age: Option<u32>,
+    pub session_idle_timeout: Option<u32>,
+    pub session_refresh_interval: u32,
+    pub persistent_session_max_age: Option<u32>,
+    pub cookie: CookieConfig,
+    pub csrf: Option<CsrfConfig>,
+    pub cors: Option<CorsConfig>,
+    pub idp_timeouts: TimeoutsSpec,
+    pub idp_retry: Option<RetrySpec>,
+    pub idp_circuit_breaker: Option<CircuitBreakerSpec>,
+    pub http: HttpClient,
     pub apis: Vec<ApiBuilder>,
-    idp_configuration: RwLock<Option<Arc<OpenidConfiguration>>>,
+    idp_configuration: RwLock<Option<(Arc<OpenidConfiguration>, i64)>>,
 }
 
 impl BridgeBuilder {
@@ -106,6 +202,8 @@ impl BridgeBuilder {
         let apis = value.apis.iter()
             .map(|(id, api)| { ApiBuilder::new(id, api) })
             .collect::<Result<Vec<_>, _>>()?;
+        let cookie = CookieConfig::new(id, &value.cookie)?;
+        let csrf = value.csrf.as_ref().map(|csrf| CsrfConfig::new(id, csrf, &cookie)).transpose()?;
         Ok(BridgeBuilder {
             id: id.into(),
             idp_url: value.idp.clone(),
@@ -113,6 +211,22 @@ impl BridgeBuilder {
             client_id: value.client.clone(),
             client_secret: value.client_secret.clone(),
             scope: value.scope.clone(),
+            revoke_access_token: value.revoke_access_token,
+            revocation_failure: value.revocation_failure,
+            logout_id_token_hint: value.logout_id_token_hint,
+            local_logout: value.local_logout,
+            post_logout_redirect_uris: value.post_logout_redirect_uris.clone(),
+            session_max_age: value.session_max_age,
+            session_idle_timeout: value.session_idle_timeout,
+            session_refresh_interval: value.session_refresh_interval,
+            persistent_session_max_age: value.persistent_session_max_age,
+            cookie,
+            csrf,
+            cors: value.cors.as_ref().map(CorsConfig::new).transpose()?,
+            idp_timeouts: value.idp_timeouts.clone(),
+            idp_retry: value.idp_retry.clone(),
+            idp_circuit_breaker: value.idp_circuit_breaker.clone(),
+            http: HttpClient::new(id, &value.idp_timeouts, value.idp_retry.as_ref(), value.idp_circuit_breaker.as_ref())?,
             apis,
         })
     }
@@ -126,6 +240,22 @@ impl BridgeBuilder {
             client: self.client_id,
             secret: self.client_secret,
             scope: self.scope,
+            revoke_access_token: self.revoke_access_token,
+            revocation_failure: self.revocation_failure,
+            logout_id_token_hint: self.logout_id_token_hint,
+            local_logout: self.local_logout,
+            post_logout_redirect_uris: self.post_logout_redirect_uris,
+            session_max_age: self.session_max_age,
+            session_idle_timeout: self.session_idle_timeout,
+            session_refresh_interval: self.session_refresh_interval,
+            persistent_session_max_age: self.persistent_session_max_age,
+            cookie: self.cookie,
+            csrf: self.csrf,
+            cors: self.cors,
+            idp_timeouts: self.idp_timeouts,
+            idp_retry: self.idp_retry,
+            idp_circuit_breaker: self.idp_circuit_breaker,
+            http: self.http,
             apis: self.apis.into_iter().map(|api| (api.id.clone(), api.connect(me.clone()))).collect(),
         })
     }
@@ -134,24 +264,351 @@ impl BridgeBuilder {
 
 impl Bridge {
     pub fn config(&self) -> Result<Arc<Config>, ApiError> {
-        Ok(self.config.upgrade().ok_or(ApiError::Internal).context("finding config from bridge")?)
+        self.config.upgrade().ok_or(ApiError::Internal).context("finding config from bridge")
+    }
+
+    /// Prefix of log lines about the bridge, padded like those of its APIs
+    pub fn label(&self) -> Result<String, ApiError> {
+        Ok(format!("{:<width$}", self.id, width = self.config()?.log_padding))
     }
 
     pub async fn get_idp_configuration(&self) -> Result<Arc<OpenidConfiguration>, ApiError> {
-        let c = self.idp_configuration.read().unwrap();
-        match *c {
-            Some(ref config) => Ok(config.clone()),
-            None => {
-                let config = self.config()?.reqwest.get(format!("{}/.well-known/openid-configuration", self.idp))
-                    .send().await?.json::<OpenidConfiguration>().await?;
-                info!("[{:<width$}] loaded IDP configuration", self.id, width = self.config()?.log_padding);
-                drop(c);
-                let mut lock = self.idp_configuration.write().unwrap();
-                let config = lock.insert(Arc::new(config));
-                Ok(config.clone())
+        let cached = self.idp_configuration.read().unwrap().as_ref().map(|(config, _)| config.clone());
+        match cached {
+            Some(config) => Ok(config),
+            None => self.load_idp_configuration().await,
+        }
+    }
+
+    /// Fetches the IDP's discovery document anew; the one loaded before stays if that fails
+    pub async fn load_idp_configuration(&self) -> Result<Arc<OpenidConfiguration>, ApiError> {
+        let url = format!("{}/.well-known/openid-configuration", self.idp);
+        let config = metrics::idp(&self.id, "discovery", self.http.execute(&self.label()?, Method::GET, || self.http.reqwest.get(&url))).await?
+            .json::<OpenidConfiguration>().await?;
+        info!("[{:<width$}] loaded IDP configuration", self.id, width = self.config()?.log_padding);
+        let config = Arc::new(config);
+        *self.idp_configuration.write().unwrap() = Some((config.clone(), chrono::Utc::now().timestamp()));
+        Ok(config)
+    }
+
+    /// The IDP's discovery document if it is loaded, along with when it was
+    pub fn loaded_idp_configuration(&self) -> Option<(Arc<OpenidConfiguration>, i64)> {
+        self.idp_configuration.read().unwrap().clone()
+    }
+}
+
+/// Attributes of the cookie a bridge keeps its sessions in
+#[derive(Serialize)]
+pub struct CookieConfig {
+    pub name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub domain: Option<String>,
+    pub path: String,
+    pub same_site: SameSitePolicy,
+}
+
+impl CookieConfig {
+    pub fn new(bridge_id: &str, value: &CookieSpec) -> Result<Self, ConfigError> {
+        let invalid = |reason| ConfigError::InvalidCookie(bridge_id.into(), reason);
+        if value.name.is_empty() || !value.name.chars().all(|c| c.is_ascii_alphanumeric() || "!#$%&'*+-.^_`|~".contains(c)) {
+            return Err(invalid("name must be a non-empty token"));
+        }
+        if value.domain.as_ref().is_some_and(|d| d.is_empty() || d.contains(|c: char| c.is_whitespace() || c == ';')) {
+            return Err(invalid("malformed domain"));
+        }
+        if value.path.as_ref().is_some_and(|p| !p.starts_with('/') || p.contains(|c: char| c.is_whitespace() || c == ';')) {
+            return Err(invalid("path must start with '/'"));
+        }
+        let (name, path) = if value.host_prefix {
+            if value.domain.is_some() {
+                return Err(invalid("the __Host- prefix forbids setting a domain"));
+            }
+            if value.path.as_ref().is_some_and(|p| p != "/") {
+                return Err(invalid("the __Host- prefix requires path \"/\""));
             }
+            (format!("__Host-{}", value.name), String::from("/"))
+        } else {
+            if value.name.starts_with("__Host-") || value.name.starts_with("__Secure-") {
+                return Err(invalid("use host_prefix instead of naming the cookie with a prefix"));
+            }
+            (value.name.clone(), value.path.clone().unwrap_or_else(|| format!("/bridge/{}", bridge_id)))
+        };
+        Ok(CookieConfig { name, domain: value.domain.clone(), path, same_site: value.same_site })
+    }
+
+    /// SameSite attribute of the session cookie
+    pub fn same_site(&self) -> SameSite {
+        match self.same_site {
+            SameSitePolicy::Strict => SameSite::Strict,
+            SameSitePolicy::Lax => SameSite::Lax,
+            SameSitePolicy::None => SameSite::None,
+        }
+    }
+
+    /// SameSite attribute of the cookie during login, which must survive the redirect back from the IDP
+    pub fn login_same_site(&self) -> SameSite {
+        match self.same_site {
+            SameSitePolicy::None => SameSite::None,
+            _ => SameSite::Lax,
+        }
+    }
+
+    /// Whether a browser could send both cookies on the same request, which would leave us unable to tell them apart
+    pub fn collides_with(&self, other: &CookieConfig) -> bool {
+        fn path_matches(cookie_path: &str, path: &str) -> bool {
+            path.starts_with(cookie_path)
+                && (cookie_path.ends_with('/') || path.len() == cookie_path.len() || path[cookie_path.len()..].starts_with('/'))
+        }
+        self.name == other.name && (path_matches(&self.path, &other.path) || path_matches(&other.path, &self.path))
+    }
+}
+
+/// Cross-site request forgery protection of a bridge
+#[derive(Serialize)]
+pub struct CsrfConfig {
+    #[serde(skip_serializing_if = "Option::is_none", serialize_with = "serialize_optional_header_name")]
+    pub header: Option<HeaderName>,
+    pub check_origin: bool,
+    pub allowed_origins: Vec<String>,
+    pub double_submit: bool,
+    pub token_cookie: String,
+    #[serde(serialize_with = "serialize_header_name")]
+    pub token_header: HeaderName,
+}
+
+impl CsrfConfig {
+    /// The token cookie is set on path `/` and the domain of the session cookie, `cookie`
+    pub fn new(bridge_id: &str, value: &CsrfSpec, cookie: &CookieConfig) -> Result<Self, ConfigError> {
+        let invalid = |reason| ConfigError::InvalidCookie(bridge_id.into(), reason);
+        if value.token_cookie == cookie.name {
+            return Err(invalid("the CSRF token cookie must not be named like the session cookie"));
+        }
+        if value.token_cookie.starts_with("__Host-") && (cookie.domain.is_some() || cookie.path != "/") {
+            return Err(invalid("the __Host- prefix of the CSRF token cookie requires a session cookie with path \"/\" and no domain"));
+        }
+        Ok(CsrfConfig {
+            header: value.header.as_ref()
+                .map(|h| HeaderName::from_lowercase(h.to_lowercase().as_bytes()))
+                .transpose()?,
+            check_origin: value.check_origin,
+            allowed_origins: value.allowed_origins.iter()
+                .map(|o| Url::parse(o)
+                    .map(|u| u.origin().ascii_serialization())
+                    .map_err(|e| ConfigError::InvalidUrl(o.clone(), e)))
+                .collect::<Result<Vec<_>, _>>()?,
+            double_submit: value.double_submit,
+            token_cookie: value.token_cookie.clone(),
+            token_header: HeaderName::from_lowercase(value.token_header.to_lowercase().as_bytes())?,
+        })
+    }
+}
+
+#[derive(Serialize, PartialEq)]
+pub struct AdminConfig {
+    pub address: String,
+    #[serde(skip_serializing_if = "Option::is_none", serialize_with = "serialize_asterisks")]
+    pub token: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none", serialize_with = "serialize_optional_block")]
+    pub tls: Option<AdminTlsSpec>,
+}
+
+impl AdminConfig {
+    pub fn new(value: &AdminSpec) -> Result<Self, ConfigError> {
+        if value.token.is_none() && value.tls.is_none() {
+            return Err(ConfigError::InvalidAdmin("set a token, tls, or both"));
+        }
+        if value.token.as_ref().is_some_and(|token| token.len() < 16) {
+            return Err(ConfigError::InvalidAdmin("token must be at least 16 characters"));
+        }
+        Ok(AdminConfig { address: value.address.clone(), token: value.token.clone(), tls: value.tls.clone() })
+    }
+}
+
+#[derive(Serialize, Clone, PartialEq)]
+pub struct RequestIdConfig {
+    #[serde(serialize_with = "serialize_header_name")]
+    pub header: HeaderName,
+    pub trust: RequestIdTrust,
+}
+
+impl RequestIdConfig {
+    pub fn new(value: &RequestIdSpec) -> Result<Self, ConfigError> {
+        Ok(RequestIdConfig {
+            header: HeaderName::from_lowercase(value.header.to_lowercase().as_bytes())?,
+            trust: value.trust,
+        })
+    }
+}
+
+/// Cross-origin resource sharing policy of the global config, a bridge or an API
+#[derive(Serialize)]
+pub struct CorsConfig {
+    pub allowed_origins: Vec<String>,
+    #[serde(serialize_with = "serialize_regexes")]
+    pub allowed_origin_patterns: Vec<Regex>,
+    #[serde(serialize_with = "serialize_methods")]
+    pub allowed_methods: Vec<Method>,
+    #[serde(serialize_with = "serialize_header_names")]
+    pub allowed_headers: Vec<HeaderName>,
+    #[serde(serialize_with = "serialize_header_names")]
+    pub exposed_headers: Vec<HeaderName>,
+    pub max_age: u32,
+    pub dev_mode: bool,
+}
+
+impl CorsConfig {
+    pub fn new(value: &CorsSpec) -> Result<Self, ConfigError> {
+        if value.allowed_origins.is_empty() && value.allowed_origin_patterns.is_empty() && !value.dev_mode {
+            return Err(ConfigError::NoCorsOrigin);
+        }
+        Ok(CorsConfig {
+            allowed_origins: value.allowed_origins.iter()
+                .map(|o| Url::parse(o)
+                    .map(|u| u.origin().ascii_serialization())
+                    .map_err(|e| ConfigError::InvalidUrl(o.clone(), e)))
+                .collect::<Result<Vec<_>, _>>()?,
+            allowed_origin_patterns: value.allowed_origin_patterns.iter()
+                .map(|p| Regex::new(&format!("^(?:{p})$")))
+                .collect::<Result<Vec<_>, _>>()?,
+            allowed_methods: value.allowed_methods.iter()
+                .map(|m| Method::from_str(&m.to_uppercase()).map_err(|_| ConfigError::InvalidMethod(m.clone())))
+                .collect::<Result<Vec<_>, _>>()?,
+            allowed_headers: header_names(&value.allowed_headers)?,
+            exposed_headers: header_names(&value.exposed_headers)?,
+            max_age: value.max_age,
+            dev_mode: value.dev_mode,
+        })
+    }
+
+    /// Creates a middleware enforcing this policy
+    pub fn middleware(&self) -> Cors {
+        let cors = match self.dev_mode {
+            true => Cors::default().allow_any_origin(),
+            false => {
+                let patterns = self.allowed_origin_patterns.clone();
+                let cors = self.allowed_origins.iter().fold(Cors::default(), |cors, origin| cors.allowed_origin(origin));
+                match patterns.is_empty() {
+                    true => cors,
+                    false => cors.allowed_origin_fn(move |origin, _| origin.to_str()
+                        .is_ok_and(|origin| patterns.iter().any(|p| p.is_match(origin)))),
+                }
+            },
+        };
+        let cors = match self.allowed_methods.is_empty() {
+            true => cors.allow_any_method(),
+            false => cors.allowed_methods(self.allowed_methods.clone()),
+        };
+        let cors = match self.allowed_headers.is_empty() {
+            true => cors.allow_any_header(),
+            false => cors.allowed_headers(self.allowed_headers.clone()),
+        };
+        let cors = match self.exposed_headers.is_empty() {
+            true => cors,
+            false => cors.expose_headers(self.exposed_headers.clone()),
+        };
+        cors.supports_credentials().max_age(self.max_age as usize)
+    }
+}
+
+fn serialize_regexes<S>(input: &[Regex], ser: S) -> Result<S::Ok, S::Error> where S: Serializer {
+    let mut seq = ser.serialize_seq(Some(input.len()))?;
+    for i in input.iter() {
+        // strip the anchors we added
+        seq.serialize_element(&i.as_str()[4..i.as_str().len() - 2])?;
+    }
+    seq.end()
+}
+
+fn serialize_methods<S>(input: &[Method], ser: S) -> Result<S::Ok, S::Error> where S: Serializer {
+    let mut seq = ser.serialize_seq(Some(input.len()))?;
+    for i in input.iter() {
+        seq.serialize_element(i.as_str())?;
+    }
+    seq.end()
+}
+
+/// Request header manipulation of an API
+#[derive(Serialize)]
+pub struct RequestHeaderRules {
+    #[serde(serialize_with = "serialize_header_templates")]
+    pub set: Vec<(HeaderName, Template)>,
+    #[serde(serialize_with = "serialize_header_renames")]
+    pub rename: Vec<(HeaderName, HeaderName)>,
+    #[serde(serialize_with = "serialize_header_names")]
+    pub remove: Vec<HeaderName>,
+}
+
+impl RequestHeaderRules {
+    pub fn new(value: &RequestHeadersSpec) -> Result<Self, ConfigError> {
+        let header_name = |name: &String| HeaderName::from_lowercase(name.to_lowercase().as_bytes());
+        Ok(RequestHeaderRules {
+            set: value.set.iter()
+                .map(|(name, template)| Ok((header_name(name)?, Template::new(template)?)))
+                .collect::<Result<Vec<_>, ConfigError>>()?,
+            rename: value.rename.iter()
+                .map(|(from, to)| Ok((header_name(from)?, header_name(to)?)))
+                .collect::<Result<Vec<_>, ConfigError>>()?,
+            remove: header_names(&value.remove)?,
+        })
+    }
+
+    /// Whether rendering the rules needs the claims of the access token
+    pub fn needs_claims(&self) -> bool {
+        self.set.iter().any(|(_, template)| !template.is_static())
+    }
+}
+
+fn serialize_header_templates<S>(input: &[(HeaderName, Template)], ser: S) -> Result<S::Ok, S::Error> where S: Serializer {
+    let mut map = ser.serialize_map(Some(input.len()))?;
+    for (name, template) in input.iter() {
+        map.serialize_entry(name.as_str(), template)?;
+    }
+    map.end()
+}
+
+fn serialize_header_renames<S>(input: &[(HeaderName, HeaderName)], ser: S) -> Result<S::Ok, S::Error> where S: Serializer {
+    let mut map = ser.serialize_map(Some(input.len()))?;
+    for (from, to) in input.iter() {
+        map.serialize_entry(from.as_str(), to.as_str())?;
+    }
+    map.end()
+}
+
+/// Signed identity headers in place of the bearer token
+#[derive(Serialize)]
+pub struct IdentityConfig {
+    #[serde(serialize_with = "serialize_claim_headers")]
+    pub headers: Vec<(HeaderName, String)>,
+    #[serde(serialize_with = "serialize_asterisks")]
+    pub secret: Vec<u8>,
+    #[serde(serialize_with = "serialize_header_name")]
+    pub signature_header: HeaderName,
+}
+
+impl IdentityConfig {
+    pub fn new(api_id: &str, value: &IdentitySpec) -> Result<Self, ConfigError> {
+        let invalid = |reason| ConfigError::InvalidIdentity(api_id.into(), reason);
+        let secret = general_purpose::STANDARD.decode(&value.secret)
+            .map_err(|_| invalid("secret must be base64"))?;
+        if secret.len() < 32 {
+            return Err(invalid("secret must be at least 32 bytes"));
         }
+        Ok(IdentityConfig {
+            headers: value.headers.iter()
+                .map(|(name, claim)| Ok((HeaderName::from_lowercase(name.to_lowercase().as_bytes())?, claim.clone())))
+                .collect::<Result<Vec<_>, ConfigError>>()?,
+            secret,
+            signature_header: HeaderName::from_lowercase(value.signature_header.to_lowercase().as_bytes())?,
+        })
+    }
+}
+
+fn serialize_claim_headers<S>(input: &[(HeaderName, String)], ser: S) -> Result<S::Ok, S::Error> where S: Serializer {
+    let mut map = ser.serialize_map(Some(input.len()))?;
+    for (name, claim) in input.iter() {
+        map.serialize_entry(name.as_str(), claim)?;
     }
+    map.end()
 }
 
 #[derive(Serialize)]
@@ -160,12 +617,53 @@ pub struct Api {
     pub bridge: Weak<Bridge>,
     #[serde(skip_serializing)]
     pub id: String,
-    pub backend: Url,
+    #[serde(serialize_with = "hcl::ser::block")]
+    pub upstream: Upstream,
     #[serde(serialize_with = "serialize_header_names")]
     pub headers: Vec<HeaderName>,
+    #[serde(skip_serializing_if = "Option::is_none", serialize_with = "serialize_optional_header_names")]
+    pub response_headers: Option<Vec<HeaderName>>,
+    #[serde(serialize_with = "serialize_header_names")]
+    pub strip_response_headers: Vec<HeaderName>,
+    #[serde(serialize_with = "hcl::ser::block")]
+    pub request_headers: RequestHeaderRules,
+    #[serde(skip_serializing_if = "Option::is_none", serialize_with = "serialize_optional_block")]
+    pub identity: Option<IdentityConfig>,
+    #[serde(skip_serializing_if = "Option::is_none", serialize_with = "serialize_optional_block")]
+    pub cors: Option<CorsConfig>,
+    #[serde(serialize_with = "hcl::ser::block")]
+    pub authorization: Policy,
+    pub auth: AuthMode,
+    #[serde(skip_serializing_if = "Option::is_none", serialize_with = "serialize_optional_block")]
+    pub client_credentials: Option<ClientCredentialsSpec>,
+    #[serde(skip_serializing)]
+    client_token: RwLock<Option<(Arc<str>, i64)>>,
+    #[serde(rename = "route", serialize_with = "hcl::ser::labeled_block")]
+    pub routes: hcl::Map<String, Route>,
+    #[serde(serialize_with = "hcl::ser::block")]
+    pub timeouts: TimeoutsSpec,
+    #[serde(skip_serializing_if = "Option::is_none", serialize_with = "serialize_optional_block")]
+    pub retry: Option<RetrySpec>,
+    #[serde(skip_serializing_if = "Option::is_none", serialize_with = "serialize_optional_block")]
+    pub circuit_breaker: Option<CircuitBreakerSpec>,
+    /// Client for the requests to the backend
+    #[serde(skip_serializing)]
+    pub http: HttpClient,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub websocket_max_lifetime: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none", serialize_with = "serialize_optional_block")]
+    pub streaming: Option<StreamingSpec>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_body_size: Option<u64>,
+    #[serde(serialize_with = "serialize_content_types")]
+    pub allowed_content_types: Vec<Mime>,
+    #[serde(skip_serializing_if = "Option::is_none", serialize_with = "serialize_optional_block")]
+    pub forwarded: Option<ForwardedSpec>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub readiness_probe: Option<String>,
 }
 
-fn serialize_header_names<S>(input: &Vec<HeaderName>, ser: S) -> Result<S::Ok, S::Error> where S: Serializer {
+fn serialize_header_names<S>(input: &[HeaderName], ser: S) -> Result<S::Ok, S::Error> where S: Serializer {
     let mut seq = ser.serialize_seq(Some(input.len()))?;
     for i in input.iter() {
         seq.serialize_element(i.as_str())?;
@@ -173,41 +671,209 @@ fn serialize_header_names<S>(input: &Vec<HeaderName>, ser: S) -> Result<S::Ok, S
     seq.end()
 }
 
+fn serialize_optional_header_names<S>(input: &Option<Vec<HeaderName>>, ser: S) -> Result<S::Ok, S::Error> where S: Serializer {
+    match input {
+        Some(names) => serialize_header_names(names, ser),
+        None => ser.serialize_none(),
+    }
+}
+
+fn serialize_content_types<S>(input: &[Mime], ser: S) -> Result<S::Ok, S::Error> where S: Serializer {
+    let mut seq = ser.serialize_seq(Some(input.len()))?;
+    for i in input.iter() {
+        seq.serialize_element(i.as_ref())?;
+    }
+    seq.end()
+}
+
+fn serialize_header_name<S>(input: &HeaderName, ser: S) -> Result<S::Ok, S::Error> where S: Serializer {
+    ser.serialize_str(input.as_str())
+}
+
+fn serialize_optional_header_name<S>(input: &Option<HeaderName>, ser: S) -> Result<S::Ok, S::Error> where S: Serializer {
+    match input {
+        Some(name) => ser.serialize_str(name.as_str()),
+        None => ser.serialize_none(),
+    }
+}
+
+pub fn serialize_optional_block<S, T>(input: &Option<T>, ser: S) -> Result<S::Ok, S::Error> where S: Serializer, T: serde::Serialize {
+    match input {
+        Some(value) => hcl::ser::block(value, ser),
+        None => ser.serialize_none(),
+    }
+}
+
 pub struct ApiBuilder {
     pub id: String,
-    pub backend: Url,
+    pub upstream: Upstream,
     pub headers: Vec<HeaderName>,
+    pub response_headers: Option<Vec<HeaderName>>,
+    pub strip_response_headers: Vec<HeaderName>,
+    pub request_headers: RequestHeaderRules,
+    pub identity: Option<IdentityConfig>,
+    pub cors: Option<CorsConfig>,
+    pub authorization: Policy,
+    pub auth: AuthMode,
+    pub client_credentials: Option<ClientCredentialsSpec>,
+    pub routes: hcl::Map<String, Route>,
+    pub timeouts: TimeoutsSpec,
+    pub retry: Option<RetrySpec>,
+    pub circuit_breaker: Option<CircuitBreakerSpec>,
+    pub http: HttpClient,
+    pub websocket_max_lifetime: Option<u32>,
+    pub streaming: Option<StreamingSpec>,
+    pub max_body_size: Option<u64>,
+    pub allowed_content_types: Vec<Mime>,
+    pub forwarded: Option<ForwardedSpec>,
+    pub readiness_probe: Option<String>,
 }
 
 impl ApiBuilder {
     pub fn new(id: &str, value: &ApiSpec) -> Result<Self, ConfigError> {
-        let mut backend = value.backend.clone();
-        if !backend.ends_with("/") {
-            backend.push('/');
-        }
         Ok(ApiBuilder {
             id: id.into(),
-            backend: Url::from_str(&backend)
-                .map_err(|p| ConfigError::InvalidUrl(backend, p))?,
-            headers: value.headers.iter()
-                .map(|x| x.to_lowercase())
-                .map(|x| HeaderName::from_lowercase(x.as_bytes()))
-                .collect::<Result<Vec<_>, InvalidHeaderName>>()?,
+            upstream: match (&value.backend, &value.upstream) {
+                (Some(backend), None) => Upstream::single(backend_url(backend)?),
+                (None, Some(upstream)) => Upstream::new(id, upstream)?,
+                _ => return Err(ConfigError::InvalidUpstream(id.into(), "needs either backend or upstream")),
+            },
+            headers: header_names(&value.headers)?,
+            response_headers: value.response_headers.as_deref().map(header_names).transpose()?,
+            strip_response_headers: header_names(&value.strip_response_headers)?,
+            request_headers: RequestHeaderRules::new(&value.request_headers)?,
+            identity: value.identity.as_ref().map(|identity| IdentityConfig::new(id, identity)).transpose()?,
+            cors: value.cors.as_ref().map(CorsConfig::new).transpose()?,
+            authorization: Policy::new(&value.authorization)?,
+            auth: value.auth,
+            client_credentials: value.client_credentials.clone(),
+            routes: value.routes.iter()
+                .map(|(name, route)| Ok((name.clone(), Route::new(name, route)?)))
+                .collect::<Result<_, ConfigError>>()?,
+            timeouts: value.timeouts.clone(),
+            retry: value.retry.clone(),
+            circuit_breaker: value.circuit_breaker.clone(),
+            http: HttpClient::new(id, &value.timeouts, value.retry.as_ref(), value.circuit_breaker.as_ref())?,
+            websocket_max_lifetime: value.websocket_max_lifetime,
+            streaming: value.streaming.clone(),
+            max_body_size: value.max_body_size,
+            allowed_content_types: value.allowed_content_types.iter()
+                .map(|t| Mime::from_str(t).map_err(|_| ConfigError::InvalidContentType(t.clone())))
+                .collect::<Result<_, ConfigError>>()?,
+            forwarded: value.forwarded.clone(),
+            readiness_probe: value.readiness_probe.clone(),
         })
     }
 
     pub fn connect(self, bridge: Weak<Bridge>) -> Arc<Api> {
-        Arc::new(Api { bridge, id: self.id, backend: self.backend, headers: self.headers })
+        Arc::new(Api {
+            bridge,
+            id: self.id,
+            upstream: self.upstream,
+            headers: self.headers,
+            response_headers: self.response_headers,
+            strip_response_headers: self.strip_response_headers,
+            request_headers: self.request_headers,
+            identity: self.identity,
+            cors: self.cors,
+            authorization: self.authorization,
+            auth: self.auth,
+            client_credentials: self.client_credentials,
+            client_token: RwLock::new(None),
+            routes: self.routes,
+            timeouts: self.timeouts,
+            retry: self.retry,
+            circuit_breaker: self.circuit_breaker,
+            http: self.http,
+            websocket_max_lifetime: self.websocket_max_lifetime,
+            streaming: self.streaming,
+            max_body_size: self.max_body_size,
+            allowed_content_types: self.allowed_content_types,
+            forwarded: self.forwarded,
+            readiness_probe: self.readiness_probe,
+        })
     }
 
 }
 
 impl Api {
+    /// Whether a response header of the backend may be passed on to the client
+    pub fn forwards_response_header(&self, name: &HeaderName) -> bool {
+        self.response_headers.as_ref().is_none_or(|allowed| allowed.contains(name))
+            && !self.strip_response_headers.contains(name)
+    }
+
+    /// Whether a request body of the given media type may be passed on to the backend
+    pub fn accepts_content_type(&self, content_type: Option<&Mime>) -> bool {
+        if self.allowed_content_types.is_empty() {
+            return true;
+        }
+        content_type.is_some_and(|content_type| self.allowed_content_types.iter().any(|allowed| {
+            allowed.type_() == content_type.type_()
+                && (allowed.subtype() == mime::STAR || allowed.subtype() == content_type.subtype())
+        }))
+    }
+
+    /// Where a request to `path` below the API goes, as normalised by `route::normalize`
+    pub fn destination(&self, method: &Method, path: &str) -> Result<Destination<'_>, ApiError> {
+        if self.routes.is_empty() {
+            return Ok(Destination { upstream: &self.upstream, path: path.into(), rewritten: false });
+        }
+        match find(&self.routes, method, path) {
+            Routing::Found(route, captures) => Ok(route.destination(&self.upstream, path, &captures)),
+            Routing::MethodNotAllowed => Err(ApiError::MethodNotAllowed).context(format!("No route for {method} {path}")),
+            Routing::NotFound => Err(ApiError::NotFound).context(format!("No route for {path}")),
+        }
+    }
+
+    /// Decides on a request to `path` below the API, as normalised by `route::normalize`
+    pub fn authorize(&self, method: &Method, path: &str, claims: &Value) -> Decision {
+        self.authorization.evaluate(method, path, claims)
+    }
+
+    /// How a request has to be authenticated, by the first rule matching it or else the API's setting
+    pub fn auth(&self, method: &Method, path: &str) -> AuthMode {
+        self.authorization.auth(method, path).unwrap_or(self.auth)
+    }
+
+    /// The client's own token for requests without a session, if configured; cached until shortly before it expires
+    pub async fn get_client_token(&self, now: i64) -> Result<Option<Arc<str>>, ApiError> {
+        let Some(ref client_credentials) = self.client_credentials else {
+            return Ok(None);
+        };
+        let bridge = self.bridge()?;
+        let config = bridge.config()?;
+        let cached = self.client_token.read().unwrap().clone();
+        match cached {
+            Some((token, exp)) if exp - now >= config.clock_skew as i64 => Ok(Some(token)),
+            _ => {
+                let response = retrieve_client_token(&bridge, client_credentials.scope.as_deref()).await
+                    .context("retrieving client token")?;
+                info!("[{:<width$}::{}] obtained client token", bridge.id, self.id, width = config.log_padding - self.id.len() - 2);
+                let token: Arc<str> = response.access_token.into();
+                *self.client_token.write().unwrap() = Some((token.clone(), now + response.expires_in as i64));
+                Ok(Some(token))
+            }
+        }
+    }
+
     pub fn bridge(&self) -> Result<Arc<Bridge>, ApiError> {
-        Ok(self.bridge.upgrade().ok_or(ApiError::Internal).context("finding bridge from API")?)
+        self.bridge.upgrade().ok_or(ApiError::Internal).context("finding bridge from API")
+    }
+
+    /// Prefix of log lines about the API
+    pub fn label(&self) -> Result<String, ApiError> {
+        let bridge = self.bridge()?;
+        Ok(format!("{:<width$}::{}", bridge.id, self.id, width = bridge.config()?.log_padding - self.id.len() - 2))
     }
 }
 
+fn header_names(names: &[String]) -> Result<Vec<HeaderName>, InvalidHeaderName> {
+    names.iter()
+        .map(|x| HeaderName::from_lowercase(x.to_lowercase().as_bytes()))
+        .collect()
+}
+
 #[derive(Serialize)]
 pub struct Key {
     #[serde(serialize_with = "serialize_asterisks")]
@@ -219,3 +885,118 @@ fn serialize_asterisks<S, T>(_: T, ser: S) -> Result<S::Ok, S::Error> where S: S
     ser.serialize_str("*****")
 }
 
+
+#[cfg(test)]
+mod tests {
+    use crate::components::spec::Spec;
+    use super::*;
+
+    fn cookie(spec: &str) -> Result<CookieConfig, ConfigError> {
+        CookieConfig::new("b1", &hcl::from_str::<CookieSpec>(spec).unwrap())
+    }
+
+    fn csrf(spec: &str, cookie_spec: &str) -> Result<CsrfConfig, ConfigError> {
+        CsrfConfig::new("b1", &hcl::from_str::<CsrfSpec>(spec).unwrap(), &cookie(cookie_spec).unwrap())
+    }
+
+    #[test]
+    fn csrf_token_cookie_must_differ_from_session_cookie() {
+        assert!(csrf(r#"token_cookie = "bff-csrf""#, "").is_ok());
+        assert!(matches!(csrf(r#"token_cookie = "bff-session""#, ""), Err(ConfigError::InvalidCookie(..))));
+        assert!(matches!(csrf(r#"token_cookie = "sid""#, r#"name = "sid""#), Err(ConfigError::InvalidCookie(..))));
+        assert!(matches!(csrf(r#"token_cookie = "__Host-sid""#, "name = \"sid\"\nhost_prefix = true"), Err(ConfigError::InvalidCookie(..))));
+    }
+
+    #[test]
+    fn csrf_token_cookie_with_host_prefix_requires_root_path_without_domain() {
+        assert!(csrf(r#"token_cookie = "__Host-csrf""#, r#"path = "/""#).is_ok());
+        assert!(csrf(r#"token_cookie = "__Host-csrf""#, "host_prefix = true").is_ok());
+        assert!(matches!(csrf(r#"token_cookie = "__Host-csrf""#, ""), Err(ConfigError::InvalidCookie(..))));
+        assert!(matches!(csrf(r#"token_cookie = "__Host-csrf""#, r#"path = "/app""#), Err(ConfigError::InvalidCookie(..))));
+        assert!(matches!(
+            csrf(r#"token_cookie = "__Host-csrf""#, "path = \"/\"\ndomain = \"example.com\""),
+            Err(ConfigError::InvalidCookie(..)),
+        ));
+        assert!(csrf(r#"token_cookie = "__Secure-csrf""#, r#"domain = "example.com""#).is_ok());
+    }
+
+    fn invalid_cookie(spec: &str) -> bool {
+        matches!(cookie(spec), Err(ConfigError::InvalidCookie(..)))
+    }
+
+    #[test]
+    fn cookie_names_must_be_tokens() {
+        assert_eq!(cookie("").ok().map(|cookie| (cookie.name, cookie.path)), Some(("bff-session".into(), "/bridge/b1".into())));
+        assert!(invalid_cookie(r#"name = """#));
+        assert!(invalid_cookie(r#"name = "a b""#));
+        assert!(invalid_cookie(r#"name = "a;b""#));
+        assert!(invalid_cookie(r#"path = "app""#));
+        assert!(invalid_cookie(r#"domain = "example.com; Path=/""#));
+    }
+
+    #[test]
+    fn prefixes_are_only_set_by_host_prefix() {
+        assert!(invalid_cookie(r#"name = "__Host-sid""#));
+        assert!(invalid_cookie(r#"name = "__Secure-sid""#));
+        let cookie = cookie("name = \"sid\"\nhost_prefix = true").unwrap();
+        assert_eq!((cookie.name.as_str(), cookie.path.as_str(), cookie.domain), ("__Host-sid", "/", None));
+    }
+
+    #[test]
+    fn host_prefix_requires_root_path_and_no_domain() {
+        assert!(cookie("host_prefix = true\npath = \"/\"").is_ok());
+        assert!(invalid_cookie("host_prefix = true\npath = \"/app\""));
+        assert!(invalid_cookie("host_prefix = true\ndomain = \"example.com\""));
+    }
+
+    fn colliding(a: &str, b: &str) -> bool {
+        cookie(a).unwrap().collides_with(&cookie(b).unwrap())
+    }
+
+    #[test]
+    fn cookies_collide_if_sent_together() {
+        assert!(colliding("", ""));
+        assert!(colliding(r#"path = "/""#, r#"path = "/app""#));
+        assert!(colliding(r#"path = "/app""#, r#"path = "/""#));
+        assert!(colliding(r#"path = "/app""#, r#"path = "/app/b1""#));
+        assert!(colliding(r#"path = "/app/""#, r#"path = "/app/b1""#));
+        assert!(!colliding(r#"path = "/app""#, r#"path = "/apps""#));
+        assert!(!colliding(r#"path = "/app/b1""#, r#"path = "/app/b2""#));
+        assert!(!colliding(r#"name = "a""#, r#"name = "b""#));
+        assert!(!colliding("name = \"sid\"\nhost_prefix = true", r#"name = "sid""#));
+    }
+
+    fn bridges(cookie1: &str, cookie2: &str) -> Result<Arc<Config>, ConfigError> {
+        let bridge = |id: &str, cookie: &str| format!(r#"
+            bridge "{id}" {{
+              idp = "http://idp"
+              client = "client"
+              secret = "secret"
+              cookie {{
+                {cookie}
+              }}
+              api "api" {{
+                backend = "http://backend"
+              }}
+            }}
+        "#);
+        let spec = hcl::from_str::<Spec>(&format!(r#"
+            key "1" {{
+              value = "TnVyIGVpbiBCZWlzcGllbCwgbmljaHQgYmVudXR6ZW4="
+              active = true
+            }}
+            {}
+            {}
+        "#, bridge("b1", cookie1), bridge("b2", cookie2))).unwrap();
+        Arc::<Config>::try_from(&spec)
+    }
+
+    #[test]
+    fn bridges_must_not_share_a_session_cookie() {
+        assert!(bridges("", "").is_ok());
+        assert!(bridges(r#"name = "a""#, r#"name = "b""#).is_ok());
+        assert!(matches!(bridges(r#"path = "/""#, ""), Err(ConfigError::CookieCollision(..))));
+        assert!(matches!(bridges("host_prefix = true", "host_prefix = true"), Err(ConfigError::CookieCollision(..))));
+        assert!(bridges("host_prefix = true", r#"name = "other""#).is_ok());
+    }
+}
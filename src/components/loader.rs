@@ -0,0 +1,30 @@
+//! Loading of the configuration file, with environment variables substituted
+
+use std::sync::Arc;
+use itertools::Itertools;
+use regex::{Captures, Regex};
+use crate::components::config::Config;
+use crate::components::spec::Spec;
+use crate::components::substitutions::Substitutions;
+use crate::error::LoadError;
+
+pub fn load(path: &str) -> Result<Arc<Config>, LoadError> {
+    let config_file = std::fs::read_to_string(path).map_err(|e| LoadError::Read(path.into(), e))?;
+
+    let re = Regex::new("\\$\\{([a-zA-Z_0-9]+)\\}").unwrap();
+    let vars = re.captures_iter(&config_file).fold(Substitutions::new(), |acc: Substitutions, caps: Captures| {
+        let key = &caps[1];
+        match std::env::var(key) {
+            Ok(val) => acc.ok(key.into(), val),
+            Err(_) => acc.err(key.into())
+        }
+    });
+    let vars = match vars {
+        Substitutions::Ok(m) => m,
+        Substitutions::Err(v) => return Err(LoadError::Variables(v.iter().map(|s| format!("- {s}\n")).join(""))),
+    };
+    let config_file = re.replace_all(&config_file, |caps: &Captures| { &vars[&caps[1]] });
+
+    let spec: Spec = hcl::from_str(&config_file).map_err(LoadError::Parse)?;
+    (&spec).try_into().map_err(LoadError::Config)
+}
@@ -1,4 +1,11 @@
+pub mod client;
 pub mod config;
+pub mod loader;
+pub mod network;
+pub mod policy;
+pub mod route;
 pub mod spec;
 pub mod substitutions;
-pub mod types;
\ No newline at end of file
+pub mod template;
+pub mod types;
+pub mod upstream;
\ No newline at end of file
@@ -0,0 +1,159 @@
+//! IP networks, like those of trusted proxies
+
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+use serde::Serializer;
+use crate::error::ConfigError;
+
+/// An IP address or a network in CIDR notation, e.g. `10.0.0.0/8`
+#[derive(Debug, Clone, Copy)]
+pub struct Network {
+    address: IpAddr,
+    prefix: u8,
+}
+
+impl Network {
+    pub fn contains(&self, address: &IpAddr) -> bool {
+        // IPv4 clients of a dual-stack listener arrive as mapped IPv6 addresses
+        let address = match address {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(*address, IpAddr::V4),
+            IpAddr::V4(_) => *address,
+        };
+        match (self.address, address) {
+            (IpAddr::V4(network), IpAddr::V4(address)) =>
+                masked(u32::from(network) as u128, self.prefix, 32) == masked(u32::from(address) as u128, self.prefix, 32),
+            (IpAddr::V6(network), IpAddr::V6(address)) =>
+                masked(u128::from(network), self.prefix, 128) == masked(u128::from(address), self.prefix, 128),
+            _ => false,
+        }
+    }
+}
+
+fn masked(address: u128, prefix: u8, bits: u8) -> u128 {
+    match prefix {
+        0 => 0,
+        prefix => address >> (bits - prefix),
+    }
+}
+
+impl FromStr for Network {
+    type Err = ConfigError;
+
+    fn from_str(value: &str) -> Result<Self, ConfigError> {
+        let invalid = || ConfigError::InvalidNetwork(value.into());
+        let (address, prefix) = match value.split_once('/') {
+            Some((address, prefix)) => (address, Some(prefix)),
+            None => (value, None),
+        };
+        let address = IpAddr::from_str(address.trim()).map_err(|_| invalid())?;
+        let bits = match address {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+        let prefix = match prefix {
+            Some(prefix) => prefix.trim().parse::<u8>().ok().filter(|prefix| *prefix <= bits).ok_or_else(invalid)?,
+            None => bits,
+        };
+        // Addresses get unmapped before matching, so mapped networks have to be too
+        match address {
+            IpAddr::V6(v6) if prefix >= 96 => match v6.to_ipv4_mapped() {
+                Some(v4) => Ok(Network { address: IpAddr::V4(v4), prefix: prefix - 96 }),
+                None => Ok(Network { address, prefix }),
+            },
+            _ => Ok(Network { address, prefix }),
+        }
+    }
+}
+
+impl fmt::Display for Network {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.address, self.prefix)
+    }
+}
+
+pub fn serialize_networks<S>(input: &[Network], ser: S) -> Result<S::Ok, S::Error> where S: Serializer {
+    ser.collect_seq(input.iter().map(Network::to_string))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn network(value: &str) -> Network {
+        value.parse().unwrap()
+    }
+
+    fn ip(value: &str) -> IpAddr {
+        value.parse().unwrap()
+    }
+
+    #[test]
+    fn parses_addresses_and_networks() {
+        assert_eq!(network("10.0.0.1").to_string(), "10.0.0.1/32");
+        assert_eq!(network("10.0.0.0/8").to_string(), "10.0.0.0/8");
+        assert_eq!(network(" 10.0.0.0 / 8 ").to_string(), "10.0.0.0/8");
+        assert_eq!(network("::1").to_string(), "::1/128");
+        assert_eq!(network("fd00::/8").to_string(), "fd00::/8");
+    }
+
+    #[test]
+    fn rejects_invalid_networks() {
+        for value in ["", "10.0.0", "10.0.0.0/", "10.0.0.0/33", "::/129", "10.0.0.0/-1", "10.0.0.0/8/8", "example.com", "10.0.0.0/x"] {
+            assert!(matches!(value.parse::<Network>(), Err(ConfigError::InvalidNetwork(_))), "{value}");
+        }
+    }
+
+    #[test]
+    fn contains_by_prefix() {
+        let v4 = network("192.168.16.0/20");
+        assert!(v4.contains(&ip("192.168.16.0")));
+        assert!(v4.contains(&ip("192.168.31.255")));
+        assert!(!v4.contains(&ip("192.168.32.0")));
+        assert!(!v4.contains(&ip("192.168.15.255")));
+        let v6 = network("fd00:1::/32");
+        assert!(v6.contains(&ip("fd00:1:ffff::1")));
+        assert!(!v6.contains(&ip("fd00:2::1")));
+    }
+
+    #[test]
+    fn single_address() {
+        assert!(network("10.0.0.1").contains(&ip("10.0.0.1")));
+        assert!(!network("10.0.0.1").contains(&ip("10.0.0.2")));
+        assert!(network("::1").contains(&ip("::1")));
+        assert!(!network("::1").contains(&ip("::2")));
+    }
+
+    #[test]
+    fn zero_prefix_matches_whole_family() {
+        assert!(network("0.0.0.0/0").contains(&ip("1.2.3.4")));
+        assert!(network("0.0.0.0/0").contains(&ip("255.255.255.255")));
+        assert!(!network("0.0.0.0/0").contains(&ip("::1")));
+        assert!(network("::/0").contains(&ip("2001:db8::1")));
+        assert!(!network("::/0").contains(&ip("1.2.3.4")));
+    }
+
+    #[test]
+    fn families_dont_mix() {
+        assert!(!network("10.0.0.0/8").contains(&ip("::a00:1")));
+        assert!(!network("::a00:0/104").contains(&ip("10.0.0.1")));
+    }
+
+    #[test]
+    fn mapped_addresses_match_v4_networks() {
+        assert!(network("10.0.0.0/8").contains(&ip("::ffff:10.1.2.3")));
+        assert!(!network("10.0.0.0/8").contains(&ip("::ffff:11.1.2.3")));
+        assert!(network("127.0.0.1").contains(&ip("::ffff:127.0.0.1")));
+        assert!(network("0.0.0.0/0").contains(&ip("::ffff:1.2.3.4")));
+    }
+
+    #[test]
+    fn mapped_networks_match_v4_addresses() {
+        assert_eq!(network("::ffff:10.0.0.0/104").to_string(), "10.0.0.0/8");
+        assert_eq!(network("::ffff:10.0.0.1").to_string(), "10.0.0.1/32");
+        assert!(network("::ffff:10.0.0.0/104").contains(&ip("10.1.2.3")));
+        assert!(network("::ffff:10.0.0.0/104").contains(&ip("::ffff:10.1.2.3")));
+        assert!(!network("::ffff:10.0.0.0/104").contains(&ip("11.1.2.3")));
+        assert!(network("::ffff:0:0/96").contains(&ip("1.2.3.4")));
+    }
+}
@@ -0,0 +1,401 @@
+//! Claim-based authorization of proxied requests
+
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+use actix_web::http::Method;
+use regex::Regex;
+use serde::{Serialize, Serializer};
+use serde::ser::SerializeStruct;
+use serde_json::Value;
+use crate::components::spec::{AuthMode, AuthorizationSpec, DefaultPolicy, RuleSpec};
+use crate::error::ConfigError;
+use crate::systems::token::claim;
+
+#[derive(Serialize)]
+pub struct Policy {
+    pub default: DefaultPolicy,
+    pub roles_claim: String,
+    #[serde(rename = "rule", serialize_with = "hcl::ser::labeled_block")]
+    pub rules: hcl::Map<String, Rule>,
+}
+
+pub struct Rule {
+    spec: RuleSpec,
+    methods: Vec<Method>,
+    path: Option<Regex>,
+    predicates: Vec<Predicate>,
+}
+
+struct Predicate {
+    path: String,
+    operator: Operator,
+    value: Value,
+}
+
+#[derive(Clone, Copy)]
+enum Operator {
+    Equals,
+    NotEquals,
+    Contains,
+    Exists,
+}
+
+pub enum Decision {
+    Allow(String),
+    Deny(String),
+}
+
+impl Policy {
+    pub fn new(value: &AuthorizationSpec) -> Result<Self, ConfigError> {
+        Ok(Policy {
+            default: value.default,
+            roles_claim: value.roles_claim.clone(),
+            rules: value.rules.iter()
+                .map(|(name, rule)| Ok((name.clone(), Rule::new(name, rule)?)))
+                .collect::<Result<_, ConfigError>>()?,
+        })
+    }
+
+    /// Whether deciding on a request needs the claims of the access token
+    pub fn needs_claims(&self) -> bool {
+        !self.rules.is_empty()
+    }
+
+    /// How a request for `path` has to be authenticated, if a rule overrides the API's setting
+    pub fn auth(&self, method: &Method, path: &str) -> Option<AuthMode> {
+        self.rule(method, path).and_then(|(_, rule)| rule.spec.auth)
+    }
+
+    /// Decides on a request for `path`, relative to the API and starting with a slash
+    pub fn evaluate(&self, method: &Method, path: &str, claims: &Value) -> Decision {
+        match self.rule(method, path) {
+            None => match self.default {
+                DefaultPolicy::Allow => Decision::Allow("default policy".into()),
+                DefaultPolicy::Deny => Decision::Deny("default policy".into()),
+            },
+            Some((name, rule)) => match rule.check(&self.roles_claim, claims) {
+                Ok(()) => Decision::Allow(format!("rule '{name}'")),
+                Err(reason) => Decision::Deny(format!("rule '{name}': {reason}")),
+            },
+        }
+    }
+
+    fn rule(&self, method: &Method, path: &str) -> Option<(&String, &Rule)> {
+        self.rules.iter().find(|(_, rule)| rule.matches(method, path))
+    }
+}
+
+impl Rule {
+    fn new(name: &str, value: &RuleSpec) -> Result<Self, ConfigError> {
+        let invalid = |reason: String| ConfigError::InvalidRule(name.into(), reason);
+        let requirements = !value.roles.is_empty() || !value.scopes.is_empty() || !value.claims.is_empty();
+        if value.auth == Some(AuthMode::None) && requirements {
+            return Err(invalid("roles, scopes and claims need a session, which auth = \"none\" ignores".into()));
+        }
+        Ok(Rule {
+            spec: value.clone(),
+            methods: value.methods.iter()
+                .map(|m| Method::from_str(&m.to_uppercase()).map_err(|_| ConfigError::InvalidMethod(m.clone())))
+                .collect::<Result<_, _>>()?,
+            path: value.path.as_deref().map(glob).transpose()?,
+            predicates: value.claims.iter()
+                .map(|p| Predicate::from_str(p).map_err(|e| invalid(format!("'{p}': {e}"))))
+                .collect::<Result<_, _>>()?,
+        })
+    }
+
+    fn matches(&self, method: &Method, path: &str) -> bool {
+        (self.methods.is_empty() || self.methods.contains(method))
+            && self.path.as_ref().is_none_or(|re| re.is_match(path))
+    }
+
+    fn check(&self, roles_claim: &str, claims: &Value) -> Result<(), String> {
+        if self.spec.deny {
+            return Err("denied".into());
+        }
+        let roles = claim(claims, roles_claim);
+        if let Some(role) = self.spec.roles.iter().find(|role| !contains(roles, role)) {
+            return Err(format!("missing role '{role}'"));
+        }
+        let scopes = claim(claims, "scope");
+        if let Some(scope) = self.spec.scopes.iter().find(|scope| !contains(scopes, scope)) {
+            return Err(format!("missing scope '{scope}'"));
+        }
+        match self.predicates.iter().zip(&self.spec.claims).find(|(p, _)| !p.holds(claims)) {
+            Some((_, source)) => Err(format!("claim predicate {source} doesn't hold")),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Serialize for Rule {
+    fn serialize<S>(&self, ser: S) -> Result<S::Ok, S::Error> where S: Serializer {
+        let mut rule = ser.serialize_struct("Rule", 7)?;
+        rule.serialize_field("methods", &self.spec.methods)?;
+        if let Some(ref path) = self.spec.path {
+            rule.serialize_field("path", path)?;
+        }
+        rule.serialize_field("roles", &self.spec.roles)?;
+        rule.serialize_field("scopes", &self.spec.scopes)?;
+        rule.serialize_field("claims", &self.spec.claims)?;
+        rule.serialize_field("deny", &self.spec.deny)?;
+        if let Some(ref auth) = self.spec.auth {
+            rule.serialize_field("auth", auth)?;
+        }
+        rule.end()
+    }
+}
+
+impl FromStr for Predicate {
+    type Err = &'static str;
+
+    /// Parses `<claim path> <operator> <JSON value>`, or `<claim path> exists`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let (path, rest) = s.split_once(char::is_whitespace).ok_or("expected an operator")?;
+        let rest = rest.trim_start();
+        let (operator, value) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+        let operator = match operator {
+            "==" => Operator::Equals,
+            "!=" => Operator::NotEquals,
+            "contains" => Operator::Contains,
+            "exists" => Operator::Exists,
+            _ => return Err("expected one of ==, !=, contains, exists"),
+        };
+        let value = match (operator, value.trim()) {
+            (Operator::Exists, "") => Value::Null,
+            (Operator::Exists, _) => return Err("exists takes no value"),
+            (_, value) => serde_json::from_str(value).map_err(|_| "expected a JSON value, e.g. \"admin\", 42 or true")?,
+        };
+        Ok(Predicate { path: path.into(), operator, value })
+    }
+}
+
+impl Predicate {
+    fn holds(&self, claims: &Value) -> bool {
+        let actual = claim(claims, &self.path);
+        match self.operator {
+            Operator::Equals => actual == Some(&self.value),
+            Operator::NotEquals => actual != Some(&self.value),
+            Operator::Contains => match &self.value {
+                Value::String(s) => contains(actual, s),
+                value => matches!(actual, Some(Value::Array(values)) if values.contains(value)),
+            },
+            Operator::Exists => actual.is_some_and(|v| !v.is_null()),
+        }
+    }
+}
+
+impl Display for Decision {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Decision::Allow(reason) => write!(f, "allow ({reason})"),
+            Decision::Deny(reason) => write!(f, "deny ({reason})"),
+        }
+    }
+}
+
+/// Whether a claim lists `item`, either as an array of strings or as a space-separated string like `scope`
+fn contains(claim: Option<&Value>, item: &str) -> bool {
+    match claim {
+        Some(Value::Array(values)) => values.iter().any(|v| v.as_str() == Some(item)),
+        Some(Value::String(s)) => s.split_whitespace().any(|s| s == item),
+        _ => false,
+    }
+}
+
+/// Compiles a path pattern, where `/**` at the end also matches the bare prefix
+fn glob(pattern: &str) -> Result<Regex, ConfigError> {
+    let pattern = match pattern.starts_with('/') {
+        true => pattern.to_string(),
+        false => format!("/{pattern}"),
+    };
+    let mut re = String::from("^");
+    let mut rest = pattern.as_str();
+    while !rest.is_empty() {
+        if let Some(tail) = rest.strip_prefix("/**") {
+            re.push_str(if tail.is_empty() { "(?:/.*)?" } else { "/.*" });
+            rest = tail;
+        } else if let Some(tail) = rest.strip_prefix("**") {
+            re.push_str(".*");
+            rest = tail;
+        } else if let Some(tail) = rest.strip_prefix('*') {
+            re.push_str("[^/]*");
+            rest = tail;
+        } else {
+            let end = rest.char_indices().skip(1)
+                .find(|(_, c)| *c == '*' || *c == '/')
+                .map_or(rest.len(), |(i, _)| i);
+            re.push_str(&regex::escape(&rest[..end]));
+            rest = &rest[end..];
+        }
+    }
+    re.push('$');
+    Ok(Regex::new(&re)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use super::*;
+
+    fn policy(hcl: &str) -> Policy {
+        Policy::new(&hcl::from_str::<AuthorizationSpec>(hcl).unwrap()).unwrap()
+    }
+
+    fn allowed(policy: &Policy, method: Method, path: &str, claims: &Value) -> bool {
+        matches!(policy.evaluate(&method, path, claims), Decision::Allow(_))
+    }
+
+    #[test]
+    fn star_matches_within_a_segment() {
+        let re = glob("/items/*").unwrap();
+        assert!(re.is_match("/items/42"));
+        assert!(re.is_match("/items/"));
+        assert!(!re.is_match("/items"));
+        assert!(!re.is_match("/items/42/parts"));
+        let re = glob("/items/*.json").unwrap();
+        assert!(re.is_match("/items/42.json"));
+        assert!(!re.is_match("/items/42/parts.json"));
+        assert!(!re.is_match("/items/42xjson"));
+    }
+
+    #[test]
+    fn double_star_matches_across_segments() {
+        let re = glob("/admin/**").unwrap();
+        assert!(re.is_match("/admin"));
+        assert!(re.is_match("/admin/"));
+        assert!(re.is_match("/admin/users/42"));
+        assert!(!re.is_match("/administrators"));
+        let re = glob("/**/edit").unwrap();
+        assert!(re.is_match("/items/42/edit"));
+        assert!(!re.is_match("/items/42/edit/more"));
+        let re = glob("/files**").unwrap();
+        assert!(re.is_match("/files/a/b"));
+        assert!(re.is_match("/filesystem"));
+    }
+
+    #[test]
+    fn glob_is_anchored_and_literal() {
+        assert!(glob("items").unwrap().is_match("/items"));
+        assert!(!glob("/items").unwrap().is_match("/items/42"));
+        assert!(!glob("/a.b").unwrap().is_match("/axb"));
+        assert!(glob("/a+(b)").unwrap().is_match("/a+(b)"));
+    }
+
+    #[test]
+    fn default_decision_applies_without_matching_rule() {
+        let claims = json!({});
+        assert!(allowed(&policy(""), Method::GET, "/anything", &claims));
+        assert!(!allowed(&policy(r#"default = "deny""#), Method::GET, "/anything", &claims));
+        let policy = policy(r#"
+            default = "deny"
+            rule "read" {
+              methods = ["GET"]
+              path = "/public/**"
+            }
+        "#);
+        assert!(allowed(&policy, Method::GET, "/public/a", &claims));
+        assert!(!allowed(&policy, Method::POST, "/public/a", &claims));
+        assert!(!allowed(&policy, Method::GET, "/private", &claims));
+    }
+
+    #[test]
+    fn first_matching_rule_decides() {
+        let policy = policy(r#"
+            rule "locked" {
+              path = "/admin/locked"
+              deny = true
+            }
+            rule "admin" {
+              path = "/admin/**"
+              roles = ["admin"]
+            }
+        "#);
+        let admin = json!({ "realm_access": { "roles": ["admin"] } });
+        assert!(!allowed(&policy, Method::GET, "/admin/locked", &admin));
+        assert!(allowed(&policy, Method::GET, "/admin/users", &admin));
+        assert!(!allowed(&policy, Method::GET, "/admin/users", &json!({})));
+    }
+
+    #[test]
+    fn roles_and_scopes() {
+        let policy = policy(r#"
+            roles_claim = "groups"
+            rule "write" {
+              roles = ["editor"]
+              scopes = ["items:write"]
+            }
+        "#);
+        let claims = |groups: Value, scope: &str| json!({ "groups": groups, "scope": scope });
+        assert!(allowed(&policy, Method::PUT, "/", &claims(json!(["editor"]), "openid items:write")));
+        assert!(allowed(&policy, Method::PUT, "/", &claims(json!("viewer editor"), "items:write")));
+        assert!(!allowed(&policy, Method::PUT, "/", &claims(json!(["viewer"]), "items:write")));
+        assert!(!allowed(&policy, Method::PUT, "/", &claims(json!(["editor"]), "items:read")));
+        assert!(!allowed(&policy, Method::PUT, "/", &claims(json!(["editor"]), "items:write:all")));
+    }
+
+    #[test]
+    fn predicates() {
+        let claims = json!({
+            "tenant": "acme",
+            "level": 3,
+            "verified": true,
+            "groups": ["a", "b"],
+            "ids": [1, 2],
+            "nothing": null,
+            "org": { "name": "ACME" },
+        });
+        let holds = |source: &str| Predicate::from_str(source).unwrap().holds(&claims);
+        assert!(holds(r#"tenant == "acme""#));
+        assert!(!holds(r#"tenant == "other""#));
+        assert!(holds("level == 3"));
+        assert!(!holds(r#"level == "3""#));
+        assert!(holds("verified == true"));
+        assert!(holds(r#"org.name == "ACME""#));
+        assert!(holds(r#"tenant != "other""#));
+        assert!(holds(r#"missing != "acme""#));
+        assert!(holds(r#"groups contains "b""#));
+        assert!(!holds(r#"groups contains "c""#));
+        assert!(holds("ids contains 2"));
+        assert!(!holds("ids contains 3"));
+        assert!(holds("tenant exists"));
+        assert!(holds("org.name exists"));
+        assert!(!holds("missing exists"));
+        assert!(!holds("nothing exists"));
+    }
+
+    #[test]
+    fn invalid_predicates() {
+        for source in ["tenant", "tenant ~= \"acme\"", "tenant == acme", "tenant exists true", "tenant =="] {
+            assert!(Predicate::from_str(source).is_err(), "{source}");
+        }
+        let spec = hcl::from_str::<AuthorizationSpec>(r#"rule "r" { claims = ["tenant = 1"] }"#).unwrap();
+        assert!(matches!(Policy::new(&spec), Err(ConfigError::InvalidRule(..))));
+    }
+
+    #[test]
+    fn requirements_need_a_session() {
+        let spec = hcl::from_str::<AuthorizationSpec>(r#"
+            rule "public" {
+              roles = ["admin"]
+              auth = "none"
+            }
+        "#).unwrap();
+        assert!(matches!(Policy::new(&spec), Err(ConfigError::InvalidRule(..))));
+    }
+
+    #[test]
+    fn rule_overrides_auth() {
+        let policy = policy(r#"
+            rule "health" {
+              methods = ["get"]
+              path = "/health"
+              auth = "none"
+            }
+        "#);
+        assert!(matches!(policy.auth(&Method::GET, "/health"), Some(AuthMode::None)));
+        assert!(policy.auth(&Method::POST, "/health").is_none());
+        assert!(policy.auth(&Method::GET, "/other").is_none());
+    }
+}
@@ -0,0 +1,345 @@
+//! Routes within an API, which pick a backend and rewrite the path for it
+
+use std::str::FromStr;
+use actix_web::http::Method;
+use regex::{Captures, Regex};
+use serde::{Serialize, Serializer};
+use serde::ser::SerializeStruct;
+use url::Url;
+use crate::components::spec::RouteSpec;
+use crate::components::upstream::Upstream;
+use crate::error::ConfigError;
+
+pub struct Route {
+    spec: RouteSpec,
+    pattern: Regex,
+    methods: Vec<Method>,
+    rewrite: Option<Vec<Segment>>,
+    backend: Option<Upstream>,
+}
+
+enum Segment {
+    Literal(String),
+    Index(usize),
+    Name(String),
+}
+
+/// Where a request goes: the upstream to pick a target from, and the path below it
+pub struct Destination<'a> {
+    pub upstream: &'a Upstream,
+    pub path: String,
+    /// Whether a route rewrote the path, so that paths of the backend don't map back onto those of the API
+    pub rewritten: bool,
+}
+
+/// Outcome of looking for the route of a request
+pub enum Routing<'a, 'p> {
+    Found(&'a Route, Captures<'p>),
+    /// Routes match the path, but not the method
+    MethodNotAllowed,
+    NotFound,
+}
+
+impl Route {
+    pub fn new(name: &str, value: &RouteSpec) -> Result<Self, ConfigError> {
+        let invalid = |reason: String| ConfigError::InvalidRoute(name.into(), reason);
+        let pattern = match (&value.prefix, &value.regex) {
+            (Some(prefix), None) => {
+                let prefix = match prefix.trim_matches('/') {
+                    "" => String::new(),
+                    prefix => format!("/{prefix}"),
+                };
+                Regex::new(&format!("^{}(?P<rest>/.*)?$", regex::escape(&prefix)))?
+            },
+            (None, Some(regex)) => Regex::new(&format!("^(?:{regex})$"))?,
+            _ => return Err(invalid("needs either prefix or regex".into())),
+        };
+        let rewrite = value.rewrite.as_deref().map(|rewrite| parse_rewrite(rewrite, &pattern)).transpose().map_err(invalid)?;
+        let backend = value.backend.as_deref().map(backend_url).transpose()?.map(Upstream::single);
+        Ok(Route {
+            spec: value.clone(),
+            methods: value.methods.iter()
+                .map(|m| Method::from_str(&m.to_uppercase()).map_err(|_| ConfigError::InvalidMethod(m.clone())))
+                .collect::<Result<_, _>>()?,
+            pattern,
+            rewrite,
+            backend,
+        })
+    }
+
+    /// Where a request to `path` goes, which the route has matched with `captures`
+    pub fn destination<'a>(&'a self, default: &'a Upstream, path: &str, captures: &Captures) -> Destination<'a> {
+        let path = match &self.rewrite {
+            None => path.to_string(),
+            Some(segments) => segments.iter().map(|segment| match segment {
+                Segment::Literal(literal) => literal.as_str(),
+                Segment::Index(i) => captures.get(*i).map_or("", |m| m.as_str()),
+                Segment::Name(name) => captures.name(name).map_or("", |m| m.as_str()),
+            }).collect(),
+        };
+        Destination { upstream: self.backend.as_ref().unwrap_or(default), path, rewritten: self.rewrite.is_some() }
+    }
+}
+
+/// Finds the first route matching a request, given its path relative to the API
+pub fn find<'a, 'p>(routes: &'a hcl::Map<String, Route>, method: &Method, path: &'p str) -> Routing<'a, 'p> {
+    let mut path_matched = false;
+    for route in routes.values() {
+        if let Some(captures) = route.pattern.captures(path) {
+            if route.methods.is_empty() || route.methods.contains(method) {
+                return Routing::Found(route, captures);
+            }
+            path_matched = true;
+        }
+    }
+    match path_matched {
+        true => Routing::MethodNotAllowed,
+        false => Routing::NotFound,
+    }
+}
+
+/// Parses a backend URL, which gets a trailing slash so that paths are appended to it
+pub fn backend_url(backend: &str) -> Result<Url, ConfigError> {
+    let mut backend = backend.to_string();
+    if !backend.ends_with('/') {
+        backend.push('/');
+    }
+    Url::from_str(&backend).map_err(|p| ConfigError::InvalidUrl(backend, p))
+}
+
+/// Appends a path relative to the API to a backend URL
+pub fn join(backend: &Url, path: &str, query: Option<&str>) -> Url {
+    let mut url = backend.clone();
+    let path = format!("{}{}", url.path(), path.trim_start_matches('/'));
+    url.set_path(&path);
+    url.set_query(query);
+    url
+}
+
+impl Serialize for Route {
+    fn serialize<S>(&self, ser: S) -> Result<S::Ok, S::Error> where S: Serializer {
+        let mut route = ser.serialize_struct("Route", 5)?;
+        if let Some(ref prefix) = self.spec.prefix {
+            route.serialize_field("prefix", prefix)?;
+        }
+        if let Some(ref regex) = self.spec.regex {
+            route.serialize_field("regex", regex)?;
+        }
+        route.serialize_field("methods", &self.spec.methods)?;
+        if let Some(ref rewrite) = self.spec.rewrite {
+            route.serialize_field("rewrite", rewrite)?;
+        }
+        if let Some(ref backend) = self.spec.backend {
+            route.serialize_field("backend", backend)?;
+        }
+        route.end()
+    }
+}
+
+/// Splits a rewrite template into literals and `${captures.<name or index>}` placeholders, which must exist in `pattern`
+///
+/// Rewrites only replace the path; the query of the client is passed on as it is.
+fn parse_rewrite(source: &str, pattern: &Regex) -> Result<Vec<Segment>, String> {
+    if source.contains(['?', '#']) {
+        return Err("rewrite can only replace the path, not add a query or fragment".into());
+    }
+    let mut segments = Vec::new();
+    let mut rest = source;
+    while let Some(start) = rest.find("${") {
+        let (literal, placeholder) = rest.split_at(start);
+        let end = placeholder.find('}').ok_or("unterminated placeholder in rewrite")?;
+        if !literal.is_empty() {
+            segments.push(Segment::Literal(literal.into()));
+        }
+        let capture = placeholder[2..end].strip_prefix("captures.")
+            .ok_or("expected ${captures.<name or index>} in rewrite")?;
+        segments.push(match usize::from_str(capture) {
+            Ok(i) if i < pattern.captures_len() => Segment::Index(i),
+            Err(_) if pattern.capture_names().any(|name| name == Some(capture)) => Segment::Name(capture.into()),
+            _ => return Err(format!("rewrite refers to unknown capture group '{capture}'")),
+        });
+        rest = &placeholder[end + 1..];
+    }
+    if !rest.is_empty() {
+        segments.push(Segment::Literal(rest.into()));
+    }
+    Ok(segments)
+}
+
+/// Resolves dot segments in a path relative to the API, so that they can't dodge routes and rules
+pub fn normalize(path: &str) -> String {
+    let mut url = Url::parse("http://api/").unwrap();
+    url.set_path(path);
+    url.path().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spec(prefix: Option<&str>, regex: Option<&str>, methods: &[&str], rewrite: Option<&str>) -> RouteSpec {
+        RouteSpec {
+            prefix: prefix.map(String::from),
+            regex: regex.map(String::from),
+            methods: methods.iter().map(|m| m.to_string()).collect(),
+            rewrite: rewrite.map(String::from),
+            backend: None,
+        }
+    }
+
+    fn routes(specs: Vec<(&str, RouteSpec)>) -> hcl::Map<String, Route> {
+        specs.into_iter().map(|(name, spec)| (name.to_string(), Route::new(name, &spec).unwrap())).collect()
+    }
+
+    /// The name of the route found, or the reason none was
+    fn found(routes: &hcl::Map<String, Route>, method: Method, path: &str) -> String {
+        match find(routes, &method, path) {
+            Routing::Found(route, _) => routes.iter().find(|(_, r)| std::ptr::eq(*r, route)).unwrap().0.clone(),
+            Routing::MethodNotAllowed => "method not allowed".into(),
+            Routing::NotFound => "not found".into(),
+        }
+    }
+
+    fn rewritten(spec: RouteSpec, path: &str) -> String {
+        let route = Route::new("r", &spec).unwrap();
+        let captures = route.pattern.captures(path).unwrap();
+        let default = Upstream::single(backend_url("http://backend").unwrap());
+        let destination = route.destination(&default, path, &captures);
+        assert_eq!(destination.rewritten, spec.rewrite.is_some());
+        destination.path
+    }
+
+    #[test]
+    fn prefix_matches_whole_segments() {
+        let routes = routes(vec![("users", spec(Some("/users/"), None, &[], None))]);
+        assert_eq!(found(&routes, Method::GET, "/users"), "users");
+        assert_eq!(found(&routes, Method::GET, "/users/"), "users");
+        assert_eq!(found(&routes, Method::GET, "/users/42"), "users");
+        assert_eq!(found(&routes, Method::GET, "/usersettings"), "not found");
+        assert_eq!(found(&routes, Method::GET, "/api/users"), "not found");
+    }
+
+    #[test]
+    fn empty_prefix_matches_everything() {
+        let routes = routes(vec![("all", spec(Some("/"), None, &[], None))]);
+        assert_eq!(found(&routes, Method::GET, "/"), "all");
+        assert_eq!(found(&routes, Method::GET, "/anything/at/all"), "all");
+    }
+
+    #[test]
+    fn regex_matches_whole_path() {
+        let routes = routes(vec![("item", spec(None, Some("/items/[0-9]+"), &[], None))]);
+        assert_eq!(found(&routes, Method::GET, "/items/42"), "item");
+        assert_eq!(found(&routes, Method::GET, "/items/42/parts"), "not found");
+        assert_eq!(found(&routes, Method::GET, "/v1/items/42"), "not found");
+        assert_eq!(found(&routes, Method::GET, "/items/abc"), "not found");
+    }
+
+    #[test]
+    fn first_match_wins() {
+        let routes = routes(vec![
+            ("item", spec(None, Some("/items/[0-9]+"), &[], None)),
+            ("items", spec(Some("/items"), None, &[], None)),
+        ]);
+        assert_eq!(found(&routes, Method::GET, "/items/42"), "item");
+        assert_eq!(found(&routes, Method::GET, "/items/abc"), "items");
+    }
+
+    #[test]
+    fn method_not_allowed_vs_not_found() {
+        let routes = routes(vec![
+            ("read", spec(Some("/items"), None, &["get", "HEAD"], None)),
+            ("write", spec(Some("/items"), None, &["post"], None)),
+        ]);
+        assert_eq!(found(&routes, Method::GET, "/items/1"), "read");
+        assert_eq!(found(&routes, Method::HEAD, "/items/1"), "read");
+        assert_eq!(found(&routes, Method::POST, "/items"), "write");
+        assert_eq!(found(&routes, Method::DELETE, "/items/1"), "method not allowed");
+        assert_eq!(found(&routes, Method::DELETE, "/orders/1"), "not found");
+    }
+
+    #[test]
+    fn needs_prefix_or_regex() {
+        assert!(Route::new("r", &spec(None, None, &[], None)).is_err());
+        assert!(Route::new("r", &spec(Some("/a"), Some("/a"), &[], None)).is_err());
+    }
+
+    #[test]
+    fn rejects_invalid_method_and_regex() {
+        assert!(matches!(Route::new("r", &spec(Some("/a"), None, &["g e t"], None)), Err(ConfigError::InvalidMethod(_))));
+        assert!(matches!(Route::new("r", &spec(None, Some("/a("), &[], None)), Err(ConfigError::InvalidRegex(_))));
+    }
+
+    #[test]
+    fn rewrite_by_capture_name() {
+        assert_eq!(rewritten(spec(Some("/users"), None, &[], Some("/v1${captures.rest}")), "/users/42"), "/v1/42");
+        assert_eq!(rewritten(spec(Some("/users"), None, &[], Some("/v1${captures.rest}")), "/users"), "/v1");
+        let regex = spec(None, Some("/items/(?P<id>[0-9]+)/(?P<part>[a-z]+)"), &[], Some("/parts/${captures.part}/items/${captures.id}"));
+        assert_eq!(rewritten(regex, "/items/7/wheel"), "/parts/wheel/items/7");
+    }
+
+    #[test]
+    fn rewrite_by_capture_index() {
+        let regex = spec(None, Some("/a/([0-9]+)/b/([0-9]+)"), &[], Some("/b/${captures.2}/a/${captures.1}"));
+        assert_eq!(rewritten(regex, "/a/1/b/2"), "/b/2/a/1");
+        let whole = spec(None, Some("/a/([0-9]+)/b/([0-9]+)"), &[], Some("/x${captures.0}"));
+        assert_eq!(rewritten(whole, "/a/1/b/2"), "/x/a/1/b/2");
+    }
+
+    #[test]
+    fn rewrite_keeps_query_of_client() {
+        let path = rewritten(spec(Some("/users"), None, &[], Some("/v1${captures.rest}")), "/users/42");
+        let backend = backend_url("http://backend/api").unwrap();
+        assert_eq!(join(&backend, &path, Some("expand=true")).as_str(), "http://backend/api/v1/42?expand=true");
+    }
+
+    #[test]
+    fn rewrite_rejects_query_and_fragment() {
+        for rewrite in ["/parts?item=${captures.rest}", "/parts${captures.rest}#top"] {
+            assert!(matches!(Route::new("r", &spec(Some("/users"), None, &[], Some(rewrite))), Err(ConfigError::InvalidRoute(..))), "{rewrite}");
+        }
+    }
+
+    #[test]
+    fn without_rewrite_path_is_kept() {
+        assert_eq!(rewritten(spec(Some("/users"), None, &[], None), "/users/42"), "/users/42");
+    }
+
+    #[test]
+    fn rewrite_rejects_unknown_captures() {
+        let invalid = |regex: Option<&str>, rewrite: &str| {
+            let spec = spec(regex.is_none().then_some("/users"), regex, &[], Some(rewrite));
+            matches!(Route::new("r", &spec), Err(ConfigError::InvalidRoute(..)))
+        };
+        assert!(invalid(None, "/v1${captures.id}"));
+        assert!(invalid(None, "/v1${captures.2}"));
+        assert!(invalid(Some("/items/([0-9]+)"), "/${captures.2}"));
+        assert!(invalid(Some("/items/(?P<id>[0-9]+)"), "/${captures.name}"));
+        assert!(invalid(None, "/v1${claims.sub}"));
+        assert!(invalid(None, "/v1${captures.rest"));
+    }
+
+    #[test]
+    fn normalize_resolves_dot_segments() {
+        assert_eq!(normalize("users/42"), "/users/42");
+        assert_eq!(normalize("/public/../admin"), "/admin");
+        assert_eq!(normalize("/public/./../../admin"), "/admin");
+        assert_eq!(normalize("/public/%2e%2e/admin"), "/admin");
+        assert_eq!(normalize("/public/%2E%2E/admin"), "/admin");
+        assert_eq!(normalize("/public/.%2e/admin"), "/admin");
+        assert_eq!(normalize("/a/%2e/b"), "/a/b");
+    }
+
+    #[test]
+    fn normalized_paths_cant_dodge_routes() {
+        let routes = routes(vec![("public", spec(Some("/public"), None, &[], None))]);
+        assert_eq!(found(&routes, Method::GET, &normalize("/public/%2e%2e/admin")), "not found");
+    }
+
+    #[test]
+    fn join_appends_path_and_query() {
+        let backend = backend_url("http://backend/api").unwrap();
+        assert_eq!(join(&backend, "/items/1", Some("a=b")).as_str(), "http://backend/api/items/1?a=b");
+        assert_eq!(join(&backend, "items", None).as_str(), "http://backend/api/items");
+    }
+}
@@ -1,4 +1,4 @@
-/// Config file types
+//! Config file types
 
 use serde_derive::{Deserialize, Serialize};
 
@@ -12,10 +12,148 @@ pub struct Spec {
     pub clock_skew: u16,
     #[serde(default)]
     pub expose_errors: bool,
+    pub cors: Option<CorsSpec>,
+    /// Addresses or networks of proxies in front of us, whose forwarding headers are believed
+    #[serde(default)]
+    pub trusted_proxies: Vec<String>,
+    pub metrics: Option<MetricsSpec>,
+    pub tracing: Option<TracingSpec>,
+    #[serde(default)]
+    pub logging: LoggingSpec,
+    #[serde(default)]
+    pub request_id: RequestIdSpec,
+    #[serde(default)]
+    pub readiness: ReadinessSpec,
+    pub admin: Option<AdminSpec>,
     #[serde(rename = "bridge", serialize_with = "hcl::ser::labeled_block")]
     pub bridges: hcl::Map<String, BridgeSpec>,
 }
 
+/// Exposes Prometheus metrics at `/metrics`
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct MetricsSpec {
+    /// Port of a separate listener for the metrics; unset means the port of all other endpoints
+    pub port: Option<u16>,
+}
+
+/// Exports traces by OTLP over HTTP
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct TracingSpec {
+    /// Base URL of the collector, e.g. `http://localhost:4318`, below which traces go to `/v1/traces`
+    pub endpoint: String,
+    #[serde(default = "_default_service_name")]
+    pub service_name: String,
+    /// Share of the traces starting here which are recorded; traces of clients are recorded as they decided
+    #[serde(default = "_default_sample_ratio")]
+    pub sample_ratio: f64,
+}
+
+/// How access lines, audit events and all other logs are written
+#[derive(Deserialize, Serialize, Debug, Clone, Default, PartialEq)]
+pub struct LoggingSpec {
+    #[serde(default)]
+    pub format: LogFormat,
+    /// Logs `[redacted]` in place of the usernames of sessions
+    #[serde(default)]
+    pub redact_usernames: bool,
+    /// Logs the names of query parameters, but not their values
+    #[serde(default)]
+    pub redact_queries: bool,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// Lines for humans
+    #[default]
+    Text,
+    /// One JSON object per line, with stable fields for access lines and audit events
+    Json,
+}
+
+/// Ids which tie the logs of a request to those of the IDP and the backends, and to the response
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct RequestIdSpec {
+    /// Header which carries the id in requests and responses
+    #[serde(default = "_default_request_id_header")]
+    pub header: String,
+    /// Whose ids are taken over; all other requests get a new one
+    #[serde(default)]
+    pub trust: RequestIdTrust,
+}
+
+impl Default for RequestIdSpec {
+    fn default() -> Self {
+        RequestIdSpec { header: _default_request_id_header(), trust: RequestIdTrust::default() }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum RequestIdTrust {
+    /// Every client
+    #[default]
+    Any,
+    /// Only the reverse proxies in `trusted_proxies`
+    TrustedProxies,
+    /// Nobody, i.e. every request gets a new id
+    Never,
+}
+
+/// A listener for operators, apart from the one for clients; it needs a token, client certificates, or both
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct AdminSpec {
+    /// Address to bind to, e.g. `127.0.0.1:9000`
+    pub address: String,
+    /// Token which requests have to present as `Authorization: Bearer <token>`
+    pub token: Option<String>,
+    pub tls: Option<AdminTlsSpec>,
+}
+
+/// HTTPS with client certificates, all as paths of PEM files
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct AdminTlsSpec {
+    pub certificate: String,
+    pub key: String,
+    /// CAs which client certificates have to be issued by
+    pub client_ca: String,
+}
+
+/// What `/ready` checks, and which of the checks keep it from answering 200 while they fail
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct ReadinessSpec {
+    /// Checks which have to pass; all others are only reported
+    #[serde(default = "_default_readiness_gates")]
+    pub gates: Vec<ReadinessCheck>,
+    /// Seconds to wait for each check
+    #[serde(default = "_default_2")]
+    pub timeout: u16,
+    /// Seconds for which the outcome of the checks is reused, so that probes don't pile up requests to the IDPs and
+    /// backends
+    #[serde(default = "_default_5")]
+    pub cache_ttl: u16,
+    /// Whether `/ready` tells how each check went, and not only whether all gates passed
+    #[serde(default)]
+    pub details: bool,
+}
+
+impl Default for ReadinessSpec {
+    fn default() -> Self {
+        ReadinessSpec { gates: _default_readiness_gates(), timeout: _default_2(), cache_ttl: _default_5(), details: false }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ReadinessCheck {
+    /// The discovery document of every bridge's IDP is loaded
+    Discovery,
+    /// The JWKS of every bridge's IDP can be fetched and has keys
+    Jwks,
+    /// Every API with a `readiness_probe` has a target which answers it
+    Upstreams,
+}
+
 #[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct BridgeSpec {
     pub idp: String,
@@ -24,15 +162,388 @@ pub struct BridgeSpec {
     pub client_secret: String,
     #[serde(default = "_default_openid")]
     pub scope: String,
+    #[serde(default)]
+    pub revoke_access_token: bool,
+    #[serde(default)]
+    pub revocation_failure: RevocationFailure,
+    #[serde(default = "_default_true")]
+    pub logout_id_token_hint: bool,
+    #[serde(default)]
+    pub local_logout: bool,
+    /// Where logouts may redirect to without the IDP checking it, besides the token handler's own origin
+    #[serde(default)]
+    pub post_logout_redirect_uris: Vec<String>,
+    pub session_max_age: Option<u32>,
+    pub session_idle_timeout: Option<u32>,
+    #[serde(default = "_default_60")]
+    pub session_refresh_interval: u32,
+    pub persistent_session_max_age: Option<u32>,
+    #[serde(default)]
+    pub cookie: CookieSpec,
+    pub csrf: Option<CsrfSpec>,
+    pub cors: Option<CorsSpec>,
+    /// Timeouts of the calls to the IDP
+    #[serde(default)]
+    pub idp_timeouts: TimeoutsSpec,
+    pub idp_retry: Option<RetrySpec>,
+    pub idp_circuit_breaker: Option<CircuitBreakerSpec>,
     #[serde(rename = "api", serialize_with = "hcl::ser::labeled_block")]
     pub apis: hcl::Map<String, ApiSpec>,
 }
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct ApiSpec {
-    pub backend: String,
+    /// URL of the only backend, as an alternative to `upstream`
+    pub backend: Option<String>,
+    pub upstream: Option<UpstreamSpec>,
     #[serde(default = "_default_headers")]
     pub headers: Vec<String>,
+    /// Response headers to pass back to the client; unset means all
+    pub response_headers: Option<Vec<String>>,
+    #[serde(default = "_default_strip_response_headers")]
+    pub strip_response_headers: Vec<String>,
+    #[serde(default)]
+    pub request_headers: RequestHeadersSpec,
+    pub identity: Option<IdentitySpec>,
+    pub cors: Option<CorsSpec>,
+    #[serde(default)]
+    pub authorization: AuthorizationSpec,
+    /// Whether requests need a session; rules can override this for their paths
+    #[serde(default)]
+    pub auth: AuthMode,
+    /// Send requests without a session with a token of the bridge's client instead of none
+    pub client_credentials: Option<ClientCredentialsSpec>,
+    /// Routes by name, tried in order; without any, every path goes to `backend` as it is
+    #[serde(default, rename = "route")]
+    pub routes: hcl::Map<String, RouteSpec>,
+    #[serde(default)]
+    pub timeouts: TimeoutsSpec,
+    pub retry: Option<RetrySpec>,
+    pub circuit_breaker: Option<CircuitBreakerSpec>,
+    /// Seconds after which proxied WebSockets are closed, at the latest when their access token expires
+    pub websocket_max_lifetime: Option<u32>,
+    pub streaming: Option<StreamingSpec>,
+    /// Bytes a request body may have at most; unset means no limit
+    pub max_body_size: Option<u64>,
+    /// Media types which request bodies may have, like `application/json` or `image/*`; empty means any
+    #[serde(default)]
+    pub allowed_content_types: Vec<String>,
+    /// Tell the backend about the client and the URL it requested; unset means nothing is added
+    pub forwarded: Option<ForwardedSpec>,
+    /// Path below each target which `/ready` probes; unset means the API isn't probed
+    pub readiness_probe: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct ForwardedSpec {
+    #[serde(default = "_default_forwarded_headers")]
+    pub headers: Vec<ForwardedHeaders>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ForwardedHeaders {
+    /// The `Forwarded` header of RFC 7239
+    Forwarded,
+    /// `X-Forwarded-For`, `X-Forwarded-Proto`, `X-Forwarded-Host` and `X-Forwarded-Prefix`
+    XForwarded,
+}
+
+/// Limits of responses which the backend streams, like Server-Sent Events; all in seconds and unset by default
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct StreamingSpec {
+    /// Without data from the backend for this long, the response ends
+    pub idle_timeout: Option<u32>,
+    /// The response ends after this long, whether the backend is done or not
+    pub max_duration: Option<u32>,
+    /// Without data from the backend for this long, a comment is sent on `text/event-stream` responses
+    pub heartbeat: Option<u32>,
+}
+
+/// Timeouts in seconds of outgoing requests; `null` means none
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct TimeoutsSpec {
+    /// Until the connection is established
+    #[serde(default = "_default_connect_timeout")]
+    pub connect: Option<u16>,
+    /// Until the response headers have arrived
+    #[serde(default = "_default_response_headers_timeout")]
+    pub response_headers: Option<u16>,
+    /// Until the response body has arrived
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub total: Option<u16>,
+}
+
+impl Default for TimeoutsSpec {
+    fn default() -> Self {
+        TimeoutsSpec { connect: _default_connect_timeout(), response_headers: _default_response_headers_timeout(), total: None }
+    }
+}
+
+/// Retries of failed requests; those which aren't idempotent only if they couldn't connect, and those with a body never
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct RetrySpec {
+    /// Retries after the first attempt
+    #[serde(default = "_default_2")]
+    pub attempts: u16,
+    #[serde(default = "_default_initial_backoff")]
+    pub initial_backoff_ms: u32,
+    #[serde(default = "_default_max_backoff")]
+    pub max_backoff_ms: u32,
+    /// Response statuses worth a retry, besides connection errors and timeouts
+    #[serde(default = "_default_retry_statuses")]
+    pub statuses: Vec<u16>,
+    /// Retries as a fraction of requests, on top of a reserve for bursts
+    #[serde(default = "_default_retry_budget")]
+    pub budget: f64,
+}
+
+/// Fail fast while the other side keeps failing
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct CircuitBreakerSpec {
+    /// Failed requests in a row which open the circuit
+    #[serde(default = "_default_5")]
+    pub failure_threshold: u16,
+    /// Seconds until a single request may try again
+    #[serde(default = "_default_30")]
+    pub open_duration: u16,
+}
+
+/// Several backends serving the same API
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct UpstreamSpec {
+    pub targets: Vec<String>,
+    #[serde(default)]
+    pub strategy: Strategy,
+    /// Take targets out of rotation for a while after consecutive failures; unset means never
+    pub ejection: Option<EjectionSpec>,
+    /// Probe targets periodically and only use healthy ones; unset means all targets count as healthy
+    pub health_check: Option<HealthCheckSpec>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Strategy {
+    #[default]
+    RoundRobin,
+    /// The target with the fewest requests in flight
+    LeastConnections,
+    /// The same target for the same user, by the `sub` claim; round robin for anonymous requests
+    ConsistentHash,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct EjectionSpec {
+    /// Connection errors and 5xx responses in a row
+    #[serde(default = "_default_5")]
+    pub consecutive_failures: u16,
+    /// Seconds to leave an ejected target out
+    #[serde(default = "_default_30")]
+    pub duration: u16,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct HealthCheckSpec {
+    /// Path below each target, which answers with 2xx while it is healthy
+    pub path: String,
+    /// Seconds between probes
+    #[serde(default = "_default_10")]
+    pub interval: u16,
+    /// Seconds to wait for an answer
+    #[serde(default = "_default_2")]
+    pub timeout: u16,
+    /// Failed probes in a row before a target counts as unhealthy
+    #[serde(default = "_default_2")]
+    pub unhealthy_threshold: u16,
+    /// Successful probes in a row before an unhealthy target counts as healthy again
+    #[serde(default = "_default_2")]
+    pub healthy_threshold: u16,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct RouteSpec {
+    /// Path prefix below the API, matching whole segments
+    pub prefix: Option<String>,
+    /// Regular expression the path below the API has to match as a whole, as an alternative to `prefix`
+    pub regex: Option<String>,
+    /// Empty means any method
+    #[serde(default)]
+    pub methods: Vec<String>,
+    /// Path for the backend with `${captures.<name or index>}` placeholders; unset means the path as it is
+    pub rewrite: Option<String>,
+    /// Backend for this route; unset means the API's
+    pub backend: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum AuthMode {
+    /// Reject requests without a session
+    #[default]
+    Required,
+    /// Forward requests with the user's token if there is a session, and without otherwise
+    Optional,
+    /// Never look at the session
+    None,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct ClientCredentialsSpec {
+    /// Scope to request; unset means the client's default scopes
+    pub scope: Option<String>,
+}
+
+/// Claim-based access rules, checked before a request is forwarded
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct AuthorizationSpec {
+    /// Decision for requests no rule matches
+    #[serde(default)]
+    pub default: DefaultPolicy,
+    /// Dot-separated path of the claim listing the user's roles
+    #[serde(default = "_default_roles_claim")]
+    pub roles_claim: String,
+    /// Rules by name, tried in order; the first one matching method and path decides
+    #[serde(default, rename = "rule")]
+    pub rules: hcl::Map<String, RuleSpec>,
+}
+
+impl Default for AuthorizationSpec {
+    fn default() -> Self {
+        AuthorizationSpec { default: DefaultPolicy::default(), roles_claim: _default_roles_claim(), rules: hcl::Map::new() }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum DefaultPolicy {
+    #[default]
+    Allow,
+    Deny,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct RuleSpec {
+    /// Empty means any method
+    #[serde(default)]
+    pub methods: Vec<String>,
+    /// Path below the API, where `*` matches within a segment and `**` across segments; unset means any path
+    pub path: Option<String>,
+    /// Roles the user needs all of
+    #[serde(default)]
+    pub roles: Vec<String>,
+    /// Scopes the access token needs all of
+    #[serde(default)]
+    pub scopes: Vec<String>,
+    /// Predicates like `realm_access.roles contains "admin"`, which all have to hold
+    #[serde(default)]
+    pub claims: Vec<String>,
+    /// Reject matching requests whatever their claims
+    #[serde(default)]
+    pub deny: bool,
+    /// Overrides the API's `auth` for matching requests
+    pub auth: Option<AuthMode>,
+}
+
+/// Send claims as signed headers instead of the bearer token
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct IdentitySpec {
+    /// Header names to dot-separated claim paths
+    pub headers: hcl::Map<String, String>,
+    /// Secret in base64 shared with the backend only; never one of the keys, which the backend could open cookies with
+    pub secret: String,
+    #[serde(default = "_default_signature_header")]
+    pub signature_header: String,
+}
+
+/// Rules for request headers, applied after `headers` in the order remove, rename, set
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct RequestHeadersSpec {
+    /// Header names to values, which may contain `${claims.<path>}` and `${env.<NAME>}`
+    #[serde(default)]
+    pub set: hcl::Map<String, String>,
+    /// Old header names to new ones
+    #[serde(default)]
+    pub rename: hcl::Map<String, String>,
+    #[serde(default)]
+    pub remove: Vec<String>,
+}
+
+/// Cross-origin resource sharing policy; the most specific one of API, bridge and global config wins
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct CorsSpec {
+    #[serde(default)]
+    pub allowed_origins: Vec<String>,
+    /// Regular expressions an origin has to match as a whole
+    #[serde(default)]
+    pub allowed_origin_patterns: Vec<String>,
+    /// Empty means any method
+    #[serde(default)]
+    pub allowed_methods: Vec<String>,
+    /// Empty means any header
+    #[serde(default)]
+    pub allowed_headers: Vec<String>,
+    #[serde(default)]
+    pub exposed_headers: Vec<String>,
+    #[serde(default = "_default_3600")]
+    pub max_age: u32,
+    /// Allow any origin with credentials, which is only ever acceptable during development
+    #[serde(default)]
+    pub dev_mode: bool,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct CookieSpec {
+    #[serde(default = "_default_cookie_name")]
+    pub name: String,
+    #[serde(default)]
+    pub host_prefix: bool,
+    pub domain: Option<String>,
+    pub path: Option<String>,
+    #[serde(default)]
+    pub same_site: SameSitePolicy,
+}
+
+impl Default for CookieSpec {
+    fn default() -> Self {
+        CookieSpec { name: _default_cookie_name(), host_prefix: false, domain: None, path: None, same_site: SameSitePolicy::default() }
+    }
+}
+
+/// Defences against cross-site request forgery, which can be combined
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct CsrfSpec {
+    /// Require this request header, which can't be set cross-origin without a CORS preflight
+    pub header: Option<String>,
+    #[serde(default)]
+    pub check_origin: bool,
+    #[serde(default)]
+    pub allowed_origins: Vec<String>,
+    #[serde(default)]
+    pub double_submit: bool,
+    #[serde(default = "_default_csrf_cookie")]
+    pub token_cookie: String,
+    #[serde(default = "_default_csrf_header")]
+    pub token_header: String,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SameSitePolicy {
+    #[default]
+    Strict,
+    Lax,
+    None,
+}
+
+/// What to do when the IDP won't revoke our tokens on logout
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum RevocationFailure {
+    /// Log the failure and log out locally anyway
+    #[default]
+    Continue,
+    /// Abort the logout with an error, keeping the session
+    Fail,
 }
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
@@ -43,6 +554,29 @@ pub struct KeySpec {
 }
 
 const fn _default_8080() -> u16 { 8080 }
+const fn _default_2() -> u16 { 2 }
+const fn _default_connect_timeout() -> Option<u16> { Some(10) }
+const fn _default_response_headers_timeout() -> Option<u16> { Some(60) }
+const fn _default_initial_backoff() -> u32 { 100 }
+const fn _default_max_backoff() -> u32 { 2000 }
+const fn _default_retry_budget() -> f64 { 0.2 }
+fn _default_retry_statuses() -> Vec<u16> { vec![502, 503, 504] }
+const fn _default_5() -> u16 { 5 }
+const fn _default_10() -> u16 { 10 }
 const fn _default_30() -> u16 { 30 }
+const fn _default_60() -> u32 { 60 }
+const fn _default_3600() -> u32 { 3600 }
+const fn _default_true() -> bool { true }
+const fn _default_sample_ratio() -> f64 { 1.0 }
+fn _default_service_name() -> String { "token-handler".into() }
 fn _default_openid() -> String { "openid".into() }
+fn _default_cookie_name() -> String { "bff-session".into() }
+fn _default_csrf_cookie() -> String { "bff-csrf".into() }
+fn _default_request_id_header() -> String { "x-request-id".into() }
+fn _default_csrf_header() -> String { "x-csrf-token".into() }
+fn _default_signature_header() -> String { token_handler::identity::SIGNATURE_HEADER.into() }
+fn _default_roles_claim() -> String { "realm_access.roles".into() }
 fn _default_headers() -> Vec<String> { vec!["content-type".into() ]}
+fn _default_readiness_gates() -> Vec<ReadinessCheck> { vec![ReadinessCheck::Discovery, ReadinessCheck::Jwks] }
+fn _default_forwarded_headers() -> Vec<ForwardedHeaders> { vec![ForwardedHeaders::Forwarded, ForwardedHeaders::XForwarded] }
+fn _default_strip_response_headers() -> Vec<String> { vec!["server".into(), "x-powered-by".into()] }
@@ -1,4 +1,4 @@
-/// Helper for proper error propagation when substituting environment variables
+//! Helper for proper error propagation when substituting environment variables
 
 use std::collections::HashMap;
 
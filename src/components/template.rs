@@ -0,0 +1,79 @@
+//! Header value templates over token claims and environment variables
+
+use serde::{Serialize, Serializer};
+use serde_json::Value;
+use crate::error::ConfigError;
+use crate::systems::token::{claim, claim_to_string};
+
+/// A string with placeholders `${claims.<path>}`, resolved per request, and `${env.<NAME>}`, resolved at startup
+pub struct Template {
+    source: String,
+    segments: Vec<Segment>,
+    secret: bool,
+}
+
+enum Segment {
+    Literal(String),
+    Claim(String),
+}
+
+impl Template {
+    pub fn new(source: &str) -> Result<Self, ConfigError> {
+        let invalid = |reason| ConfigError::InvalidTemplate(source.into(), reason);
+        let mut segments = Vec::new();
+        let mut secret = false;
+        let mut rest = source;
+        while let Some(start) = rest.find("${") {
+            let (literal, placeholder) = rest.split_at(start);
+            let end = placeholder.find('}').ok_or_else(|| invalid("unterminated placeholder"))?;
+            let name = &placeholder[2..end];
+            push_literal(&mut segments, literal);
+            match name.split_once('.') {
+                Some(("claims", path)) if !path.is_empty() => segments.push(Segment::Claim(path.into())),
+                Some(("env", var)) => {
+                    let value = std::env::var(var).map_err(|_| ConfigError::MissingVariable(var.into()))?;
+                    push_literal(&mut segments, &value);
+                    secret = true;
+                },
+                _ => return Err(invalid("expected ${claims.<path>} or ${env.<NAME>}")),
+            }
+            rest = &placeholder[end + 1..];
+        }
+        push_literal(&mut segments, rest);
+        Ok(Template { source: source.into(), segments, secret })
+    }
+
+    /// Whether the template doesn't depend on the request
+    pub fn is_static(&self) -> bool {
+        self.segments.iter().all(|s| matches!(s, Segment::Literal(_)))
+    }
+
+    /// Renders the template; `None` if a claim is missing
+    pub fn render(&self, claims: &Value) -> Option<String> {
+        self.segments.iter().map(|segment| match segment {
+            Segment::Literal(literal) => Some(literal.clone()),
+            Segment::Claim(path) => claim(claims, path).and_then(claim_to_string),
+        }).collect()
+    }
+}
+
+impl Serialize for Template {
+    /// Serialises the template as written, unless it contains secrets from the environment
+    fn serialize<S>(&self, ser: S) -> Result<S::Ok, S::Error> where S: Serializer {
+        match self.secret {
+            true => ser.serialize_str("*****"),
+            false => ser.serialize_str(&self.source),
+        }
+    }
+}
+
+fn push_literal(segments: &mut Vec<Segment>, literal: &str) {
+    if literal.is_empty() {
+        return;
+    }
+    match segments.last_mut() {
+        Some(Segment::Literal(previous)) => previous.push_str(literal),
+        _ => segments.push(Segment::Literal(literal.into())),
+    }
+}
+
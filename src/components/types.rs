@@ -8,6 +8,8 @@ pub struct LoginCookie {
     pub bff_redirect_uri: String,
     pub post_login_redirect: String,
     pub code_verifier: String,
+    #[serde(default)]
+    pub remember: bool,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -15,8 +17,25 @@ pub struct SessionCookie {
     pub access_token: String,
     pub refresh_token: String,
     pub id_token: String,
-}
-
+    /// Session id of the IDP, if it told us; needed to match front-channel logout requests
+    #[serde(default)]
+    pub sid: Option<String>,
+    /// Unix timestamp of the login; 0 for sessions from before it was tracked
+    #[serde(default)]
+    pub created_at: i64,
+    /// Unix timestamp of the last time the session was used, give or take the refresh interval; 0 for sessions from
+    /// before it was tracked
+    #[serde(default)]
+    pub last_seen: i64,
+    /// Whether the session should survive closing the browser
+    #[serde(default)]
+    pub remember: bool,
+    /// Token the client has to echo on unsafe requests, if double-submit CSRF protection is enabled
+    #[serde(default)]
+    pub csrf_token: Option<String>,
+}
+
+#[allow(dead_code)]
 #[derive(Deserialize, Debug)]
 pub struct TokenResponse {
     pub access_token: String,
@@ -31,17 +50,27 @@ pub struct TokenResponse {
     pub id_token: String,
 }
 
+/// Token of the client itself, which comes without refresh or id token
+#[derive(Deserialize, Debug)]
+pub struct ClientTokenResponse {
+    pub access_token: String,
+    pub expires_in: u32,
+}
+
 #[derive(Deserialize, Debug)]
 pub struct IdTokenClaims {
     pub nonce: String,
     pub preferred_username: String,
+    pub sid: Option<String>,
 }
 
+#[allow(dead_code)]
 #[derive(Deserialize, Debug)]
 pub struct AccessTokenClaims {
     pub exp: u32,
     pub iat: u32,
     pub preferred_username: String,
+    pub sub: Option<String>,
 }
 
 #[derive(Deserialize, Debug)]
@@ -54,12 +83,15 @@ pub struct IntrospectionClaims {
     pub active: bool,
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Serialize, Debug)]
 pub struct OpenidConfiguration {
+    pub issuer: String,
     pub token_endpoint: String,
     pub authorization_endpoint: String,
-    pub end_session_endpoint: String,
+    pub end_session_endpoint: Option<String>,
     pub introspection_endpoint: String,
+    pub revocation_endpoint: Option<String>,
+    pub jwks_uri: Option<String>,
 }
 
 #[derive(Serialize)]
@@ -92,6 +124,14 @@ pub struct IntrospectionRequest<'a> {
     pub token: &'a str
 }
 
+#[derive(Serialize)]
+pub struct RevocationRequest<'a> {
+    #[serde(flatten)]
+    pub auth: ClientAuth<'a>,
+    pub token: &'a str,
+    pub token_type_hint: &'a str,
+}
+
 #[derive(Serialize)]
 #[serde(tag = "grant_type")]
 pub enum TokenRequestDetails<'a> {
@@ -99,6 +139,11 @@ pub enum TokenRequestDetails<'a> {
     RefreshToken { refresh_token: &'a str },
     #[serde(rename = "authorization_code")]
     AuthorizationCode { code: &'a str, redirect_uri: &'a str, code_verifier: &'a str },
+    #[serde(rename = "client_credentials")]
+    ClientCredentials {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        scope: Option<&'a str>,
+    },
 }
 
 #[derive(Serialize)]
@@ -113,9 +158,24 @@ pub struct LoginRequest<'a> {
         pub nonce: &'a str,
 }
 
+/// Query of the end session endpoint, cf. OpenID Connect RP-Initiated Logout 1.0
+#[derive(Serialize)]
+pub struct EndSessionRequest<'a> {
+    pub client_id: &'a str,
+    pub post_logout_redirect_uri: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id_token_hint: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub state: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ui_locales: Option<&'a str>,
+}
+
 #[derive(Deserialize)]
 pub struct LoginQuery {
     pub redirect: String,
+    #[serde(default)]
+    pub remember: bool,
 }
 
 #[derive(Deserialize)]
@@ -0,0 +1,351 @@
+//! Pools of backend targets with load balancing and outlier ejection
+
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicI64, AtomicU16, AtomicUsize, Ordering};
+use serde::Serializer;
+use serde::ser::SerializeSeq;
+use serde_derive::Serialize;
+use url::Url;
+use crate::components::config::serialize_optional_block;
+use crate::components::route::backend_url;
+use crate::components::spec::{EjectionSpec, HealthCheckSpec, Strategy, UpstreamSpec};
+use crate::error::ConfigError;
+
+#[derive(Serialize)]
+pub struct Upstream {
+    #[serde(serialize_with = "serialize_targets")]
+    pub targets: Vec<Arc<Target>>,
+    pub strategy: Strategy,
+    #[serde(skip_serializing_if = "Option::is_none", serialize_with = "serialize_optional_block")]
+    pub ejection: Option<EjectionSpec>,
+    #[serde(skip_serializing_if = "Option::is_none", serialize_with = "serialize_optional_block")]
+    pub health_check: Option<HealthCheckSpec>,
+    #[serde(skip_serializing)]
+    next: AtomicUsize,
+}
+
+pub struct Target {
+    pub url: Url,
+    in_flight: AtomicUsize,
+    /// Failed requests in a row
+    failures: AtomicU16,
+    ejected_until: AtomicI64,
+    healthy: AtomicBool,
+    /// Probes in a row which disagree with `healthy`
+    probes: AtomicU16,
+}
+
+/// A target picked for a request, which counts as in flight until dropped
+pub struct Lease {
+    target: Arc<Target>,
+}
+
+impl Upstream {
+    pub fn new(api_id: &str, value: &UpstreamSpec) -> Result<Self, ConfigError> {
+        if value.targets.is_empty() {
+            return Err(ConfigError::InvalidUpstream(api_id.into(), "needs at least one target"));
+        }
+        if value.ejection.as_ref().is_some_and(|ejection| ejection.consecutive_failures == 0) {
+            return Err(ConfigError::InvalidUpstream(api_id.into(), "ejection needs consecutive_failures of at least 1"));
+        }
+        if let Some(ref health_check) = value.health_check {
+            if health_check.interval == 0 {
+                return Err(ConfigError::InvalidUpstream(api_id.into(), "health_check needs an interval of at least 1"));
+            }
+            if health_check.unhealthy_threshold == 0 || health_check.healthy_threshold == 0 {
+                return Err(ConfigError::InvalidUpstream(api_id.into(), "health_check needs thresholds of at least 1"));
+            }
+        }
+        let targets = value.targets.iter()
+            .map(|url| backend_url(url).map(Target::new))
+            .collect::<Result<_, _>>()?;
+        Ok(Upstream {
+            targets,
+            strategy: value.strategy,
+            ejection: value.ejection.clone(),
+            health_check: value.health_check.clone(),
+            next: AtomicUsize::new(0),
+        })
+    }
+
+    /// An upstream of a single backend
+    pub fn single(url: Url) -> Self {
+        Upstream {
+            targets: vec![Target::new(url)],
+            strategy: Strategy::default(),
+            ejection: None,
+            health_check: None,
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    /// Picks a target for a request by `key`, i.e. the user's subject; unless none is, only from the available ones
+    pub fn pick(&self, key: Option<&str>, now: i64) -> Lease {
+        let available = self.targets.iter().filter(|t| t.available(now)).collect::<Vec<_>>();
+        let candidates = match available.is_empty() {
+            true => self.targets.iter().collect(),
+            false => available,
+        };
+        let offset = self.next.fetch_add(1, Ordering::Relaxed);
+        let target = match (self.strategy, key) {
+            (Strategy::ConsistentHash, Some(key)) => candidates.iter()
+                .max_by_key(|t| score(key, &t.url))
+                .copied(),
+            (Strategy::LeastConnections, _) => (0..candidates.len())
+                .map(|i| candidates[(offset + i) % candidates.len()])
+                .min_by_key(|t| t.in_flight.load(Ordering::Relaxed)),
+            _ => candidates.get(offset % candidates.len()).copied(),
+        }.unwrap_or(&self.targets[0]);
+        target.in_flight.fetch_add(1, Ordering::Relaxed);
+        Lease { target: target.clone() }
+    }
+
+    /// Records how a request to a target went; `true` if the target got ejected because of it
+    pub fn report(&self, lease: &Lease, success: bool, now: i64) -> bool {
+        let target = &lease.target;
+        if success {
+            target.failures.store(0, Ordering::Relaxed);
+            return false;
+        }
+        let Some(ref ejection) = self.ejection else {
+            return false;
+        };
+        let failures = target.failures.fetch_add(1, Ordering::Relaxed) + 1;
+        if failures < ejection.consecutive_failures {
+            return false;
+        }
+        target.failures.store(0, Ordering::Relaxed);
+        target.ejected_until.store(now + ejection.duration as i64, Ordering::Relaxed);
+        true
+    }
+}
+
+impl Target {
+    fn new(url: Url) -> Arc<Self> {
+        Arc::new(Target {
+            url,
+            in_flight: AtomicUsize::new(0),
+            failures: AtomicU16::new(0),
+            ejected_until: AtomicI64::new(0),
+            healthy: AtomicBool::new(true),
+            probes: AtomicU16::new(0),
+        })
+    }
+
+    fn available(&self, now: i64) -> bool {
+        self.healthy.load(Ordering::Relaxed) && self.ejected_until.load(Ordering::Relaxed) <= now
+    }
+
+    /// Records the outcome of a health probe; the new state if it changes
+    pub fn record_probe(&self, success: bool, check: &HealthCheckSpec) -> Option<bool> {
+        if success == self.healthy.load(Ordering::Relaxed) {
+            self.probes.store(0, Ordering::Relaxed);
+            return None;
+        }
+        let probes = self.probes.fetch_add(1, Ordering::Relaxed) + 1;
+        let threshold = match success {
+            true => check.healthy_threshold,
+            false => check.unhealthy_threshold,
+        };
+        if probes < threshold {
+            return None;
+        }
+        self.probes.store(0, Ordering::Relaxed);
+        self.healthy.store(success, Ordering::Relaxed);
+        Some(success)
+    }
+}
+
+impl Lease {
+    pub fn url(&self) -> &Url {
+        &self.target.url
+    }
+}
+
+impl Drop for Lease {
+    fn drop(&mut self) {
+        self.target.in_flight.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Rendezvous hashing, so that users only move when their target goes away
+fn score(key: &str, url: &Url) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    url.as_str().hash(&mut hasher);
+    hasher.finish()
+}
+
+fn serialize_targets<S>(input: &[Arc<Target>], ser: S) -> Result<S::Ok, S::Error> where S: Serializer {
+    let mut seq = ser.serialize_seq(Some(input.len()))?;
+    for target in input.iter() {
+        seq.serialize_element(target.url.as_str())?;
+    }
+    seq.end()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOW: i64 = 1_700_000_000;
+
+    fn upstream(hcl: &str) -> Upstream {
+        Upstream::new("api", &hcl::from_str::<UpstreamSpec>(hcl).unwrap()).unwrap()
+    }
+
+    fn three(extra: &str) -> Upstream {
+        upstream(&format!(r#"
+            targets = ["http://a", "http://b", "http://c"]
+            {extra}
+        "#))
+    }
+
+    fn host(lease: &Lease) -> &str {
+        lease.url().host_str().unwrap()
+    }
+
+    fn check(healthy_threshold: u16, unhealthy_threshold: u16) -> HealthCheckSpec {
+        HealthCheckSpec { path: "/health".into(), interval: 10, timeout: 2, unhealthy_threshold, healthy_threshold }
+    }
+
+    #[test]
+    fn round_robin_rotates() {
+        let upstream = three("");
+        let hosts = (0..6).map(|_| host(&upstream.pick(None, NOW)).to_string()).collect::<Vec<_>>();
+        assert_eq!(hosts, ["a", "b", "c", "a", "b", "c"]);
+    }
+
+    #[test]
+    fn least_connections_prefers_fewer_leases() {
+        let upstream = three(r#"strategy = "least_connections""#);
+        let a = upstream.pick(None, NOW);
+        let b = upstream.pick(None, NOW);
+        assert_eq!((host(&a), host(&b)), ("a", "b"));
+        assert_eq!(host(&upstream.pick(None, NOW)), "c");
+        let c = upstream.pick(None, NOW);
+        assert_eq!(host(&c), "c");
+        // a, b and c have one lease each, so dropping b's makes b the least busy
+        drop(b);
+        assert_eq!(host(&upstream.pick(None, NOW)), "b");
+        drop((a, c));
+        assert!(upstream.targets.iter().all(|t| t.in_flight.load(Ordering::Relaxed) == 0));
+    }
+
+    #[test]
+    fn consistent_hash_keeps_users_on_their_target() {
+        let upstream = three(r#"strategy = "consistent_hash""#);
+        for user in ["alice", "bob", "carol", "dave"] {
+            let first = host(&upstream.pick(Some(user), NOW)).to_string();
+            assert!((0..5).all(|_| host(&upstream.pick(Some(user), NOW)) == first), "{user}");
+        }
+        let hosts = (0..50).map(|i| host(&upstream.pick(Some(&format!("user{i}")), NOW)).to_string()).collect::<std::collections::HashSet<_>>();
+        assert_eq!(hosts.len(), 3);
+    }
+
+    #[test]
+    fn consistent_hash_only_moves_users_of_ejected_target() {
+        let upstream = three(r#"
+            strategy = "consistent_hash"
+            ejection {
+              consecutive_failures = 1
+              duration = 30
+            }
+        "#);
+        // anonymous requests start with the first target
+        let a = upstream.pick(None, NOW);
+        let users = (0..30).map(|i| format!("user{i}")).collect::<Vec<_>>();
+        let before = users.iter().map(|user| host(&upstream.pick(Some(user), NOW)).to_string()).collect::<Vec<_>>();
+        assert!(upstream.report(&a, false, NOW));
+        for (user, before) in users.iter().zip(before) {
+            let after = host(&upstream.pick(Some(user), NOW)).to_string();
+            match before.as_str() {
+                "a" => assert_ne!(after, "a"),
+                _ => assert_eq!(after, before),
+            }
+        }
+    }
+
+    #[test]
+    fn anonymous_requests_are_balanced_round_robin() {
+        let upstream = three(r#"strategy = "consistent_hash""#);
+        let hosts = (0..3).map(|_| host(&upstream.pick(None, NOW)).to_string()).collect::<Vec<_>>();
+        assert_eq!(hosts, ["a", "b", "c"]);
+    }
+
+    #[test]
+    fn ejection_after_consecutive_failures() {
+        let upstream = three(r#"
+            ejection {
+              consecutive_failures = 2
+              duration = 30
+            }
+        "#);
+        let a = upstream.pick(None, NOW);
+        assert!(!upstream.report(&a, false, NOW));
+        // a success in between starts the count anew
+        assert!(!upstream.report(&a, true, NOW));
+        assert!(!upstream.report(&a, false, NOW));
+        assert!(upstream.report(&a, false, NOW));
+        let hosts = (0..4).map(|_| host(&upstream.pick(None, NOW + 29)).to_string()).collect::<Vec<_>>();
+        assert!(!hosts.contains(&"a".to_string()), "{hosts:?}");
+        let hosts = (0..3).map(|_| host(&upstream.pick(None, NOW + 30)).to_string()).collect::<Vec<_>>();
+        assert!(hosts.contains(&"a".to_string()), "{hosts:?}");
+    }
+
+    #[test]
+    fn without_ejection_failures_are_ignored() {
+        let upstream = three("");
+        let a = upstream.pick(None, NOW);
+        assert!((0..10).all(|_| !upstream.report(&a, false, NOW)));
+        assert!(upstream.targets.iter().all(|t| t.available(NOW)));
+    }
+
+    #[test]
+    fn falls_back_to_all_targets_when_none_is_available() {
+        let upstream = upstream(r#"
+            targets = ["http://a", "http://b"]
+            ejection {
+              consecutive_failures = 1
+              duration = 30
+            }
+        "#);
+        let a = upstream.pick(None, NOW);
+        let b = upstream.pick(None, NOW);
+        assert!(upstream.report(&a, false, NOW));
+        assert!(upstream.report(&b, false, NOW));
+        let hosts = (0..4).map(|_| host(&upstream.pick(None, NOW)).to_string()).collect::<Vec<_>>();
+        assert_eq!(hosts, ["a", "b", "a", "b"]);
+    }
+
+    #[test]
+    fn health_transitions_at_thresholds() {
+        let target = Target::new(Url::parse("http://a/").unwrap());
+        let check = check(2, 3);
+        assert_eq!(target.record_probe(true, &check), None);
+        assert_eq!(target.record_probe(false, &check), None);
+        assert_eq!(target.record_probe(false, &check), None);
+        // a success in between starts the count anew
+        assert_eq!(target.record_probe(true, &check), None);
+        assert_eq!(target.record_probe(false, &check), None);
+        assert_eq!(target.record_probe(false, &check), None);
+        assert!(target.available(NOW));
+        assert_eq!(target.record_probe(false, &check), Some(false));
+        assert!(!target.available(NOW));
+        assert_eq!(target.record_probe(false, &check), None);
+        assert_eq!(target.record_probe(true, &check), None);
+        assert_eq!(target.record_probe(true, &check), Some(true));
+        assert!(target.available(NOW));
+    }
+
+    #[test]
+    fn unhealthy_targets_are_skipped() {
+        let upstream = three("");
+        let check = check(1, 1);
+        upstream.targets[1].record_probe(false, &check);
+        let hosts = (0..4).map(|_| host(&upstream.pick(None, NOW)).to_string()).collect::<Vec<_>>();
+        assert!(!hosts.contains(&"b".to_string()), "{hosts:?}");
+    }
+}
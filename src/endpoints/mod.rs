@@ -3,10 +3,18 @@ mod mod_logout;
 mod mod_proxy;
 mod mod_login;
 mod mod_health;
+mod mod_metrics;
+mod mod_ready;
+mod mod_admin;
 
 pub use mod_me::me;
 pub use mod_logout::logout;
+pub use mod_logout::logout_silently;
+pub use mod_logout::frontchannel_logout;
 pub use mod_proxy::proxy;
 pub use mod_login::login;
 pub use mod_login::login2;
-pub use mod_health::health;
\ No newline at end of file
+pub use mod_health::health;
+pub use mod_metrics::metrics;
+pub use mod_ready::ready;
+pub use mod_admin::admin;
\ No newline at end of file
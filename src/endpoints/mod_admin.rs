@@ -0,0 +1,147 @@
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use actix_web::{get, post, HttpResponse, Responder, web};
+use actix_web::http::header;
+use chrono::{DateTime, SecondsFormat};
+use log::{info, warn};
+use serde_derive::Serialize;
+use serde_json::json;
+use crate::components::config::Bridge;
+use crate::components::types::OpenidConfiguration;
+use crate::systems::admin::Admin;
+use crate::systems::crypto::fingerprint;
+use crate::systems::readiness::check;
+
+/// Endpoints of the admin listener
+pub fn admin(cfg: &mut web::ServiceConfig) {
+    cfg.service(effective_config)
+        .service(keys)
+        .service(bridges)
+        .service(refresh_discovery)
+        .service(build)
+        .service(readiness)
+        .service(reload);
+}
+
+/// The effective config, as logged at start, with secrets masked
+#[get("/config")]
+async fn effective_config(admin: web::Data<Admin>) -> impl Responder {
+    let (config, _) = admin.config();
+    match hcl::to_string(&config) {
+        Ok(body) => HttpResponse::Ok()
+            .insert_header((header::CONTENT_TYPE, mime::TEXT_PLAIN_UTF_8))
+            .body(body),
+        Err(e) => HttpResponse::InternalServerError().json(json!({ "error": e.to_string() })),
+    }
+}
+
+#[derive(Serialize)]
+struct KeyInfo<'a> {
+    id: &'a str,
+    /// `active` keys seal new cookies, `inactive` ones only open existing cookies
+    status: &'static str,
+    fingerprint: String,
+}
+
+#[get("/keys")]
+async fn keys(admin: web::Data<Admin>) -> impl Responder {
+    let (config, _) = admin.config();
+    let mut keys = config.keys.iter()
+        .map(|(id, key)| KeyInfo {
+            id,
+            status: if key.active { "active" } else { "inactive" },
+            fingerprint: fingerprint(&key.value),
+        })
+        .collect::<Vec<_>>();
+    keys.sort_by_key(|key| key.id);
+    HttpResponse::Ok().json(keys)
+}
+
+#[derive(Serialize)]
+struct BridgeInfo {
+    idp: String,
+    /// Absent until the discovery document is first needed
+    discovery: Option<Discovery>,
+}
+
+#[derive(Serialize)]
+struct Discovery {
+    loaded_at: String,
+    document: Arc<OpenidConfiguration>,
+}
+
+impl From<&Bridge> for BridgeInfo {
+    fn from(bridge: &Bridge) -> Self {
+        BridgeInfo {
+            idp: bridge.idp.clone(),
+            discovery: bridge.loaded_idp_configuration().map(|(document, loaded_at)| Discovery {
+                loaded_at: timestamp(loaded_at),
+                document,
+            }),
+        }
+    }
+}
+
+#[get("/bridges")]
+async fn bridges(admin: web::Data<Admin>) -> impl Responder {
+    let (config, _) = admin.config();
+    let bridges = config.bridges.iter()
+        .map(|(id, bridge)| (id.as_str(), BridgeInfo::from(bridge.as_ref())))
+        .collect::<BTreeMap<_, _>>();
+    HttpResponse::Ok().json(bridges)
+}
+
+/// Fetches the bridge's discovery document anew; the previous one is kept if that fails
+#[post("/bridges/{bridge}/discovery")]
+async fn refresh_discovery(admin: web::Data<Admin>, path: web::Path<String>) -> impl Responder {
+    let (config, _) = admin.config();
+    let Some(bridge) = config.bridges.get(path.as_str()) else {
+        return HttpResponse::NotFound().json(json!({ "error": "unknown bridge" }));
+    };
+    match bridge.load_idp_configuration().await {
+        Ok(_) => HttpResponse::Ok().json(BridgeInfo::from(bridge.as_ref())),
+        Err(e) => HttpResponse::BadGateway().json(json!({ "error": e.to_string() })),
+    }
+}
+
+#[get("/build")]
+async fn build(admin: web::Data<Admin>) -> impl Responder {
+    HttpResponse::Ok().json(json!({
+        "name": env!("CARGO_PKG_NAME"),
+        "version": env!("CARGO_PKG_VERSION"),
+        "profile": if cfg!(debug_assertions) { "debug" } else { "release" },
+        "target": format!("{}-{}", std::env::consts::ARCH, std::env::consts::OS),
+        "started_at": timestamp(admin.started_at),
+    }))
+}
+
+/// Runs the checks of `/ready` anew and tells how each went
+#[get("/ready")]
+async fn readiness(admin: web::Data<Admin>) -> impl Responder {
+    let (config, _) = admin.config();
+    let report = check(&config).await;
+    match report.ready {
+        true => HttpResponse::Ok().json(report),
+        false => HttpResponse::ServiceUnavailable().json(report),
+    }
+}
+
+/// Loads the config file again; requests under way are finished with the previous config
+#[post("/reload")]
+async fn reload(admin: web::Data<Admin>) -> impl Responder {
+    match admin.reload() {
+        Ok(_) => {
+            info!("Reload of config requested by admin");
+            let (_, loaded_at) = admin.config();
+            HttpResponse::Ok().json(json!({ "loaded_at": timestamp(loaded_at) }))
+        },
+        Err(e) => {
+            warn!("Unable to reload config: {e}");
+            HttpResponse::UnprocessableEntity().json(json!({ "error": e.to_string() }))
+        },
+    }
+}
+
+fn timestamp(seconds: i64) -> String {
+    DateTime::from_timestamp(seconds, 0).unwrap_or_default().to_rfc3339_opts(SecondsFormat::Secs, true)
+}
@@ -1,14 +1,16 @@
 use actix_web::http::header;
 use actix_web::{get, HttpRequest, HttpResponse, Responder, web};
-use actix_web::cookie::SameSite;
 use actix_web::dev::ConnectionInfo;
 use nanoid::nanoid;
-use crate::systems::cookies::{decode, create, SESSION_COOKIE_NAME};
+use crate::systems::cookies::{decode, create, get};
 use crate::components::types::{IdTokenClaims, Login2Query, LoginCookie, LoginQuery, LoginRequest, SessionCookie, TokenRequestDetails};
-use log::info;
 use crate::components::config::Bridge;
 use crate::error::ApiError;
 use crate::systems::crypto::hash;
+use crate::systems::csrf;
+use crate::systems::logging::Audit;
+use crate::systems::metrics;
+use crate::systems::session;
 use crate::systems::token::{claims, retrieve_token};
 
 #[get("/login")]
@@ -30,17 +32,19 @@ pub async fn login(bridge: web::Data<Bridge>, query: web::Query<LoginQuery>, con
         code_challenge: &hash(&code_verifier)?,
     })?;
 
+    let query = query.into_inner();
     let cookie_value = LoginCookie {
         nonce,
         state,
         code_verifier,
         bff_redirect_uri,
-        post_login_redirect: query.into_inner().redirect,
+        post_login_redirect: query.redirect,
+        remember: query.remember && bridge.persistent_session_max_age.is_some(),
     };
 
     Ok(HttpResponse::TemporaryRedirect()
         .insert_header((header::LOCATION, format!("{url}?{login_query}")))
-        .cookie(create(cookie_value, &bridge, SameSite::Lax)?)
+        .cookie(create(cookie_value, &bridge, bridge.cookie.login_same_site())?)
         .finish())
 }
 
@@ -50,10 +54,18 @@ pub async fn login2(
     query: web::Query<Login2Query>,
     bridge: web::Data<Bridge>,
 ) -> Result<impl Responder, ApiError> {
-    let cookie = decode::<LoginCookie>(&req.cookie(&SESSION_COOKIE_NAME).ok_or(ApiError::Unauthorized)?, &bridge)?;
+    let failed = |reason| {
+        metrics::login_failed(&bridge.id, reason);
+        Audit::new("login_failed", &bridge.id).reason(reason).log();
+    };
+    let cookie = get(&req, &bridge).ok_or(ApiError::Unauthorized)
+        .inspect_err(|_| failed("missing_cookie"))?;
+    let cookie = decode::<LoginCookie>(&cookie, &bridge)
+        .inspect_err(|_| failed("invalid_cookie"))?;
 
     // verify state
     if cookie.state != query.state {
+        failed("state_mismatch");
         return Err(ApiError::Unauthorized);
     }
 
@@ -61,23 +73,36 @@ pub async fn login2(
         code: &query.code,
         redirect_uri: &cookie.bff_redirect_uri,
         code_verifier: &cookie.code_verifier,
-    }).await?;
+    }).await.inspect_err(|_| failed("token_exchange"))?;
 
     // verify nonce
-    let claims = claims::<IdTokenClaims>(&response.id_token)?;
+    let claims = claims::<IdTokenClaims>(&response.id_token)
+        .inspect_err(|_| failed("invalid_id_token"))?;
     if claims.nonce != cookie.nonce {
+        failed("nonce_mismatch");
         return Err(ApiError::Unauthorized);
     }
 
-    info!("[{:<width$}] login   ({})", bridge.id, claims.preferred_username, width = bridge.config()?.log_padding);
+    Audit::new("login", &bridge.id).user(claims.preferred_username.as_str()).log();
+    metrics::login(&bridge.id);
 
+    let now = chrono::Utc::now().timestamp();
     let cookie_value = SessionCookie {
         access_token: response.access_token,
         refresh_token: response.refresh_token,
         id_token: response.id_token,
+        sid: claims.sid,
+        created_at: now,
+        last_seen: now,
+        remember: cookie.remember,
+        csrf_token: csrf::new_token(&bridge),
     };
-    Ok(HttpResponse::TemporaryRedirect()
+    let mut builder = HttpResponse::TemporaryRedirect();
+    if let Some(csrf_cookie) = csrf::cookie(&cookie_value, &bridge, now) {
+        builder.cookie(csrf_cookie);
+    }
+    Ok(builder
         .insert_header((header::LOCATION, cookie.post_login_redirect))
-        .cookie(create(cookie_value, &bridge, SameSite::Strict)?)
+        .cookie(session::cookie(&cookie_value, &bridge, now)?)
         .finish())
 }
\ No newline at end of file
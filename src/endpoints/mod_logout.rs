@@ -1,39 +1,147 @@
-use actix_web::{get, HttpRequest, HttpResponse, Responder, web};
-use actix_web::cookie::{Cookie, SameSite};
-use actix_web::cookie::time::OffsetDateTime;
+use actix_web::{get, post, HttpRequest, HttpResponse, Responder, web};
 use actix_web::http::header;
-use log::info;
+use url::Url;
 use crate::components::config::Bridge;
-use crate::error::ApiError;
-use crate::systems::token::claims;
-use crate::components::types::{IdTokenClaims, SessionCookie};
-use crate::systems::cookies::{decode, SESSION_COOKIE_NAME};
+use crate::components::spec::RevocationFailure;
+use crate::error::{ApiError, Context};
+use crate::systems::token::{claims, revoke_tokens};
+use crate::components::types::{EndSessionRequest, IdTokenClaims, SessionCookie};
+use crate::systems::cookies::{clear, decode, get};
+use crate::systems::csrf;
+use crate::systems::logging::Audit;
+use crate::systems::metrics;
 use serde_derive::Deserialize;
 
 #[get("/logout")]
 pub async fn logout(req: HttpRequest, bridge: web::Data<Bridge>, query: web::Query<LogoutQuery>) -> Result<impl Responder, ApiError> {
-    let cookie = decode::<SessionCookie>(&req.cookie(SESSION_COOKIE_NAME).ok_or(ApiError::Unauthorized)?, &bridge)?;
-    let logout_uri = &bridge.get_idp_configuration().await?.end_session_endpoint;
-    let redirect = query.into_inner()
-        .post_logout_redirect_uri
+    let cookie = decode::<SessionCookie>(&get(&req, &bridge).ok_or(ApiError::Unauthorized)?, &bridge)?;
+    let query = query.into_inner();
+    let redirect = query.post_logout_redirect_uri.clone()
         .or_else(|| req.headers().get(header::REFERER).and_then(|h| h.to_str().ok()).map(|h| h.to_owned()))
         .ok_or(ApiError::UnknownRedirect)?;
-    let id_token = &cookie.id_token;
-    let location = format!("{logout_uri}?post_logout_redirect_uri={redirect}&id_token_hint={id_token}");
+    let end_session_endpoint = match bridge.local_logout {
+        true => None,
+        false => bridge.get_idp_configuration().await?.end_session_endpoint.clone(),
+    };
+    let location = match end_session_endpoint {
+        // the IDP doesn't know about logouts, so we only forget about the session ourselves, and check the redirect
+        // which it would have checked
+        None if allowed_redirect(&req, &bridge, &redirect) => redirect,
+        None => return Err(ApiError::BadRequest).context("post logout redirect URI not allowed"),
+        Some(endpoint) => {
+            let mut location = Url::parse(&endpoint).context("parsing end session endpoint")?;
+            let logout_query = serde_urlencoded::to_string(EndSessionRequest {
+                client_id: &bridge.client,
+                post_logout_redirect_uri: &redirect,
+                id_token_hint: Some(cookie.id_token.as_str()).filter(|_| bridge.logout_id_token_hint),
+                state: query.state.as_deref(),
+                ui_locales: query.ui_locales.as_deref(),
+            })?;
+            let logout_query = match location.query() {
+                Some(existing) if !existing.is_empty() => format!("{existing}&{logout_query}"),
+                _ => logout_query,
+            };
+            location.set_query(Some(&logout_query));
+            location.into()
+        }
+    };
     let claims = claims::<IdTokenClaims>(&cookie.id_token)?;
-    info!("[{:<width$}] logout  ({})", bridge.id, claims.preferred_username, width = bridge.config()?.log_padding);
-    Ok(HttpResponse::TemporaryRedirect()
+    revoke(&bridge, &cookie, &claims).await?;
+    Audit::new("logout", &bridge.id).user(claims.preferred_username.as_str()).log();
+    metrics::logout(&bridge.id, "redirect");
+    let mut builder = HttpResponse::TemporaryRedirect();
+    if let Some(csrf_cookie) = csrf::clear(&bridge) {
+        builder.cookie(csrf_cookie);
+    }
+    Ok(builder
         .insert_header((header::LOCATION, location))
-        .cookie(Cookie::build(SESSION_COOKIE_NAME, "")
-            .same_site(SameSite::Lax)
-            .secure(true)
-            .expires(OffsetDateTime::UNIX_EPOCH)
-            .path(format!("/bridge/{}", bridge.id))
-            .finish())
+        .cookie(clear(&bridge))
         .finish())
 }
 
+/// Logout without leaving the page: revokes the tokens and forgets the session
+#[post("/logout")]
+pub async fn logout_silently(req: HttpRequest, bridge: web::Data<Bridge>) -> Result<impl Responder, ApiError> {
+    let cookie = decode::<SessionCookie>(&get(&req, &bridge).ok_or(ApiError::Unauthorized)?, &bridge)?;
+    let claims = claims::<IdTokenClaims>(&cookie.id_token)?;
+    revoke(&bridge, &cookie, &claims).await?;
+    Audit::new("logout", &bridge.id).user(claims.preferred_username.as_str()).log();
+    metrics::logout(&bridge.id, "silent");
+    let mut builder = HttpResponse::NoContent();
+    if let Some(csrf_cookie) = csrf::clear(&bridge) {
+        builder.cookie(csrf_cookie);
+    }
+    Ok(builder
+        .cookie(clear(&bridge))
+        .finish())
+}
+
+/// Whether a logout may redirect to a URI without the IDP checking it: one configured, or one of our own origin
+fn allowed_redirect(req: &HttpRequest, bridge: &Bridge, redirect: &str) -> bool {
+    if bridge.post_logout_redirect_uris.iter().any(|uri| uri == redirect) {
+        return true;
+    }
+    let info = req.connection_info();
+    let Ok(own) = Url::parse(&format!("{}://{}", info.scheme(), info.host())) else { return false };
+    // relative URIs, including scheme-relative ones, are resolved as the user agent would
+    own.join(redirect).is_ok_and(|url| url.origin() == own.origin())
+}
+
+async fn revoke(bridge: &Bridge, session: &SessionCookie, claims: &IdTokenClaims) -> Result<(), ApiError> {
+    let result = revoke_tokens(bridge, session).await;
+    match result {
+        Ok(_) => Audit::new("revocation", &bridge.id).user(claims.preferred_username.as_str()).log(),
+        Err(ref e) => Audit::new("revocation_failed", &bridge.id).user(claims.preferred_username.as_str()).reason(e).log(),
+    }
+    match (result, bridge.revocation_failure) {
+        (Err(_), RevocationFailure::Continue) => Ok(()),
+        (result, _) => result,
+    }
+}
+
+/// Target of the IDP's front-channel logout iframe, cf. OpenID Connect Front-Channel Logout 1.0
+#[get("/frontchannel-logout")]
+pub async fn frontchannel_logout(req: HttpRequest, bridge: web::Data<Bridge>, query: web::Query<FrontchannelLogoutQuery>) -> Result<impl Responder, ApiError> {
+    let idp_configuration = bridge.get_idp_configuration().await?;
+    let query = query.into_inner();
+    let session = get(&req, &bridge)
+        .and_then(|cookie| decode::<SessionCookie>(&cookie, &bridge).ok());
+
+    let mut builder = HttpResponse::Ok();
+    builder
+        .insert_header((header::CONTENT_TYPE, mime::TEXT_HTML_UTF_8))
+        .insert_header((header::CACHE_CONTROL, "no-cache, no-store"))
+        .insert_header((header::PRAGMA, "no-cache"));
+    // allow the IDP, and only the IDP, to embed us
+    if let Ok(idp) = Url::parse(&bridge.idp) {
+        builder.insert_header((header::CONTENT_SECURITY_POLICY, format!("frame-ancestors {}", idp.origin().ascii_serialization())));
+    }
+
+    // only ever end the session the IDP is talking about; with a sid, the spec requires the iss along
+    let session = session.filter(|session| session.sid.is_some()
+        && session.sid == query.sid
+        && query.iss.as_ref().is_some_and(|iss| iss == &idp_configuration.issuer));
+    if let Some(session) = session {
+        let claims = claims::<IdTokenClaims>(&session.id_token)?;
+        Audit::new("frontchannel_logout", &bridge.id).user(claims.preferred_username.as_str()).log();
+        metrics::logout(&bridge.id, "frontchannel");
+        if let Some(csrf_cookie) = csrf::clear(&bridge) {
+            builder.cookie(csrf_cookie);
+        }
+        builder.cookie(clear(&bridge));
+    }
+    Ok(builder.finish())
+}
+
 #[derive(Deserialize)]
 pub struct LogoutQuery {
     post_logout_redirect_uri: Option<String>,
+    state: Option<String>,
+    ui_locales: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct FrontchannelLogoutQuery {
+    iss: Option<String>,
+    sid: Option<String>,
 }
\ No newline at end of file
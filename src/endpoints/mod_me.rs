@@ -1,30 +1,44 @@
-use actix_web::{HttpRequest, HttpResponse, Responder, web, get};
-use actix_web::http::header;
+use actix_web::{HttpRequest, HttpResponse, Responder, ResponseError, web, get};
+use actix_web::http::{header, Method};
 use base64::Engine;
 use base64::engine::general_purpose;
-use log::info;
 use crate::components::config::Bridge;
 use crate::error::ApiError;
 use crate::components::types::{AccessTokenClaims, ClientAuth, IntrospectionClaims, IntrospectionRequest, SessionCookie};
-use crate::systems::cookies::{decode, SESSION_COOKIE_NAME};
+use crate::systems::cookies::{decode, get};
 use crate::error::Context;
+use crate::systems::{csrf, metrics, session};
+use crate::systems::logging::Access;
 use crate::systems::token::claims;
 
 #[get("/me")]
 pub async fn me(req: HttpRequest, bridge: web::Data<Bridge>) -> Result<impl Responder, ApiError> {
-    let cookie = req.cookie(SESSION_COOKIE_NAME)
+    let mut access = Access::new("me", &req, &bridge.id, None, req.path());
+    let result = session_info(&req, &bridge, &mut access).await;
+    access.log(match result {
+        Ok(ref response) => response.status(),
+        Err(ref e) => e.status_code(),
+    }.as_u16());
+    result
+}
+
+async fn session_info(req: &HttpRequest, bridge: &Bridge, access: &mut Access) -> Result<HttpResponse, ApiError> {
+    let cookie = get(req, bridge)
         .ok_or(ApiError::NotLoggedIn)
         .context("No session cookie")?;
-    let cookie = decode::<SessionCookie>(&cookie, &bridge)
+    let mut cookie = decode::<SessionCookie>(&cookie, bridge)
         .map_err(|_| ApiError::NotLoggedIn)
         .context("Couldn't decode session cookie")?;
+    let now = chrono::Utc::now().timestamp();
+    session::check(&cookie, bridge, now)?;
     let access_claims = claims::<AccessTokenClaims>(&cookie.access_token)?;
 
     // perform token introspection
-    let response = bridge.config()?.reqwest.post(&bridge.get_idp_configuration().await?.introspection_endpoint)
+    let endpoint = bridge.get_idp_configuration().await?.introspection_endpoint.clone();
+    let body = serde_urlencoded::to_string(IntrospectionRequest { auth: ClientAuth::new(bridge), token: &cookie.id_token })?;
+    let response = metrics::idp(&bridge.id, "introspection", bridge.http.execute(&bridge.label()?, Method::POST, || bridge.http.reqwest.post(&endpoint)
         .header(header::CONTENT_TYPE, mime::APPLICATION_WWW_FORM_URLENCODED.as_ref())
-        .body(serde_urlencoded::to_string(IntrospectionRequest { auth: ClientAuth::new(&bridge), token: &cookie.id_token })?)
-        .send()
+        .body(body.clone())))
         .await.context("posting token introspection to IDP")?
         .json::<IntrospectionClaims>()
         .await.context("deserializing introspection claims")?;
@@ -33,9 +47,19 @@ pub async fn me(req: HttpRequest, bridge: web::Data<Bridge>) -> Result<impl Resp
         return Err(ApiError::Unauthorized).context("session inactive");
     }
 
-    let base64 = cookie.id_token.as_bytes().split(|c| *c == 46).skip(1).next().ok_or(ApiError::BadGateway)?;
+    let base64 = cookie.id_token.as_bytes().split(|c| *c == 46).nth(1).ok_or(ApiError::BadGateway)?;
     let bytes = general_purpose::URL_SAFE_NO_PAD.decode(base64)?;
 
-    info!("[{:<width$}] me      ({})", bridge.id, &access_claims.preferred_username, width = bridge.config()?.log_padding);
-    Ok(HttpResponse::Ok().insert_header((header::CONTENT_TYPE, mime::APPLICATION_JSON)).body(bytes))
+    access.user = Some(access_claims.preferred_username);
+    let mut builder = HttpResponse::Ok();
+    if let (Some(csrf), Some(token)) = (&bridge.csrf, &cookie.csrf_token) {
+        builder.insert_header((csrf.token_header.clone(), token.clone()));
+    }
+    if session::touch(&mut cookie, bridge, now) {
+        builder.cookie(session::cookie(&cookie, bridge, now)?);
+        if let Some(csrf_cookie) = csrf::cookie(&cookie, bridge, now) {
+            builder.cookie(csrf_cookie);
+        }
+    }
+    Ok(builder.insert_header((header::CONTENT_TYPE, mime::APPLICATION_JSON)).body(bytes))
 }
\ No newline at end of file
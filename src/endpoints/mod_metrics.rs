@@ -0,0 +1,12 @@
+use actix_web::{get, HttpResponse, Responder};
+use actix_web::http::header;
+use crate::error::{ApiError, Context};
+use crate::systems::metrics::render;
+
+#[get("/metrics")]
+pub async fn metrics() -> Result<impl Responder, ApiError> {
+    let body = render().map_err(|_| ApiError::Internal).context("rendering metrics")?;
+    Ok(HttpResponse::Ok()
+        .insert_header((header::CONTENT_TYPE, prometheus::TEXT_FORMAT))
+        .body(body))
+}
@@ -1,66 +1,108 @@
-use actix_web::{HttpRequest, HttpResponse, Responder, web};
-use actix_web::cookie::{Cookie, CookieJar, SameSite};
-use actix_web::http::Method;
-use futures_util::StreamExt;
+use actix_web::{HttpMessage, HttpRequest, HttpResponse, Responder, ResponseError, web};
+use actix_web::cookie::{Cookie, CookieJar};
+use actix_web::http::{Method, StatusCode};
+use std::time::{Duration, Instant};
 use itertools::Itertools;
-use log::info;
+use serde_json::Value;
+use log::{debug, warn};
 use reqwest::header;
 use reqwest::header::{HeaderMap, HeaderValue};
-use tokio::sync::mpsc;
-use tokio_stream::wrappers::ReceiverStream;
+use url::Url;
 use crate::error::{ApiError, Context};
 use crate::components::config::{Api, Bridge};
-use crate::systems::token::{claims, retrieve_token};
+use crate::components::policy::Decision;
+use crate::components::route;
+use crate::components::upstream::Lease;
+use crate::components::spec::AuthMode;
+use crate::systems::token::{self, claims, retrieve_token};
 use crate::components::types::{AccessTokenClaims, RefreshTokenClaims, SessionCookie, TokenRequestDetails};
-use crate::systems::cookies::{create, decode, SESSION_COOKIE_NAME};
+use crate::systems::cookies::{decode, get};
+use crate::systems::{body, csrf, headers, metrics, session, streaming, websocket};
+use crate::systems::logging::{self, Access, Audit};
 
 pub async fn proxy(
     req: HttpRequest,
     method: Method,
     api: web::Data<Api>,
     path: web::Path<(String,)>,
-    mut payload: web::Payload,
+    payload: web::Payload,
 ) -> Result<impl Responder, ApiError> {
-    let request_path = path.into_inner().0;
+    let start = Instant::now();
+    let bridge = api.bridge()?;
+    let path = path.into_inner().0;
+    let mut access = Access::new("proxy", &req, &bridge.id, Some(&api.id), &path);
+    let result = forward(req, method, api.clone(), path, payload, &mut access).await;
+    let status = match result {
+        Ok(ref response) => response.status(),
+        Err(ref e) => e.status_code(),
+    };
+    metrics::proxied(&bridge.id, &api.id, status.as_u16(), start);
+    access.log(status.as_u16());
+    result
+}
+
+async fn forward(
+    req: HttpRequest,
+    method: Method,
+    api: web::Data<Api>,
+    request_path: String,
+    payload: web::Payload,
+    access: &mut Access,
+) -> Result<HttpResponse, ApiError> {
     let bridge = api.bridge()?;
     let config = bridge.config()?;
-    let cookie = req.cookie(SESSION_COOKIE_NAME).ok_or(ApiError::Unauthorized)?;
-    let session = decode::<SessionCookie>(&cookie, &bridge)?;
-    let mut jar = CookieJar::new();
-    jar.add_original(cookie);
-    let access_claims = claims::<AccessTokenClaims>(&session.access_token)?;
     let now = chrono::Utc::now().timestamp();
-    let (access_token, refreshed) = if access_claims.exp as i64 - now < config.clock_skew as i64 {
-        let refresh_claims = claims::<RefreshTokenClaims>(&session.refresh_token)?;
-        if refresh_claims.exp as i64 - now < config.clock_skew as i64 {
-            return Err(ApiError::NotLoggedIn).context("Refresh Token expired");
+    let path = route::normalize(&request_path);
+    let destination = api.destination(&method, &path)?;
+    let mut jar = CookieJar::new();
+    let user = match api.auth(&method, &path) {
+        AuthMode::Required => {
+            let (session, access_claims) = open(&req, &bridge, &mut jar)?;
+            Some(authenticate(&req, &bridge, &mut jar, session, access_claims, now).await?)
         }
-        let renewed = get_new_token(&session.refresh_token, &bridge).await?;
-        jar.add(renewed.0);
-        (renewed.1, "|r")
-    } else {
-        (session.access_token, "  ")
+        // without a session which can be read, e.g. for an old or corrupt cookie, the request goes on anonymously
+        AuthMode::Optional => match open(&req, &bridge, &mut jar) {
+            Err(_) => None,
+            Ok((session, access_claims)) => match authenticate(&req, &bridge, &mut jar, session, access_claims, now).await {
+                Ok(user) => Some(user),
+                // likewise for an expired session, but not if the IDP fails to refresh it
+                Err(e) if e.status_code() == StatusCode::UNAUTHORIZED => None,
+                Err(e) => return Err(e),
+            },
+        },
+        AuthMode::None => None,
     };
+    let claims = match (&user, api.request_headers.needs_claims() || api.identity.is_some() || api.authorization.needs_claims()) {
+        (Some(user), true) => claims::<Value>(&user.access_token)?,
+        _ => Value::Null,
+    };
+    access.user = user.as_ref().map(|user| user.username.clone());
+    access.refreshed = user.as_ref().is_some_and(|user| user.refreshed);
+    if let Decision::Deny(reason) = api.authorize(&method, &path, &claims) {
+        Audit::new("authorization_denied", &bridge.id)
+            .api(&api.id)
+            .user(access.user.as_deref())
+            .request(&req)
+            .reason(&reason)
+            .log();
+        return Err(ApiError::Forbidden).context(format!("Access denied by {reason}"));
+    }
 
-    let mut url = api.backend.clone();
-    let path = format!("{}{}", url.path(), &request_path);
-    url.set_path(&path);
-    url.set_query(req.uri().query());
-
-    let (tx, rx) = mpsc::channel(10);
-
-    actix_web::rt::spawn(async move {
-        while let Some(chunk) = payload.next().await {
-            let _ = tx.send(chunk).await;
-        }
-    });
+    let sub = user.as_ref().and_then(|user| user.sub.clone());
+    let access_token = match user {
+        Some(user) => Some(user.access_token),
+        None => api.get_client_token(now).await?.map(|token| token.to_string()),
+    };
 
-    let headers2: HeaderMap = api.headers.iter()
-        .map(|name| {
+    let hop_by_hop = headers::hop_by_hop(req.headers().get_all(header::CONNECTION));
+    let mut headers2: HeaderMap = api.headers.iter()
+        .filter(|name| !hop_by_hop.contains(name))
+        .filter_map(|name| {
             if name == header::COOKIE {
                 let value = req.cookies().iter().flat_map(|x| x.iter())
-                    // Don't expose our own cookie to the backend
-                    .filter(|cookie| cookie.name() != SESSION_COOKIE_NAME)
+                    // Don't expose our own cookies to the backend
+                    .filter(|cookie| cookie.name() != bridge.cookie.name
+                        && bridge.csrf.as_ref().is_none_or(|csrf| cookie.name() != csrf.token_cookie))
                     .map(|cookie| cookie.to_string())
                     .join("; ");
                 HeaderValue::from_str(&value).ok().map(|v| (name.clone(), v))
@@ -68,41 +110,204 @@ pub async fn proxy(
                 req.headers().get(name).map(|val| (name.clone(), val.clone()))
             }
         })
-        .filter_map(|x| x)
         .collect::<HeaderMap>();
+    if let Some(ref forwarded) = api.forwarded {
+        headers::forward(forwarded, &config.trusted_proxies, &req, &api, &bridge, &mut headers2);
+    }
+    headers::apply_rules(&api.request_headers, &mut headers2, &claims);
+
+    if websocket::is_upgrade(&req) {
+        let lease = destination.upstream.pick(sub.as_deref(), now);
+        let url = route::join(lease.url(), &destination.path, req.uri().query());
+        let headers = upstream_headers(&api, &headers2, &claims, &method, &url, access_token.as_deref(), now);
+        // the connection ends before the token it was opened with expires
+        let expires_in = access_token.as_deref()
+            .and_then(|access_token| token::claims::<Value>(access_token).ok())
+            .and_then(|claims| claims.get("exp")?.as_i64())
+            .map(|exp| exp.saturating_sub(now).max(0) as u64);
+        let lifetime = api.websocket_max_lifetime.map(u64::from).into_iter().chain(expires_in).min().map(Duration::from_secs);
+        let response = websocket::proxy(&req, payload, &api, &url, headers, lifetime, lease).await;
+        let mut response = response?;
+        jar.delta().for_each(|c| { let _ = response.add_cookie(c); });
+        access.kind = "socket";
+        return Ok(response);
+    }
+
+    // without a body, the request can be sent again; a streamed body can only be sent once
+    let replayable = !req.headers().contains_key(header::TRANSFER_ENCODING)
+        && req.headers().get(header::CONTENT_LENGTH).is_none_or(|length| length == "0");
+    if !replayable {
+        let content_type = req.mime_type().ok().flatten();
+        if !api.accepts_content_type(content_type.as_ref()) {
+            return Err(ApiError::UnsupportedMediaType).context(match content_type {
+                Some(content_type) => format!("Content type {content_type} not allowed"),
+                None => "Content type missing".to_string(),
+            });
+        }
+        let length = req.headers().get(header::CONTENT_LENGTH)
+            .and_then(|length| length.to_str().ok()?.parse::<u64>().ok());
+        if let (Some(limit), Some(length)) = (api.max_body_size, length) {
+            if length > limit {
+                return Err(ApiError::PayloadTooLarge).context(format!("Request body exceeds {limit} bytes"));
+            }
+        }
+    }
+    let (mut body, forwarding) = match replayable {
+        true => (None, None),
+        false => {
+            let (body, forwarding) = body::forward(payload, api.max_body_size);
+            (Some(body), Some(forwarding))
+        },
+    };
 
-    let response  = bridge.config()?.reqwest
-        .request(method, url)
-        .headers(headers2)
-        .header(header::AUTHORIZATION, format!("Bearer {}", access_token))
-        .body(reqwest::Body::wrap_stream(ReceiverStream::new(rx)))
-        .send()
-        .await?;
+    let attempt = || {
+        let lease = destination.upstream.pick(sub.as_deref(), now);
+        let url = route::join(lease.url(), &destination.path, req.uri().query());
+        debug!("[{:<width$}::{}] {} {} -> {}", bridge.id, api.id, method, request_path, logging::url(&url), width = config.log_padding - api.id.len() - 2);
+        let headers = upstream_headers(&api, &headers2, &claims, &method, &url, access_token.as_deref(), now);
+        let request = api.http.reqwest
+            .request(method.clone(), url)
+            .headers(headers);
+        let request = match body.take() {
+            Some(body) => request.body(body),
+            None => request,
+        };
+        Ok((request, lease))
+    };
+    let report = |lease: &Lease, success| {
+        if destination.upstream.report(lease, success, now) {
+            warn!("[{:<width$}::{}] ejected {} for {}s",
+                bridge.id,
+                api.id,
+                lease.url(),
+                destination.upstream.ejection.as_ref().map_or(0, |ejection| ejection.duration),
+                width = config.log_padding - api.id.len() - 2,
+            );
+        }
+    };
+    let (response, lease) = match api.http.send(&api.label()?, &method, replayable, attempt, report).await {
+        Ok(sent) => sent,
+        Err(e) => {
+            // the rest of the body won't be needed anymore
+            if let Some(forwarding) = forwarding {
+                forwarding.abort();
+            }
+            return Err(body::blame(e));
+        },
+    };
 
     let mut builder = HttpResponse::build(response.status());
-    response.headers().iter().for_each(|(k, v)| { builder.append_header((k, v)); });
+    let hop_by_hop = headers::hop_by_hop(response.headers().get_all(header::CONNECTION));
+    response.headers().iter()
+        .filter(|(k, _)| !hop_by_hop.contains(k) && api.forwards_response_header(k))
+        .for_each(|(k, v)| match *k {
+            header::SET_COOKIE => {
+                let backend = (!destination.rewritten).then(|| lease.url());
+                if let Some(cookie) = headers::rewrite_set_cookie(v, &api, &bridge, backend) {
+                    builder.cookie(cookie);
+                }
+            },
+            // the rewrite of a route can't be reversed, so its locations are left for the backend to get right
+            header::LOCATION | header::CONTENT_LOCATION if destination.rewritten => { builder.append_header((k, v)); },
+            header::LOCATION | header::CONTENT_LOCATION => {
+                builder.append_header((k, headers::rewrite_location(v, &api, &bridge, lease.url()).unwrap_or_else(|| v.clone())));
+            },
+            _ => { builder.append_header((k, v)); },
+        });
     jar.delta().for_each(|c| { builder.cookie(c.clone()); });
-    info!("[{:<width$}::{}] proxy{} ({}) -- {:>7} {} : {}",
-        bridge.id,
-        api.id,
-        refreshed,
-        &access_claims.preferred_username,
-        req.method().as_str(),
-        request_path,
-        response.status().as_u16(),
-        width = bridge.config()?.log_padding - api.id.len() - 2,
-    );
-    Ok(builder.streaming(response.bytes_stream()))
+    let event_stream = response.headers().get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.split(';').next().is_some_and(|mime| mime.trim().eq_ignore_ascii_case("text/event-stream")));
+    if event_stream {
+        // keep reverse proxies in front of us from buffering events
+        builder.insert_header(("x-accel-buffering", "no"));
+    }
+    let body = streaming::relay(response.bytes_stream(), api.streaming.as_ref(), event_stream, api.label()?, lease);
+    Ok(builder.streaming(body))
+}
+
+/// Headers of a request to `url`, along with either the signed identity of the user or the access token
+fn upstream_headers(api: &Api, headers: &HeaderMap, claims: &Value, method: &Method, url: &Url, access_token: Option<&str>, now: i64) -> HeaderMap {
+    let mut headers = headers.clone();
+    match (&api.identity, access_token) {
+        (Some(identity), _) => headers::sign_identity(identity, &mut headers, claims, method, url, now),
+        // backends which get signed identity headers never see the token
+        (None, Some(access_token)) => if let Ok(value) = HeaderValue::from_str(&format!("Bearer {}", access_token)) {
+            headers.insert(header::AUTHORIZATION, value);
+        },
+        (None, None) => {},
+    }
+    headers
+}
+
+/// The user behind a proxied request
+struct User {
+    access_token: String,
+    username: String,
+    sub: Option<String>,
+    refreshed: bool,
+}
+
+/// Reads the user's session, refreshing its tokens if they are about to expire
+/// Reads the session cookie of the request
+fn open(req: &HttpRequest, bridge: &Bridge, jar: &mut CookieJar) -> Result<(SessionCookie, AccessTokenClaims), ApiError> {
+    let cookie = get(req, bridge).ok_or(ApiError::Unauthorized)?;
+    let session = decode::<SessionCookie>(&cookie, bridge)?;
+    jar.add_original(cookie);
+    let access_claims = claims::<AccessTokenClaims>(&session.access_token)?;
+    Ok((session, access_claims))
+}
+
+async fn authenticate(
+    req: &HttpRequest,
+    bridge: &Bridge,
+    jar: &mut CookieJar,
+    mut session: SessionCookie,
+    access_claims: AccessTokenClaims,
+    now: i64,
+) -> Result<User, ApiError> {
+    let config = bridge.config()?;
+    session::check(&session, bridge, now)?;
+    csrf::verify(req, bridge, &session)?;
+    if access_claims.exp as i64 - now < config.clock_skew as i64 {
+        let refresh_claims = claims::<RefreshTokenClaims>(&session.refresh_token)?;
+        if refresh_claims.exp as i64 - now < config.clock_skew as i64 {
+            metrics::refresh_failed(&bridge.id, "refresh_token_expired");
+            Audit::new("refresh_failed", &bridge.id).user(access_claims.preferred_username.as_str()).reason("refresh_token_expired").log();
+            return Err(ApiError::NotLoggedIn).context("Refresh Token expired");
+        }
+        let (cookie, access_token) = get_new_token(&session, bridge, now).await
+            .inspect_err(|_| Audit::new("refresh_failed", &bridge.id).user(access_claims.preferred_username.as_str()).reason("idp_error").log())?;
+        Audit::new("refresh", &bridge.id).user(access_claims.preferred_username.as_str()).log();
+        jar.add(cookie);
+        if let Some(csrf_cookie) = csrf::cookie(&session, bridge, now) {
+            jar.add(csrf_cookie);
+        }
+        Ok(User { access_token, username: access_claims.preferred_username, sub: access_claims.sub, refreshed: true })
+    } else {
+        if session::touch(&mut session, bridge, now) {
+            jar.add(session::cookie(&session, bridge, now)?);
+            if let Some(csrf_cookie) = csrf::cookie(&session, bridge, now) {
+                jar.add(csrf_cookie);
+            }
+        }
+        Ok(User { access_token: session.access_token, username: access_claims.preferred_username, sub: access_claims.sub, refreshed: false })
+    }
 }
 
-async fn get_new_token(refresh_token: &str, bridge: &Bridge) -> Result<(Cookie<'static>, String), ApiError> {
-    let response = retrieve_token(&bridge, TokenRequestDetails::RefreshToken { refresh_token }).await?;
+async fn get_new_token(session: &SessionCookie, bridge: &Bridge, now: i64) -> Result<(Cookie<'static>, String), ApiError> {
+    let response = retrieve_token(bridge, TokenRequestDetails::RefreshToken { refresh_token: &session.refresh_token }).await?;
     let access_token = response.access_token.clone();
     let cookie_value = SessionCookie {
         access_token: response.access_token,
         refresh_token: response.refresh_token,
         id_token: response.id_token,
+        sid: session.sid.clone(),
+        created_at: session::created_at(session, now),
+        last_seen: now,
+        remember: session.remember,
+        csrf_token: session.csrf_token.clone(),
     };
-    let cookie = create(cookie_value, &bridge, SameSite::Strict)?;
+    let cookie = session::cookie(&cookie_value, bridge, now)?;
     Ok((cookie, access_token))
 }
\ No newline at end of file
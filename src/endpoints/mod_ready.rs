@@ -0,0 +1,18 @@
+use actix_web::{get, HttpResponse, Responder, web};
+use serde_json::json;
+use crate::components::config::Config;
+use crate::systems::readiness::cached;
+
+/// Answers 503 while a check which gates readiness fails; how each check went is only told with `readiness.details`
+#[get("/ready")]
+pub async fn ready(config: web::Data<Config>) -> impl Responder {
+    let report = cached(&config).await;
+    let mut response = match report.ready {
+        true => HttpResponse::Ok(),
+        false => HttpResponse::ServiceUnavailable(),
+    };
+    match config.readiness.details {
+        true => response.json(&*report),
+        false => response.json(json!({ "ready": report.ready })),
+    }
+}
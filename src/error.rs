@@ -17,6 +17,7 @@ use rand::Error as RandError;
 use aead::Error as AeadError;
 use log::debug;
 use serde_urlencoded::ser::Error as UrlError;
+use crate::systems::logging;
 
 #[derive(Display, Debug, Error, From)]
 pub enum ConfigError {
@@ -30,6 +31,82 @@ pub enum ConfigError {
     InvalidUrl(String, ParseError),
     #[display(fmt = "invalid header name '{}'", _0)]
     InvalidHeader(InvalidHeaderName),
+    #[display(fmt = "invalid cookie settings for bridge '{}': {}", _0, _1)]
+    #[from(ignore)]
+    InvalidCookie(String, &'static str),
+    #[display(fmt = "bridges '{}' and '{}' would share a session cookie", _0, _1)]
+    #[from(ignore)]
+    CookieCollision(String, String),
+    #[display(fmt = "invalid method '{}'", _0)]
+    #[from(ignore)]
+    InvalidMethod(#[error(not(source))] String),
+    #[display(fmt = "invalid regular expression: {}", _0)]
+    InvalidRegex(regex::Error),
+    #[display(fmt = "CORS policy allows no origin; list allowed_origins or enable dev_mode")]
+    NoCorsOrigin,
+    #[display(fmt = "invalid template '{}': {}", _0, _1)]
+    #[from(ignore)]
+    InvalidTemplate(String, &'static str),
+    #[display(fmt = "unable to find environment variable '{}'", _0)]
+    #[from(ignore)]
+    MissingVariable(#[error(not(source))] String),
+    #[display(fmt = "invalid identity settings for API '{}': {}", _0, _1)]
+    #[from(ignore)]
+    InvalidIdentity(String, &'static str),
+    #[display(fmt = "invalid authorization rule '{}': {}", _0, _1)]
+    #[from(ignore)]
+    InvalidRule(String, #[error(not(source))] String),
+    #[display(fmt = "invalid route '{}': {}", _0, _1)]
+    #[from(ignore)]
+    InvalidRoute(String, #[error(not(source))] String),
+    #[display(fmt = "invalid upstream for API '{}': {}", _0, _1)]
+    #[from(ignore)]
+    InvalidUpstream(String, &'static str),
+    #[display(fmt = "invalid retry or circuit breaker settings for '{}': {}", _0, _1)]
+    #[from(ignore)]
+    InvalidResilience(String, &'static str),
+    #[display(fmt = "invalid content type '{}'", _0)]
+    #[from(ignore)]
+    InvalidContentType(#[error(not(source))] String),
+    #[display(fmt = "invalid network '{}'", _0)]
+    #[from(ignore)]
+    InvalidNetwork(#[error(not(source))] String),
+    #[display(fmt = "unable to create HTTP client: {}", _0)]
+    HttpClient(reqwest::Error),
+    #[display(fmt = "unable to set up tracing: {}", _0)]
+    Tracing(opentelemetry::trace::TraceError),
+    #[display(fmt = "invalid admin settings: {}", _0)]
+    #[from(ignore)]
+    InvalidAdmin(#[error(not(source))] &'static str),
+    #[display(fmt = "unable to set up TLS: {}", _0)]
+    Tls(openssl::error::ErrorStack),
+    #[display(fmt = "changes of {} only take effect after a restart", _0)]
+    #[from(ignore)]
+    RestartRequired(#[error(not(source))] String),
+}
+
+/// Why a configuration file couldn't be loaded
+#[derive(Display, Debug)]
+pub enum LoadError {
+    #[display(fmt = "Unable to read configuration file `{}`: {}", _0, _1)]
+    Read(String, IoError),
+    #[display(fmt = "Unable to find environment variables:\n{}", _0)]
+    Variables(String),
+    #[display(fmt = "Unable to parse configuration file: {}", _0)]
+    Parse(hcl::Error),
+    #[display(fmt = "Unable to understand configuration file: {}", _0)]
+    Config(ConfigError),
+}
+
+impl LoadError {
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Self::Read(..) => 1,
+            Self::Variables(_) => 2,
+            Self::Parse(_) => 3,
+            Self::Config(_) => 4,
+        }
+    }
 }
 
 #[derive(Display, Debug, Error, From)]
@@ -37,18 +114,26 @@ pub enum ApiError {
     Aead(AeadError),
     B64(B64Error),
     BadGateway,
+    BadRequest,
+    CircuitOpen,
     Decode(DecError),
     Encode(EncError),
+    Forbidden,
     Internal,
     Io(IoError),
     Json(JsonError),
+    MethodNotAllowed,
+    NotFound,
     NotLoggedIn,
     Parse(ParseError),
+    PayloadTooLarge,
     Rand(RandError),
     Reqwest(reqwest::Error),
+    Timeout,
     ToStr(ToStrError),
     Unauthorized,
     UnknownKey,
+    UnsupportedMediaType,
     UnknownRedirect,
     Url(UrlError),
     Utf8(Utf8Error),
@@ -68,6 +153,14 @@ impl ResponseError for ApiError {
                 | Self::B64(_)
                 | Self::Utf8(_)
                 | Self::UnknownRedirect => StatusCode::UNAUTHORIZED,
+            Self::BadRequest => StatusCode::BAD_REQUEST,
+            Self::Forbidden => StatusCode::FORBIDDEN,
+            Self::NotFound => StatusCode::NOT_FOUND,
+            Self::CircuitOpen => StatusCode::SERVICE_UNAVAILABLE,
+            Self::Timeout => StatusCode::GATEWAY_TIMEOUT,
+            Self::MethodNotAllowed => StatusCode::METHOD_NOT_ALLOWED,
+            Self::PayloadTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            Self::UnsupportedMediaType => StatusCode::UNSUPPORTED_MEDIA_TYPE,
             _ => StatusCode::INTERNAL_SERVER_ERROR,
         }
     }
@@ -76,7 +169,11 @@ impl ResponseError for ApiError {
         debug!("Observed error: {:?}", self);
         let mut builder = HttpResponse::build(self.status_code());
         builder.extensions_mut().insert(ErrorResponse::from(self));
-        builder.json(json!({}))
+        // unless errors are exposed, this is all the client learns
+        builder.json(match logging::request_id() {
+            Some(request_id) => json!({ "request_id": request_id }),
+            None => json!({}),
+        })
     }
 }
 
@@ -105,6 +202,8 @@ pub struct ErrorResponse {
     error: Cow<'static, str>,
     #[serde(skip_serializing_if = "Vec::is_empty")]
     details: Vec<Cow<'static, str>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    request_id: Option<String>,
 }
 
 impl From<&ApiError> for ErrorResponse {
@@ -119,6 +218,7 @@ impl From<&ApiError> for ErrorResponse {
                 status: value.status_code().as_u16(),
                 error: format!("{:?}", value).into(),
                 details: Vec::new(),
+                request_id: logging::request_id(),
             }
         }
     }
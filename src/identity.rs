@@ -0,0 +1,187 @@
+//! Signed identity headers for backends which can't validate JWTs.
+//!
+//! Instead of a bearer token, the token handler sends selected claims as headers along with a signature header of the
+//! form `t=<unix timestamp>;h=<comma-separated header names>;s=<signature>`. The signature is the base64url-encoded
+//! (without padding) HMAC-SHA256 over the lines
+//!
+//! ```text
+//! <timestamp>
+//! <METHOD>
+//! <path and query>
+//! <header name>:<header value>
+//! ...
+//! ```
+//!
+//! with one line per signed header, in the order given by `h`. A missing header is signed with an empty value.
+
+use std::fmt::{Display, Formatter};
+use base64::Engine;
+use base64::engine::general_purpose;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+/// Default name of the signature header
+pub const SIGNATURE_HEADER: &str = "x-identity-signature";
+
+/// Creates the value of the signature header
+pub fn sign(secret: &[u8], timestamp: i64, method: &str, path: &str, headers: &[(&str, Option<&str>)]) -> String {
+    let names = headers.iter().map(|(name, _)| *name).collect::<Vec<_>>().join(",");
+    let signature = general_purpose::URL_SAFE_NO_PAD.encode(mac(secret, timestamp, method, path, headers).finalize().into_bytes());
+    format!("t={timestamp};h={names};s={signature}")
+}
+
+/// Verifies the signature header of a request and returns the signed headers. `header` looks up request headers by
+/// their lowercase name; `max_age` is the tolerated age of the signature in seconds.
+pub fn verify<'a, F>(secret: &[u8], signature: &str, method: &str, path: &str, header: F, max_age: i64, now: i64) -> Result<Vec<(String, Option<&'a str>)>, VerifyError>
+    where F: Fn(&str) -> Option<&'a str> {
+    let mut timestamp = None;
+    let mut names = None;
+    let mut mac_value = None;
+    for part in signature.split(';') {
+        match part.split_once('=') {
+            Some(("t", t)) => timestamp = Some(t.parse::<i64>().map_err(|_| VerifyError::Malformed)?),
+            Some(("h", h)) => names = Some(h.split(',').filter(|n| !n.is_empty()).map(|n| n.to_lowercase()).collect::<Vec<_>>()),
+            Some(("s", s)) => mac_value = Some(general_purpose::URL_SAFE_NO_PAD.decode(s).map_err(|_| VerifyError::Malformed)?),
+            _ => return Err(VerifyError::Malformed),
+        }
+    }
+    let (timestamp, names, mac_value) = match (timestamp, names, mac_value) {
+        (Some(t), Some(h), Some(s)) => (t, h, s),
+        _ => return Err(VerifyError::Malformed),
+    };
+    if (now - timestamp).abs() > max_age {
+        return Err(VerifyError::Expired);
+    }
+    let headers = names.into_iter().map(|name| { let value = header(&name); (name, value) }).collect::<Vec<_>>();
+    let borrowed = headers.iter().map(|(name, value)| (name.as_str(), *value)).collect::<Vec<_>>();
+    mac(secret, timestamp, method, path, &borrowed)
+        .verify_slice(&mac_value)
+        .map_err(|_| VerifyError::BadSignature)?;
+    Ok(headers)
+}
+
+fn mac(secret: &[u8], timestamp: i64, method: &str, path: &str, headers: &[(&str, Option<&str>)]) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("HMAC accepts keys of any length");
+    mac.update(format!("{timestamp}\n{}\n{path}", method.to_uppercase()).as_bytes());
+    for (name, value) in headers {
+        mac.update(format!("\n{}:{}", name.to_lowercase(), value.unwrap_or_default()).as_bytes());
+    }
+    mac
+}
+
+#[derive(Debug, PartialEq)]
+pub enum VerifyError {
+    /// The signature header couldn't be parsed
+    Malformed,
+    /// The signature is older than tolerated, or from the future
+    Expired,
+    /// The signature doesn't match the request
+    BadSignature,
+}
+
+impl Display for VerifyError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            VerifyError::Malformed => write!(f, "malformed identity signature"),
+            VerifyError::Expired => write!(f, "identity signature expired"),
+            VerifyError::BadSignature => write!(f, "identity signature mismatch"),
+        }
+    }
+}
+
+impl std::error::Error for VerifyError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &[u8] = b"0123456789abcdef0123456789abcdef";
+    const NOW: i64 = 1_700_000_000;
+
+    fn signed() -> String {
+        sign(SECRET, NOW, "GET", "/items?page=2", &[("x-user-id", Some("jane")), ("x-user-roles", None)])
+    }
+
+    fn header(name: &str) -> Option<&'static str> {
+        match name {
+            "x-user-id" => Some("jane"),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let headers = verify(SECRET, &signed(), "GET", "/items?page=2", header, 5, NOW + 5).unwrap();
+        assert_eq!(headers, vec![("x-user-id".to_string(), Some("jane")), ("x-user-roles".to_string(), None)]);
+    }
+
+    #[test]
+    fn tampered_header() {
+        let header = |name: &str| match name {
+            "x-user-id" => Some("john"),
+            _ => None,
+        };
+        assert_eq!(verify(SECRET, &signed(), "GET", "/items?page=2", header, 5, NOW), Err(VerifyError::BadSignature));
+    }
+
+    #[test]
+    fn added_header() {
+        let header = |name: &str| match name {
+            "x-user-id" => Some("jane"),
+            "x-user-roles" => Some("admin"),
+            _ => None,
+        };
+        assert_eq!(verify(SECRET, &signed(), "GET", "/items?page=2", header, 5, NOW), Err(VerifyError::BadSignature));
+    }
+
+    #[test]
+    fn dropped_header_name() {
+        let signature = signed().replace("h=x-user-id,x-user-roles", "h=x-user-id");
+        assert_eq!(verify(SECRET, &signature, "GET", "/items?page=2", header, 5, NOW), Err(VerifyError::BadSignature));
+    }
+
+    #[test]
+    fn wrong_method() {
+        assert_eq!(verify(SECRET, &signed(), "DELETE", "/items?page=2", header, 5, NOW), Err(VerifyError::BadSignature));
+    }
+
+    #[test]
+    fn method_case() {
+        assert!(verify(SECRET, &signed(), "get", "/items?page=2", header, 5, NOW).is_ok());
+    }
+
+    #[test]
+    fn wrong_url() {
+        assert_eq!(verify(SECRET, &signed(), "GET", "/items?page=3", header, 5, NOW), Err(VerifyError::BadSignature));
+        assert_eq!(verify(SECRET, &signed(), "GET", "/other?page=2", header, 5, NOW), Err(VerifyError::BadSignature));
+    }
+
+    #[test]
+    fn wrong_secret() {
+        let secret = b"fedcba9876543210fedcba9876543210";
+        assert_eq!(verify(secret, &signed(), "GET", "/items?page=2", header, 5, NOW), Err(VerifyError::BadSignature));
+    }
+
+    #[test]
+    fn expired_timestamp() {
+        assert_eq!(verify(SECRET, &signed(), "GET", "/items?page=2", header, 5, NOW + 6), Err(VerifyError::Expired));
+    }
+
+    #[test]
+    fn future_timestamp() {
+        assert_eq!(verify(SECRET, &signed(), "GET", "/items?page=2", header, 5, NOW - 6), Err(VerifyError::Expired));
+    }
+
+    #[test]
+    fn changed_timestamp() {
+        let signature = signed().replace(&format!("t={NOW}"), &format!("t={}", NOW + 1));
+        assert_eq!(verify(SECRET, &signature, "GET", "/items?page=2", header, 5, NOW), Err(VerifyError::BadSignature));
+    }
+
+    #[test]
+    fn malformed() {
+        for signature in ["", "t=1;h=x", "t=x;h=x-user-id;s=AAAA", "t=1;h=x-user-id;s=!!", "t=1;h=;s=AAAA;v=1"] {
+            assert_eq!(verify(SECRET, signature, "GET", "/", header, 5, 1), Err(VerifyError::Malformed), "{signature}");
+        }
+    }
+}
@@ -0,0 +1,3 @@
+//! Helpers for backends behind the token handler
+
+pub mod identity;
@@ -2,21 +2,24 @@ mod error;
 mod endpoints;
 mod systems;
 mod components;
+mod commands;
 
+use std::net::{SocketAddr, TcpListener};
 use std::sync::Arc;
-use actix_cors::Cors;
 use actix_web::{App, HttpServer, web};
-use actix_web::body::{BoxBody, EitherBody, MessageBody};
-use actix_web::dev::{Service, ServiceResponse};
-use clap::Parser;
-use itertools::Itertools;
-use regex::{Captures, Regex};
-use crate::components::config::{Api, Bridge, Config};
-use crate::components::substitutions::Substitutions;
+use actix_web::body::{BoxBody, MessageBody};
+use actix_web::dev::{Server, Service, ServiceResponse};
+use actix_web::middleware::Condition;
+use clap::{Parser, Subcommand};
+use socket2::{Domain, Socket, Type};
+use crate::components::config::{Api, Bridge, Config, CorsConfig};
+use crate::components::loader;
+use crate::components::policy::Decision;
 use crate::error::ErrorResponse;
 use futures_util::FutureExt;
 use log::info;
-use crate::components::spec::Spec;
+use crate::systems::admin::{self, Admin};
+use crate::systems::{health, logging, telemetry};
 
 #[derive(Parser, Debug)]
 #[command(about = "Token Handler")]
@@ -28,68 +31,143 @@ struct Args {
     /// Path of the configuration file
     #[arg(short = 'f', long, value_name = "FILE", default_value = "config.hcl")]
     pub config_file: String,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Check a request against an API's authorization rules and print the decision;
+    /// exits with 0 if allowed and 5 if denied
+    Authorize {
+        bridge: String,
+        api: String,
+        /// Access token, its JSON payload or `@<file>` containing either
+        token: String,
+        /// Request line relative to the API, e.g. "GET /items/1"
+        request: String,
+    },
 }
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
     let args = Args::parse();
 
-    let config_file = std::fs::read_to_string(&args.config_file).unwrap_or_else(|e| {
-        eprintln!("Unable to read configuration file `{}`: {e}", &args.config_file);
-        std::process::exit(1);
+    let config = loader::load(&args.config_file).unwrap_or_else(|e| {
+        eprintln!("{e}");
+        std::process::exit(e.exit_code())
     });
 
-    let re = Regex::new("\\$\\{([a-zA-Z_0-9]+)\\}").unwrap();
-    let vars = re.captures_iter(&config_file).fold(Substitutions::new(), |acc: Substitutions, caps: Captures| {
-        let key = &caps[1];
-        match std::env::var(key) {
-            Ok(val) => acc.ok(key.into(), val),
-            Err(_) => acc.err(key.into())
-        }
-    });
-    let vars = match vars {
-        Substitutions::Ok(m) => m,
-        Substitutions::Err(v) => {
-            eprintln!("Unable to find environment variables:\n{}", v.iter().map(|s| format!("- {s}\n")).join(""));
-            std::process::exit(2);
+    if let Some(Command::Authorize { bridge, api, token, request }) = args.command {
+        match commands::authorize(&config, &bridge, &api, &token, &request) {
+            Ok(decision) => {
+                println!("{decision}");
+                std::process::exit(if matches!(decision, Decision::Allow(_)) { 0 } else { 5 })
+            },
+            Err(e) => {
+                eprintln!("Unable to decide: {e}");
+                std::process::exit(6)
+            },
         }
-    };
-    let config_file = re.replace_all(&config_file, |caps: &Captures| { &vars[&caps[1]] });
+    }
 
-    let spec: Spec = hcl::from_str(&config_file).unwrap_or_else(|e| {
-        eprintln!("Unable to parse configuration file: {e}");
-        std::process::exit(3)
-    });
+    logging::init(&config);
 
-    let config: web::Data<Config> = <&Spec as TryInto<Arc<Config>>>::try_into(&spec).unwrap_or_else(|e| {
+    let _ = hcl::to_string(&config).map(|c| info!("Loaded config\n{}", c));
+
+    telemetry::init(config.tracing.as_ref()).unwrap_or_else(|e| {
         eprintln!("Unable to understand configuration file: {e}");
         std::process::exit(4)
-    }).into();
+    });
 
-    env_logger::builder()
-        .format_target(false)
-        .parse_env(env_logger::Env::new().default_filter_or("info"))
-        .init();
+    let tls = config.admin.as_ref()
+        .and_then(|admin| admin.tls.as_ref())
+        .map(admin::acceptor)
+        .transpose()
+        .unwrap_or_else(|e| {
+            eprintln!("Unable to understand configuration file: {e}");
+            std::process::exit(4)
+        });
 
-    let _ = hcl::to_string(&config).map(|c| info!("Loaded config\n{}", c));
+    let listener = listener(config.port)?;
+    let metrics = match config.metrics.as_ref().and_then(|metrics| metrics.port) {
+        Some(metrics_port) => Some(HttpServer::new(|| App::new().service(endpoints::metrics))
+            .workers(1)
+            .bind(("0.0.0.0", metrics_port))?
+            .run()),
+        None => None,
+    };
+    let admin = web::Data::new(Admin::new(args.config_file, config.clone()));
+    let admin_server = match (&config.admin, tls) {
+        (Some(settings), tls) => {
+            let data = admin.clone();
+            let server = HttpServer::new(move || App::new()
+                .app_data(data.clone())
+                .wrap_fn(admin::authorize)
+                .configure(endpoints::admin))
+                .workers(1);
+            Some(match tls {
+                Some(tls) => server.bind_openssl(&settings.address, tls)?,
+                None => server.bind(&settings.address)?,
+            }.run())
+        },
+        (None, _) => None,
+    };
+    drop(config);
 
-    let port = config.port;
-    HttpServer::new(move || {
+    let main = async {
+        loop {
+            let (config, _) = admin.config();
+            config.bridges.values()
+                .flat_map(|bridge| bridge.apis.values())
+                .filter(|api| api.upstream.health_check.is_some())
+                .for_each(|api| { actix_web::rt::spawn(health::watch(Arc::downgrade(api))); });
+            let server = serve(config.into(), listener.try_clone()?)?;
+            let handle = server.handle();
+            tokio::select! {
+                result = server => return result,
+                _ = admin.reloaded() => {
+                    let _ = hcl::to_string(&admin.config().0).map(|c| info!("Reloaded config\n{}", c));
+                    // the previous server finishes the requests under way, while the next one accepts new ones
+                    actix_web::rt::spawn(handle.stop(true));
+                },
+            }
+        }
+    };
+    let result = futures_util::future::try_join3(main, optional(metrics), optional(admin_server)).await.map(|_| ());
+    telemetry::shutdown();
+    result
+}
+
+/// The socket of the main port, shared by the servers of each reloaded config
+fn listener(port: u16) -> std::io::Result<TcpListener> {
+    let socket = Socket::new(Domain::IPV4, Type::STREAM, None)?;
+    socket.set_reuse_address(true)?;
+    socket.bind(&SocketAddr::from(([0, 0, 0, 0], port)).into())?;
+    socket.listen(1024)?;
+    Ok(socket.into())
+}
+
+async fn optional(server: Option<Server>) -> std::io::Result<()> {
+    match server {
+        Some(server) => server.await,
+        None => Ok(()),
+    }
+}
+
+/// Serves the bridges and APIs of a config
+fn serve(config: web::Data<Config>, listener: TcpListener) -> std::io::Result<Server> {
+    Ok(HttpServer::new(move || {
         let config = config.clone();
         let expose_errors = config.expose_errors;
-        let cors = Cors::default()
-            .allow_any_origin()
-            .allow_any_method()
-            .allow_any_header()
-            .supports_credentials()
-            .max_age(3600);
+        // one policy per scope, since nested CORS middlewares would answer preflights on behalf of each other
+        let cors = |policy: Option<&CorsConfig>| Condition::new(policy.is_some(), policy.map(CorsConfig::middleware).unwrap_or_default());
         let builder = App::new()
-            .wrap(cors)
             .app_data(config.clone())
-            .service(endpoints::health)
+            .wrap_fn(telemetry::trace)
             .wrap_fn(move |req, srv| {
                 srv.call(req).map(move |res| {
-                    res.map(|mut res: ServiceResponse<EitherBody<BoxBody>>| {
+                    res.map(|mut res: ServiceResponse<BoxBody>| {
                         let error = res.response_mut().extensions_mut().remove::<ErrorResponse>();
                         match (error, expose_errors) {
                             (Some(body), true) => {
@@ -102,24 +180,42 @@ async fn main() -> std::io::Result<()> {
                         }
                     })
                 })
-            });
+            })
+            // outermost, so that everything logged while handling a request, and its errors, carry its id
+            .wrap_fn(logging::with_request_id);
         config.bridges.iter()
             .map(|(id, bridge)| {
+                let bridge_cors = bridge.cors.as_ref().or(config.cors.as_ref());
+                let apis = bridge.apis.iter().map(|(api_id, api)| {
+                    web::scope(&format!("/bridge/{}/proxy/{}", id, api_id))
+                        .wrap(cors(api.cors.as_ref().or(bridge_cors)))
+                        .app_data::<web::Data<Api>>(api.clone().into())
+                        .route("/{tail:.*}", web::to(endpoints::proxy))
+                }).collect::<Vec<_>>();
                 let scope = web::scope(&format!("/bridge/{}", id))
+                    .wrap(cors(bridge_cors))
                     .app_data::<web::Data<Bridge>>(bridge.clone().into())
                     .service(endpoints::me)
                     .service(endpoints::login)
                     .service(endpoints::login2)
-                    .service(endpoints::logout);
-                bridge.apis.iter().map(|(id, api)| {
-                    web::scope(&format!("/proxy/{}", id))
-                        .app_data::<web::Data<Api>>(api.clone().into())
-                        .route("/{tail:.*}", web::to(endpoints::proxy))
-                }).fold(scope, |scope, api| scope.service(api))
-            }).fold(builder, |builder, bridge| builder.service(bridge))
+                    .service(endpoints::logout)
+                    .service(endpoints::logout_silently)
+                    .service(endpoints::frontchannel_logout);
+                (apis, scope)
+            })
+            // APIs first, so that the bridge's scope doesn't swallow their requests
+            .fold(builder, |builder, (apis, bridge)| apis.into_iter()
+                .fold(builder, |builder, api| builder.service(api))
+                .service(bridge))
+            .service(web::scope("")
+                .wrap(cors(config.cors.as_ref()))
+                .service(endpoints::health)
+                .service(endpoints::ready)
+                .configure(|scope| if config.metrics.as_ref().is_some_and(|metrics| metrics.port.is_none()) {
+                    scope.service(endpoints::metrics);
+                }))
     })
-        .bind(("0.0.0.0", port))?
-        .run()
-        .await
+        .listen(listener)?
+        .run())
 }
 
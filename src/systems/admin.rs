@@ -0,0 +1,109 @@
+//! The admin listener, which introspects the config being served and swaps it for a reloaded one
+
+use std::future::{ready, Future};
+use std::sync::{Arc, RwLock};
+use actix_web::{web, HttpResponse};
+use actix_web::body::EitherBody;
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse};
+use actix_web::http::header;
+use futures_util::future::Either;
+use futures_util::FutureExt;
+use openssl::ssl::{SslAcceptor, SslAcceptorBuilder, SslFiletype, SslMethod, SslVerifyMode};
+use openssl::x509::X509Name;
+use tokio::sync::Notify;
+use crate::components::config::Config;
+use crate::components::loader;
+use crate::components::spec::AdminTlsSpec;
+use crate::error::{ConfigError, LoadError};
+use crate::systems::crypto::constant_time_eq;
+
+pub struct Admin {
+    config_file: String,
+    config: RwLock<(Arc<Config>, i64)>,
+    /// Fixed at start, like the rest of the admin settings
+    token: Option<String>,
+    reloads: Notify,
+    pub started_at: i64,
+}
+
+impl Admin {
+    pub fn new(config_file: String, config: Arc<Config>) -> Self {
+        let now = chrono::Utc::now().timestamp();
+        Admin {
+            config_file,
+            token: config.admin.as_ref().and_then(|admin| admin.token.clone()),
+            config: RwLock::new((config, now)),
+            reloads: Notify::new(),
+            started_at: now,
+        }
+    }
+
+    /// The config being served, and when it was loaded
+    pub fn config(&self) -> (Arc<Config>, i64) {
+        self.config.read().unwrap().clone()
+    }
+
+    /// Loads the config file again and serves it from now on, unless it can't be loaded or needs a restart
+    pub fn reload(&self) -> Result<Arc<Config>, LoadError> {
+        let config = loader::load(&self.config_file)?;
+        let changed = restart_required(&self.config().0, &config);
+        if !changed.is_empty() {
+            return Err(LoadError::Config(ConfigError::RestartRequired(changed.join(", "))));
+        }
+        *self.config.write().unwrap() = (config.clone(), chrono::Utc::now().timestamp());
+        self.reloads.notify_one();
+        Ok(config)
+    }
+
+    /// Resolves once a reloaded config is to be served
+    pub async fn reloaded(&self) {
+        self.reloads.notified().await
+    }
+}
+
+/// The settings which are fixed at start, but differ in a reloaded config
+fn restart_required(running: &Config, reloaded: &Config) -> Vec<&'static str> {
+    [
+        ("port", running.port == reloaded.port),
+        ("metrics", running.metrics == reloaded.metrics),
+        ("tracing", running.tracing == reloaded.tracing),
+        ("logging", running.logging == reloaded.logging),
+        ("request_id", running.request_id == reloaded.request_id),
+        ("admin", running.admin == reloaded.admin),
+    ].into_iter()
+        .filter(|(_, same)| !same)
+        .map(|(name, _)| name)
+        .collect()
+}
+
+/// HTTPS which only lets in clients with a certificate issued by one of the configured CAs
+pub fn acceptor(tls: &AdminTlsSpec) -> Result<SslAcceptorBuilder, ConfigError> {
+    let mut builder = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls())?;
+    builder.set_private_key_file(&tls.key, SslFiletype::PEM)?;
+    builder.set_certificate_chain_file(&tls.certificate)?;
+    builder.set_ca_file(&tls.client_ca)?;
+    builder.set_client_ca_list(X509Name::load_client_ca_file(&tls.client_ca)?);
+    builder.set_verify(SslVerifyMode::PEER | SslVerifyMode::FAIL_IF_NO_PEER_CERT);
+    Ok(builder)
+}
+
+/// Turns away requests without the admin token, if one is configured
+pub fn authorize<S, B>(req: ServiceRequest, srv: &S) -> impl Future<Output = Result<ServiceResponse<EitherBody<B>>, actix_web::Error>>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error>,
+{
+    let token = req.app_data::<web::Data<Admin>>().and_then(|admin| admin.token.clone());
+    let presented = req.headers().get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    let authorized = match token {
+        Some(token) => presented.is_some_and(|presented| constant_time_eq(presented.as_bytes(), token.as_bytes())),
+        None => true,
+    };
+    match authorized {
+        true => Either::Left(srv.call(req).map(|res| res.map(ServiceResponse::map_into_left_body))),
+        false => Either::Right(ready(Ok(req.into_response(HttpResponse::Unauthorized()
+            .insert_header((header::WWW_AUTHENTICATE, "Bearer"))
+            .finish()).map_into_right_body()))),
+    }
+}
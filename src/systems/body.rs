@@ -0,0 +1,72 @@
+//! Forwarding of request bodies to the backend
+
+use std::error::Error;
+use actix_web::web;
+use actix_web::rt::task::JoinHandle;
+use derive_more::Display;
+use futures_util::StreamExt;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use crate::error::ApiError;
+
+/// Why a request body couldn't be forwarded, which the backend sees as a failed upload
+#[derive(Display, Debug)]
+pub enum BodyError {
+    #[display(fmt = "request body exceeds {} bytes", _0)]
+    TooLarge(u64),
+    #[display(fmt = "request body broke off: {}", _0)]
+    Client(String),
+}
+
+impl Error for BodyError {}
+
+/// Streams a request body to the backend while it arrives, up to `limit` bytes
+///
+/// The body is read no faster than the backend takes it, and no longer than it does. Abort the returned task once the
+/// request to the backend has failed, so that the rest of the body isn't waited for.
+pub fn forward(mut payload: web::Payload, limit: Option<u64>) -> (reqwest::Body, JoinHandle<()>) {
+    let (tx, rx) = mpsc::channel::<Result<web::Bytes, BodyError>>(10);
+    let task = actix_web::rt::spawn(async move {
+        let mut size = 0;
+        while let Some(chunk) = payload.next().await {
+            let chunk = chunk.map_err(|e| BodyError::Client(e.to_string())).and_then(|bytes| {
+                size += bytes.len() as u64;
+                match limit {
+                    Some(limit) if size > limit => Err(BodyError::TooLarge(limit)),
+                    _ => Ok(bytes),
+                }
+            });
+            let failed = chunk.is_err();
+            // the backend's request is gone, and with it the reason to read on
+            if tx.send(chunk).await.is_err() || failed {
+                return;
+            }
+        }
+    });
+    (reqwest::Body::wrap_stream(ReceiverStream::new(rx)), task)
+}
+
+/// The body error which made a request to the backend fail, if any
+pub fn cause(error: &reqwest::Error) -> Option<&BodyError> {
+    let mut source = error.source();
+    while let Some(error) = source {
+        if let Some(cause) = error.downcast_ref::<BodyError>() {
+            return Some(cause);
+        }
+        source = error.source();
+    }
+    None
+}
+
+/// Blames the client for failed requests whose body broke off on its side
+pub fn blame(error: ApiError) -> ApiError {
+    let cause = match error {
+        ApiError::Reqwest(ref e) => cause(e),
+        _ => None,
+    };
+    match cause {
+        Some(BodyError::TooLarge(limit)) => ApiError::PayloadTooLarge.context(format!("Request body exceeds {limit} bytes")),
+        Some(BodyError::Client(reason)) => ApiError::BadRequest.context(format!("Request body broke off: {reason}")),
+        None => error,
+    }
+}
@@ -3,52 +3,99 @@ use rmp_serde::encode::to_vec;
 use rmp_serde::decode::from_slice;
 use std::io::Write;
 use std::str::from_utf8;
-use actix_web::cookie::{Cookie, SameSite};
+use actix_web::cookie::{Cookie, CookieBuilder, SameSite};
+use actix_web::HttpRequest;
+use actix_web::cookie::time::OffsetDateTime;
 use base64::{Engine as _, engine::{general_purpose}};
 use itertools::Itertools;
+use opentelemetry::KeyValue;
 use rand::prelude::SliceRandom;
 use crate::components::config::Bridge;
 use crate::error::{ApiError, Context};
 use crate::systems::crypto::{decrypt, encrypt};
-
-pub const SESSION_COOKIE_NAME: &'static str = "bff-session";
+use crate::systems::{metrics, telemetry};
+use crate::systems::logging::Audit;
 
 /// Serialises, compresses, encrypts, and base64-encodes an instance and bakes it into a cookie
 /// which is opaque for the client
-pub fn create<'a, T: Serialize>(value: T, bridge: &Bridge, same_site: SameSite) -> Result<Cookie<'static>, ApiError> {
+pub fn create<T: Serialize>(value: T, bridge: &Bridge, same_site: SameSite) -> Result<Cookie<'static>, ApiError> {
     let config = bridge.config()?;
     let key_id = config.active_keys.choose(&mut rand::thread_rng()).ok_or(ApiError::Internal)?;
     let key = &config.keys[key_id];
+    let _span = telemetry::span("encrypt cookie", vec![KeyValue::new("token_handler.key", key_id.clone())]);
 
     let serialised = to_vec(&value)?;
     let compressed = compress(&serialised)?;
-    let encrypted = encrypt(SESSION_COOKIE_NAME, &compressed, &key.value)?;
-    Ok(Cookie::build(String::from(SESSION_COOKIE_NAME), [key_id.as_bytes(), &encrypted]
+    let encrypted = encrypt(&bridge.cookie.name, &compressed, &key.value)?;
+    metrics::key_used(key_id, "encrypt");
+    let cookie = build(bridge, [key_id.as_bytes(), &encrypted]
         .iter()
         .map(|x| general_purpose::URL_SAFE.encode(x))
         .join(".")
     )
+        .same_site(same_site)
+        .finish();
+    metrics::cookie_size(&bridge.id, cookie.name().len() + 1 + cookie.value().len());
+    Ok(cookie)
+}
+
+/// Creates an expired session cookie which makes the client forget its session
+pub fn clear(bridge: &Bridge) -> Cookie<'static> {
+    build(bridge, String::new())
+        .same_site(bridge.cookie.same_site())
+        .expires(OffsetDateTime::UNIX_EPOCH)
+        .finish()
+}
+
+/// Reads the session cookie of a bridge from a request
+pub fn get(req: &HttpRequest, bridge: &Bridge) -> Option<Cookie<'static>> {
+    req.cookie(&bridge.cookie.name)
+}
+
+fn build(bridge: &Bridge, value: String) -> CookieBuilder<'static> {
+    let builder = Cookie::build(bridge.cookie.name.clone(), value)
         .http_only(true)
         .secure(true)
-        .same_site(same_site)
-        .path(format!("/bridge/{}", bridge.id))
-        .finish())
+        .path(bridge.cookie.path.clone());
+    match bridge.cookie.domain {
+        Some(ref domain) => builder.domain(domain.clone()),
+        None => builder,
+    }
 }
 
 /// base64-decodes, decrypts, decompresses and deserialises a cookie to an instance
 pub fn decode<T: for<'a> Deserialize<'a>>(cookie: &Cookie, bridge: &Bridge) -> Result<T, ApiError> {
+    unseal(cookie, bridge).inspect_err(|e| Audit::new("cookie_decode_failed", &bridge.id).reason(failure(e)).log())
+}
+
+fn unseal<T: for<'a> Deserialize<'a>>(cookie: &Cookie, bridge: &Bridge) -> Result<T, ApiError> {
     let config = bridge.config()?;
     let (key_id, value) = cookie.value().split_once(".")
         .ok_or(ApiError::Unauthorized).context("malformed: expected '.'")?;
-    let key_id = general_purpose::URL_SAFE.decode(&key_id)?;
-    let decoded = general_purpose::URL_SAFE.decode(&value)?;
+    let key_id = general_purpose::URL_SAFE.decode(key_id)?;
+    let decoded = general_purpose::URL_SAFE.decode(value)?;
     let key_id = from_utf8(&key_id)?;
     let key = config.keys.get(key_id).ok_or(ApiError::UnknownKey)?;
+    metrics::key_used(key_id, "decrypt");
+    let _span = telemetry::span("decrypt cookie", vec![KeyValue::new("token_handler.key", key_id.to_string())]);
     let decrypted = decrypt(cookie.name(), &decoded, &key.value)?;
     let decompressed = decompress(&decrypted)?;
     Ok(from_slice(&decompressed)?)
 }
 
+/// The step of decoding a cookie which failed
+fn failure(e: &ApiError) -> &'static str {
+    match e {
+        ApiError::Context(inner, _) => failure(inner),
+        ApiError::Unauthorized | ApiError::B64(_) | ApiError::Utf8(_) => "malformed",
+        ApiError::UnknownKey => "unknown_key",
+        ApiError::Aead(_) => "decryption",
+        ApiError::Io(_) | ApiError::Internal => "decompression",
+        ApiError::Decode(_) => "deserialization",
+        _ => "other",
+    }
+}
+
 fn compress(input: &[u8]) -> Result<Vec<u8>, ApiError> {
     let mut writer = brotli::CompressorWriter::new(Vec::new(), 4096, 11, 22);
     writer.write_all(input)?;
@@ -36,9 +36,19 @@ pub fn decrypt(name: &str, value: &[u8], key: &[u8]) -> Result<Vec<u8>, ApiError
         .map_err(|_| ApiError::Unauthorized)
 }
 
+/// Compares secrets in a time which doesn't depend on where they differ
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// The start of a key's SHA-256 digest in hex, which tells keys apart without giving them away
+pub fn fingerprint(key: &[u8]) -> String {
+    Sha256::digest(key).iter().take(8).map(|b| format!("{b:02x}")).collect()
+}
+
 pub fn hash(input: &str) -> Result<String, ApiError> {
     let mut hasher = Sha256::new();
     hasher.update(input.as_bytes());
     let result = hasher.finalize();
-    Ok(general_purpose::URL_SAFE_NO_PAD.encode(&result))
+    Ok(general_purpose::URL_SAFE_NO_PAD.encode(result))
 }
\ No newline at end of file
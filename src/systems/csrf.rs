@@ -0,0 +1,103 @@
+use actix_web::cookie::Cookie;
+use actix_web::cookie::time::OffsetDateTime;
+use actix_web::http::{header, Method};
+use actix_web::HttpRequest;
+use nanoid::nanoid;
+use crate::components::config::Bridge;
+use crate::components::types::SessionCookie;
+use crate::error::{ApiError, Context};
+use crate::systems::crypto::constant_time_eq;
+use crate::systems::{session, websocket};
+
+/// Creates a new token for double-submit CSRF protection, if the bridge uses it
+pub fn new_token(bridge: &Bridge) -> Option<String> {
+    bridge.csrf.as_ref().filter(|csrf| csrf.double_submit).map(|_| nanoid!(32))
+}
+
+/// Bakes the CSRF token into a cookie which, unlike the session cookie, is readable by scripts of the client
+///
+/// It lasts as long as the session cookie, so it has to be re-issued along with it.
+pub fn cookie(session: &SessionCookie, bridge: &Bridge, now: i64) -> Option<Cookie<'static>> {
+    let (csrf, token) = bridge.csrf.as_ref().zip(session.csrf_token.as_ref())?;
+    let mut cookie = build(bridge, csrf.token_cookie.clone(), token.clone());
+    if let Some(max_age) = session::max_age(session, bridge, now) {
+        cookie.set_max_age(max_age);
+    }
+    Some(cookie)
+}
+
+fn build(bridge: &Bridge, name: String, value: String) -> Cookie<'static> {
+    let builder = Cookie::build(name, value)
+        .secure(true)
+        .same_site(bridge.cookie.same_site())
+        .path("/");
+    match bridge.cookie.domain {
+        Some(ref domain) => builder.domain(domain.clone()),
+        None => builder,
+    }.finish()
+}
+
+/// Creates an expired CSRF cookie, if the bridge uses double-submit CSRF protection
+pub fn clear(bridge: &Bridge) -> Option<Cookie<'static>> {
+    let csrf = bridge.csrf.as_ref().filter(|csrf| csrf.double_submit)?;
+    let mut cookie = build(bridge, csrf.token_cookie.clone(), String::new());
+    cookie.set_expires(OffsetDateTime::UNIX_EPOCH);
+    Some(cookie)
+}
+
+/// Verifies that a state-changing request was issued by a legitimate client
+pub fn verify(req: &HttpRequest, bridge: &Bridge, session: &SessionCookie) -> Result<(), ApiError> {
+    // browsers can't add headers to WebSocket handshakes, which aren't subject to CORS either, but send their origin,
+    // so that is checked regardless of the bridge's CSRF settings
+    if websocket::is_upgrade(req) {
+        if req.method() != Method::GET {
+            return Err(ApiError::Forbidden).context("CSRF: WebSocket upgrade without GET");
+        }
+        let allowed_origins = bridge.csrf.as_ref().map(|csrf| csrf.allowed_origins.as_slice()).unwrap_or_default();
+        return check_origin(req, allowed_origins);
+    }
+    let Some(ref csrf) = bridge.csrf else {
+        return Ok(());
+    };
+    if matches!(*req.method(), Method::GET | Method::HEAD | Method::OPTIONS | Method::TRACE) {
+        return Ok(());
+    }
+    if let Some(ref name) = csrf.header {
+        if !req.headers().contains_key(name) {
+            return Err(ApiError::Forbidden).context(format!("CSRF: missing header {name}"));
+        }
+    }
+    if csrf.check_origin {
+        check_origin(req, &csrf.allowed_origins)?;
+    }
+    if csrf.double_submit {
+        let expected = session.csrf_token.as_ref()
+            .ok_or(ApiError::Forbidden).context("CSRF: session has no token, please log in again")?;
+        let actual = req.headers().get(&csrf.token_header)
+            .ok_or(ApiError::Forbidden).context(format!("CSRF: missing header {}", csrf.token_header))?;
+        if !constant_time_eq(expected.as_bytes(), actual.as_bytes()) {
+            return Err(ApiError::Forbidden).context("CSRF: token mismatch");
+        }
+    }
+    Ok(())
+}
+
+/// Verifies that a request comes from our own origin or an allowed one
+fn check_origin(req: &HttpRequest, allowed_origins: &[String]) -> Result<(), ApiError> {
+    match req.headers().get(header::ORIGIN).map(|o| o.to_str()) {
+        Some(Ok(origin)) => {
+            let conn = req.connection_info();
+            let own_origin = format!("{}://{}", conn.scheme(), conn.host());
+            if origin != own_origin && !allowed_origins.iter().any(|o| o == origin) {
+                return Err(ApiError::Forbidden).context(format!("CSRF: origin {origin} not allowed"));
+            }
+            Ok(())
+        },
+        Some(Err(_)) => Err(ApiError::Forbidden).context("CSRF: malformed origin"),
+        // fall back to fetch metadata if the browser didn't send an origin
+        None => match req.headers().get("sec-fetch-site").and_then(|s| s.to_str().ok()) {
+            Some("same-origin") | Some("none") => Ok(()),
+            _ => Err(ApiError::Forbidden).context("CSRF: unable to verify origin"),
+        },
+    }
+}
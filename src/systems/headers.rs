@@ -0,0 +1,444 @@
+use std::net::{IpAddr, Ipv4Addr};
+use std::str::FromStr;
+use actix_web::HttpRequest;
+use actix_web::cookie::Cookie;
+use itertools::Itertools;
+use reqwest::header;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use reqwest::Method;
+use serde_json::Value;
+use url::Url;
+use token_handler::identity;
+use crate::components::config::{Api, Bridge, IdentityConfig, RequestHeaderRules};
+use crate::components::network::Network;
+use crate::components::spec::{ForwardedHeaders, ForwardedSpec};
+use crate::systems::token::{claim, claim_to_string};
+
+/// Headers which only concern a single connection and must never be forwarded, cf. RFC 9110, section 7.6.1
+const HOP_BY_HOP: [HeaderName; 8] = [
+    header::CONNECTION,
+    HeaderName::from_static("keep-alive"),
+    header::PROXY_AUTHENTICATE,
+    header::PROXY_AUTHORIZATION,
+    header::TE,
+    header::TRAILER,
+    header::TRANSFER_ENCODING,
+    header::UPGRADE,
+];
+
+/// Collects the hop-by-hop headers of a message, including those nominated by the values of its `Connection` header
+pub fn hop_by_hop<'a>(connection: impl IntoIterator<Item = &'a HeaderValue>) -> Vec<HeaderName> {
+    connection.into_iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|name| HeaderName::from_bytes(name.trim().to_lowercase().as_bytes()).ok())
+        .chain(HOP_BY_HOP)
+        .collect()
+}
+
+/// Scopes a cookie set by a backend to the API's path below the bridge and makes sure it can't shadow our own cookies
+///
+/// Paths below `backend` map onto the same paths below the API; without a `backend`, e.g. when a route rewrote the
+/// path, cookies get scoped to the whole API.
+pub fn rewrite_set_cookie(value: &HeaderValue, api: &Api, bridge: &Bridge, backend: Option<&Url>) -> Option<Cookie<'static>> {
+    let mut cookie = Cookie::parse(value.to_str().ok()?.to_owned()).ok()?;
+    let reserved = bridge.csrf.as_ref().map(|csrf| csrf.token_cookie.as_str());
+    // a __Host- cookie requires path "/", which we can't grant
+    if cookie.name() == bridge.cookie.name || Some(cookie.name()) == reserved || cookie.name().starts_with("__Host-") {
+        return None;
+    }
+    let prefix = prefix(api, bridge);
+    let path = cookie.path().zip(backend)
+        .and_then(|(path, backend)| path.strip_prefix(backend.path().trim_end_matches('/')))
+        .filter(|rest| rest.is_empty() || rest.starts_with('/'))
+        .map(|rest| format!("{prefix}{rest}"))
+        .unwrap_or(prefix);
+    cookie.set_path(path);
+    cookie.unset_domain();
+    cookie.set_secure(true);
+    Some(cookie)
+}
+
+/// Points a `Location` or `Content-Location` header at the API's path below the bridge if it points below `backend`
+///
+/// Other values, including relative references which the client resolves correctly anyway, are left alone.
+pub fn rewrite_location(value: &HeaderValue, api: &Api, bridge: &Bridge, backend: &Url) -> Option<HeaderValue> {
+    let value = value.to_str().ok()?;
+    let location = match Url::parse(value) {
+        Ok(location) => location,
+        Err(_) if value.starts_with('/') && !value.starts_with("//") => backend.join(value).ok()?,
+        Err(_) => return None,
+    };
+    if location.origin() != backend.origin() {
+        return None;
+    }
+    let rest = location.path().strip_prefix(backend.path().trim_end_matches('/'))
+        .filter(|rest| rest.is_empty() || rest.starts_with('/'))?;
+    let mut rewritten = format!("{}{rest}", prefix(api, bridge));
+    if let Some(query) = location.query() {
+        rewritten = format!("{rewritten}?{query}");
+    }
+    if let Some(fragment) = location.fragment() {
+        rewritten = format!("{rewritten}#{fragment}");
+    }
+    HeaderValue::from_str(&rewritten).ok()
+}
+
+/// Sets the forwarding headers of an API, which tell the backend about the client and the URL it requested
+///
+/// Forwarding headers of the client are only passed on if it is one of the `trusted` proxies; otherwise they are
+/// replaced, so that clients can't pretend to be someone else.
+pub fn forward(spec: &ForwardedSpec, trusted: &[Network], req: &HttpRequest, api: &Api, bridge: &Bridge, headers: &mut HeaderMap) {
+    let peer = req.peer_addr().map(|addr| addr.ip());
+    let incoming = match peer {
+        Some(peer) if trusted.iter().any(|network| network.contains(&peer)) => Forwarding::of(req.headers()),
+        _ => Forwarding::default(),
+    };
+    let nodes = incoming.nodes.into_iter()
+        .chain(peer.map(|peer| peer.to_string()))
+        .collect::<Vec<_>>();
+    // we only listen to plain HTTP ourselves
+    let proto = incoming.proto.unwrap_or_else(|| "http".into());
+    let host = incoming.host.or_else(|| {
+        req.headers().get(header::HOST).and_then(|host| host.to_str().ok()).map(String::from)
+            .or_else(|| req.uri().authority().map(|authority| authority.to_string()))
+    });
+    let prefix = format!("{}{}", incoming.prefix.as_deref().unwrap_or_default().trim_end_matches('/'), prefix(api, bridge));
+
+    for name in FORWARDED_HEADERS {
+        headers.remove(name);
+    }
+    let mut set = |name: &'static str, value: String| {
+        if let Ok(value) = HeaderValue::from_str(&value) {
+            headers.insert(HeaderName::from_static(name), value);
+        }
+    };
+    if spec.headers.contains(&ForwardedHeaders::Forwarded) {
+        let value = nodes.iter().enumerate()
+            .map(|(i, node)| {
+                let mut element = format!("for={}", forwarded_value(&node_of(node)));
+                if i == 0 {
+                    if let Some(ref host) = host {
+                        element = format!("{element};host={}", forwarded_value(host));
+                    }
+                    element = format!("{element};proto={}", forwarded_value(&proto));
+                }
+                element
+            })
+            .join(", ");
+        set("forwarded", value);
+    }
+    if spec.headers.contains(&ForwardedHeaders::XForwarded) {
+        set("x-forwarded-for", nodes.join(", "));
+        set("x-forwarded-proto", proto);
+        if let Some(host) = host {
+            set("x-forwarded-host", host);
+        }
+        set("x-forwarded-prefix", prefix);
+    }
+}
+
+const FORWARDED_HEADERS: [&str; 5] = ["forwarded", "x-forwarded-for", "x-forwarded-proto", "x-forwarded-host", "x-forwarded-prefix"];
+
+/// What a trusted proxy told us about the original request
+#[derive(Default)]
+struct Forwarding {
+    /// The client and the proxies it went through so far, in this order
+    nodes: Vec<String>,
+    proto: Option<String>,
+    host: Option<String>,
+    prefix: Option<String>,
+}
+
+impl Forwarding {
+    /// Reads the `Forwarded` header, or the `X-Forwarded-*` headers in its absence
+    fn of(headers: &actix_web::http::header::HeaderMap) -> Self {
+        let values = |name: &str| headers.get_all(name)
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .map(|value| value.trim().to_string())
+            .filter(|value| !value.is_empty())
+            .collect::<Vec<_>>();
+        let forwarded = values("forwarded");
+        if !forwarded.is_empty() {
+            let elements = forwarded.iter()
+                .map(|element| element.split(';')
+                    .filter_map(|pair| pair.split_once('='))
+                    .map(|(key, value)| (key.trim().to_lowercase(), value.trim().trim_matches('"').to_string()))
+                    .collect::<Vec<_>>())
+                .collect::<Vec<_>>();
+            let first = |key: &str| elements.first()?.iter().find(|(k, _)| k == key).map(|(_, value)| value.clone());
+            return Forwarding {
+                nodes: elements.iter()
+                    .filter_map(|element| element.iter().find(|(key, _)| key == "for"))
+                    .map(|(_, node)| node_name(node))
+                    .collect(),
+                proto: first("proto"),
+                host: first("host"),
+                prefix: values("x-forwarded-prefix").into_iter().next(),
+            };
+        }
+        Forwarding {
+            nodes: values("x-forwarded-for").iter().map(|node| node_name(node)).collect(),
+            proto: values("x-forwarded-proto").into_iter().next(),
+            host: values("x-forwarded-host").into_iter().next(),
+            prefix: values("x-forwarded-prefix").into_iter().next(),
+        }
+    }
+}
+
+/// The name of a node as it is passed on, i.e. its address without brackets and port, cf. RFC 7239, section 6
+///
+/// `unknown` and obfuscated identifiers like `_hidden` are kept as they are.
+fn node_name(node: &str) -> String {
+    if let Some((address, _)) = node.strip_prefix('[').and_then(|rest| rest.split_once(']')) {
+        return address.into();
+    }
+    match node.rsplit_once(':') {
+        Some((address, _)) if Ipv4Addr::from_str(address).is_ok() => address.into(),
+        _ => node.into(),
+    }
+}
+
+/// A node of the `Forwarded` header, where IPv6 addresses need brackets
+fn node_of(node: &str) -> String {
+    match IpAddr::from_str(node) {
+        Ok(IpAddr::V6(address)) => format!("[{address}]"),
+        _ => node.into(),
+    }
+}
+
+/// A value of the `Forwarded` header, which needs quotes unless it is a token, cf. RFC 7239, section 4
+fn forwarded_value(value: &str) -> String {
+    match value.chars().all(|c| c.is_ascii_alphanumeric() || "!#$%&'*+-.^_`|~".contains(c)) {
+        true => value.into(),
+        false => format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\"")),
+    }
+}
+
+fn prefix(api: &Api, bridge: &Bridge) -> String {
+    format!("/bridge/{}/proxy/{}", bridge.id, api.id)
+}
+
+/// Removes, renames and sets request headers according to the rules of an API
+pub fn apply_rules(rules: &RequestHeaderRules, headers: &mut HeaderMap, claims: &Value) {
+    for name in rules.remove.iter() {
+        headers.remove(name);
+    }
+    for (from, to) in rules.rename.iter() {
+        let values = headers.get_all(from).iter().cloned().collect::<Vec<_>>();
+        headers.remove(from);
+        values.into_iter().for_each(|value| { headers.append(to.clone(), value); });
+    }
+    for (name, template) in rules.set.iter() {
+        match template.render(claims).and_then(|value| HeaderValue::from_str(&value).ok()) {
+            Some(value) => { headers.insert(name.clone(), value); },
+            None => { headers.remove(name); },
+        }
+    }
+}
+
+/// Sets the identity headers of an API along with their signature
+pub fn sign_identity(config: &IdentityConfig, headers: &mut HeaderMap, claims: &Value, method: &Method, url: &Url, now: i64) {
+    headers.remove(header::AUTHORIZATION);
+    for (name, path) in config.headers.iter() {
+        match claim(claims, path).and_then(claim_to_string).and_then(|value| HeaderValue::from_str(&value).ok()) {
+            Some(value) => { headers.insert(name.clone(), value); },
+            None => { headers.remove(name); },
+        }
+    }
+    let signed = config.headers.iter()
+        .map(|(name, _)| (name.as_str(), headers.get(name).and_then(|value| value.to_str().ok())))
+        .collect::<Vec<_>>();
+    let path = match url.query() {
+        Some(query) => format!("{}?{}", url.path(), query),
+        None => url.path().into(),
+    };
+    let signature = identity::sign(&config.secret, now, method.as_str(), &path, &signed);
+    if let Ok(signature) = HeaderValue::from_str(&signature) {
+        headers.insert(config.signature_header.clone(), signature);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+    use actix_web::test::TestRequest;
+    use crate::components::config::Config;
+    use crate::components::spec::Spec;
+    use super::*;
+
+    fn config() -> Arc<Config> {
+        let spec = hcl::from_str::<Spec>(r#"
+            key "1" {
+              value = "TnVyIGVpbiBCZWlzcGllbCwgbmljaHQgYmVudXR6ZW4="
+              active = true
+            }
+            bridge "b1" {
+              idp = "http://idp"
+              client = "client"
+              secret = "secret"
+              csrf {
+                double_submit = true
+              }
+              api "api" {
+                backend = "http://backend/api"
+                forwarded {
+                  headers = ["forwarded", "x-forwarded"]
+                }
+              }
+            }
+        "#).unwrap();
+        Arc::<Config>::try_from(&spec).unwrap()
+    }
+
+    /// The headers sent to the backend for a request from `peer` with the client's `headers`
+    fn forwarded(peer: &str, trusted: &[&str], headers: &[(&str, &str)]) -> HeaderMap {
+        let config = config();
+        let bridge = &config.bridges["b1"];
+        let api = &bridge.apis["api"];
+        let trusted = trusted.iter().map(|network| network.parse().unwrap()).collect::<Vec<Network>>();
+        let req = headers.iter()
+            .fold(TestRequest::get().uri("/bridge/b1/proxy/api/x").insert_header(("host", "app.example.com")), |req, header| req.insert_header(*header))
+            .peer_addr(peer.parse::<SocketAddr>().unwrap())
+            .to_http_request();
+        let mut sent = HeaderMap::new();
+        forward(api.forwarded.as_ref().unwrap(), &trusted, &req, api, bridge, &mut sent);
+        sent
+    }
+
+    fn get<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+        headers.get(name).and_then(|value| value.to_str().ok())
+    }
+
+    #[test]
+    fn untrusted_forwarding_headers_are_replaced() {
+        let sent = forwarded("203.0.113.7:50000", &["10.0.0.0/8"], &[
+            ("x-forwarded-for", "1.2.3.4"),
+            ("x-forwarded-host", "evil.com"),
+            ("x-forwarded-proto", "https"),
+            ("x-forwarded-prefix", "/evil"),
+        ]);
+        assert_eq!(get(&sent, "x-forwarded-for"), Some("203.0.113.7"));
+        assert_eq!(get(&sent, "x-forwarded-host"), Some("app.example.com"));
+        assert_eq!(get(&sent, "x-forwarded-proto"), Some("http"));
+        assert_eq!(get(&sent, "x-forwarded-prefix"), Some("/bridge/b1/proxy/api"));
+        assert_eq!(get(&sent, "forwarded"), Some("for=203.0.113.7;host=app.example.com;proto=http"));
+    }
+
+    #[test]
+    fn trusted_forwarding_headers_are_passed_on() {
+        let sent = forwarded("10.0.0.2:50000", &["10.0.0.0/8"], &[
+            ("x-forwarded-for", "1.2.3.4, 10.0.0.1"),
+            ("x-forwarded-host", "www.example.com"),
+            ("x-forwarded-proto", "https"),
+            ("x-forwarded-prefix", "/app/"),
+        ]);
+        assert_eq!(get(&sent, "x-forwarded-for"), Some("1.2.3.4, 10.0.0.1, 10.0.0.2"));
+        assert_eq!(get(&sent, "x-forwarded-host"), Some("www.example.com"));
+        assert_eq!(get(&sent, "x-forwarded-proto"), Some("https"));
+        assert_eq!(get(&sent, "x-forwarded-prefix"), Some("/app/bridge/b1/proxy/api"));
+        assert_eq!(get(&sent, "forwarded"), Some("for=1.2.3.4;host=www.example.com;proto=https, for=10.0.0.1, for=10.0.0.2"));
+    }
+
+    #[test]
+    fn forwarded_header_takes_precedence() {
+        let sent = forwarded("10.0.0.2:50000", &["10.0.0.0/8"], &[
+            ("forwarded", r#"for="[2001:db8::1]:4711";proto=https;host=www.example.com, for=unknown, for=_hidden, for="198.51.100.1:80""#),
+            ("x-forwarded-for", "1.2.3.4"),
+        ]);
+        assert_eq!(get(&sent, "x-forwarded-for"), Some("2001:db8::1, unknown, _hidden, 198.51.100.1, 10.0.0.2"));
+        assert_eq!(get(&sent, "x-forwarded-proto"), Some("https"));
+        assert_eq!(get(&sent, "x-forwarded-host"), Some("www.example.com"));
+        assert_eq!(
+            get(&sent, "forwarded"),
+            Some(r#"for="[2001:db8::1]";host=www.example.com;proto=https, for=unknown, for=_hidden, for=198.51.100.1, for=10.0.0.2"#),
+        );
+    }
+
+    #[test]
+    fn ipv6_peers_are_bracketed() {
+        let sent = forwarded("[2001:db8::2]:50000", &[], &[]);
+        assert_eq!(get(&sent, "x-forwarded-for"), Some("2001:db8::2"));
+        assert_eq!(get(&sent, "forwarded"), Some(r#"for="[2001:db8::2]";host=app.example.com;proto=http"#));
+    }
+
+    #[test]
+    fn node_names() {
+        assert_eq!(node_name("[2001:db8::1]:4711"), "2001:db8::1");
+        assert_eq!(node_name("[2001:db8::1]"), "2001:db8::1");
+        assert_eq!(node_name("2001:db8::1"), "2001:db8::1");
+        assert_eq!(node_name("192.0.2.1:8080"), "192.0.2.1");
+        assert_eq!(node_name("192.0.2.1"), "192.0.2.1");
+        assert_eq!(node_name("unknown"), "unknown");
+        assert_eq!(node_name("_hidden"), "_hidden");
+        assert_eq!(node_name("_hidden:_port"), "_hidden:_port");
+    }
+
+    #[test]
+    fn forwarded_values_are_quoted_unless_tokens() {
+        assert_eq!(forwarded_value("192.0.2.1"), "192.0.2.1");
+        assert_eq!(forwarded_value("app.example.com"), "app.example.com");
+        assert_eq!(forwarded_value("[2001:db8::1]"), r#""[2001:db8::1]""#);
+        assert_eq!(forwarded_value("app.example.com:8443"), r#""app.example.com:8443""#);
+        assert_eq!(forwarded_value(r#"a"b\c"#), r#""a\"b\\c""#);
+        assert_eq!(forwarded_value(""), "");
+    }
+
+    fn location(value: &str, backend: &str) -> Option<String> {
+        let config = config();
+        let bridge = &config.bridges["b1"];
+        let backend = Url::parse(backend).unwrap();
+        rewrite_location(&HeaderValue::from_str(value).unwrap(), &bridge.apis["api"], bridge, &backend)
+            .map(|value| value.to_str().unwrap().to_string())
+    }
+
+    #[test]
+    fn locations_below_backend_are_rewritten() {
+        let backend = "http://backend/api/";
+        assert_eq!(location("http://backend/api/items/1", backend).as_deref(), Some("/bridge/b1/proxy/api/items/1"));
+        assert_eq!(location("/api/items/1?a=b#c", backend).as_deref(), Some("/bridge/b1/proxy/api/items/1?a=b#c"));
+        assert_eq!(location("http://backend/api", backend).as_deref(), Some("/bridge/b1/proxy/api"));
+    }
+
+    #[test]
+    fn other_locations_are_left_alone() {
+        let backend = "http://backend/api/";
+        assert_eq!(location("http://other/api/items/1", backend), None);
+        assert_eq!(location("https://backend/api/items/1", backend), None);
+        assert_eq!(location("http://backend:8080/api/items/1", backend), None);
+        assert_eq!(location("/apix/items/1", backend), None);
+        assert_eq!(location("/other", backend), None);
+        assert_eq!(location("//backend/api/items/1", backend), None);
+        assert_eq!(location("items/1", backend), None);
+    }
+
+    fn set_cookie(value: &str, backend: Option<&str>) -> Option<String> {
+        let config = config();
+        let bridge = &config.bridges["b1"];
+        let backend = backend.map(|backend| Url::parse(backend).unwrap());
+        rewrite_set_cookie(&HeaderValue::from_str(value).unwrap(), &bridge.apis["api"], bridge, backend.as_ref())
+            .map(|cookie| cookie.to_string())
+    }
+
+    #[test]
+    fn cookie_paths_are_mapped_below_api() {
+        let backend = Some("http://backend/api/");
+        assert_eq!(set_cookie("a=1; Path=/api/items", backend).as_deref(), Some("a=1; Secure; Path=/bridge/b1/proxy/api/items"));
+        assert_eq!(set_cookie("a=1; Path=/api", backend).as_deref(), Some("a=1; Secure; Path=/bridge/b1/proxy/api"));
+        assert_eq!(set_cookie("a=1; Path=/apix", backend).as_deref(), Some("a=1; Secure; Path=/bridge/b1/proxy/api"));
+        assert_eq!(set_cookie("a=1; Path=/", backend).as_deref(), Some("a=1; Secure; Path=/bridge/b1/proxy/api"));
+        assert_eq!(set_cookie("a=1", backend).as_deref(), Some("a=1; Secure; Path=/bridge/b1/proxy/api"));
+        assert_eq!(set_cookie("a=1; Path=/api/items; Domain=backend", backend).as_deref(), Some("a=1; Secure; Path=/bridge/b1/proxy/api/items"));
+        assert_eq!(set_cookie("a=1; Path=/api/items", None).as_deref(), Some("a=1; Secure; Path=/bridge/b1/proxy/api"));
+    }
+
+    #[test]
+    fn cookies_of_our_own_are_dropped() {
+        let backend = Some("http://backend/api/");
+        assert_eq!(set_cookie("bff-session=evil; Path=/", backend), None);
+        assert_eq!(set_cookie("bff-csrf=evil; Path=/", backend), None);
+        assert_eq!(set_cookie("__Host-a=1; Path=/; Secure", backend), None);
+    }
+}
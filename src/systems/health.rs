@@ -0,0 +1,41 @@
+//! Active health checks of upstream targets
+
+use std::sync::{Arc, Weak};
+use std::time::Duration;
+use futures_util::future::join_all;
+use log::{info, warn};
+use crate::components::config::Api;
+use crate::components::route::join;
+
+/// Probes the targets of an API's upstream at its configured interval, for as long as the API exists
+pub async fn watch(api: Weak<Api>) {
+    loop {
+        let Some(api) = api.upgrade() else { return };
+        let Some(ref check) = api.upstream.health_check else { return };
+        let interval = Duration::from_secs(check.interval as u64);
+        probe(&api).await;
+        drop(api);
+        actix_web::rt::time::sleep(interval).await;
+    }
+}
+
+async fn probe(api: &Arc<Api>) {
+    let (Ok(bridge), Some(check)) = (api.bridge(), api.upstream.health_check.as_ref()) else { return };
+    let Ok(config) = bridge.config() else { return };
+    let probes = api.upstream.targets.iter().map(|target| {
+        let request = api.http.reqwest.get(join(&target.url, &check.path, None))
+            .timeout(Duration::from_secs(check.timeout as u64))
+            .send();
+        async move {
+            let success = request.await.is_ok_and(|response| response.status().is_success());
+            (target, success)
+        }
+    });
+    for (target, success) in join_all(probes).await {
+        match target.record_probe(success, check) {
+            Some(true) => info!("[{:<width$}::{}] {} is healthy again", bridge.id, api.id, target.url, width = config.log_padding - api.id.len() - 2),
+            Some(false) => warn!("[{:<width$}::{}] {} is unhealthy", bridge.id, api.id, target.url, width = config.log_padding - api.id.len() - 2),
+            None => {},
+        }
+    }
+}
@@ -0,0 +1,537 @@
+//! Access and audit logs, written as text or JSON lines along with all other logs
+//!
+//! Access lines are logged with the target `access` and security events with the target `audit`, so that either can
+//! be filtered, e.g. by `RUST_LOG=info,access=off`. Every line logged while handling a request carries its id.
+
+use std::future::Future;
+use std::io::Write;
+use std::sync::OnceLock;
+use std::time::Instant;
+use actix_web::{web, HttpRequest};
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse};
+use actix_web::http::header::{HeaderName, HeaderValue};
+use chrono::SecondsFormat;
+use futures_util::FutureExt;
+use log::{log, Level};
+use nanoid::nanoid;
+use reqwest::header::HeaderMap;
+use serde_derive::Serialize;
+use serde_json::json;
+use url::Url;
+use crate::components::config::Config;
+use crate::components::spec::{LogFormat, RequestIdTrust};
+
+const ACCESS: &str = "access";
+const AUDIT: &str = "audit";
+const REDACTED: &str = "[redacted]";
+/// Longer request ids of clients are replaced
+const MAX_REQUEST_ID_LENGTH: usize = 128;
+
+static SETTINGS: OnceLock<Settings> = OnceLock::new();
+
+tokio::task_local! {
+    static REQUEST_ID: Option<String>;
+}
+
+struct Settings {
+    format: LogFormat,
+    redact_usernames: bool,
+    redact_queries: bool,
+    padding: usize,
+    request_id_header: HeaderName,
+    request_id_trust: RequestIdTrust,
+}
+
+/// Sets up the logger according to the config's `logging` settings
+pub fn init(config: &Config) {
+    let spec = &config.logging;
+    let _ = SETTINGS.set(Settings {
+        format: spec.format,
+        redact_usernames: spec.redact_usernames,
+        redact_queries: spec.redact_queries,
+        padding: config.log_padding,
+        request_id_header: config.request_id.header.clone(),
+        request_id_trust: config.request_id.trust,
+    });
+    let mut builder = env_logger::builder();
+    builder.parse_env(env_logger::Env::new().default_filter_or("info"));
+    match spec.format {
+        LogFormat::Text => builder.format(|buf, record| {
+            let level = buf.default_styled_level(record.level());
+            match request_id() {
+                Some(id) => writeln!(buf, "[{} {:<5} {}] {}", buf.timestamp(), level, id, record.args()),
+                None => writeln!(buf, "[{} {:<5}] {}", buf.timestamp(), level, record.args()),
+            }
+        }),
+        LogFormat::Json => builder.format(|buf, record| match record.target() {
+            // already rendered as JSON
+            ACCESS | AUDIT => writeln!(buf, "{}", record.args()),
+            target => writeln!(buf, "{}", json!({
+                "timestamp": timestamp(),
+                "level": record.level().as_str(),
+                "target": target,
+                "message": record.args().to_string(),
+                "request_id": request_id(),
+            })),
+        }),
+    };
+    builder.init();
+}
+
+/// Runs the handling of a request under the id it came with, if trusted, or a new one, and echoes it in the response
+pub fn with_request_id<S, B>(req: ServiceRequest, srv: &S) -> impl Future<Output = Result<ServiceResponse<B>, actix_web::Error>>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error>,
+{
+    let settings = settings();
+    let id = settings
+        .and_then(|settings| taken_over(&req, settings))
+        .map_or_else(|| nanoid!(), String::from);
+    let echo = settings.and_then(|settings| Some((settings.request_id_header.clone(), HeaderValue::from_str(&id).ok()?)));
+    REQUEST_ID.scope(Some(id), srv.call(req)).map(move |res| res.map(|mut res| {
+        if let Some((name, value)) = echo {
+            res.headers_mut().insert(name, value);
+        }
+        res
+    }))
+}
+
+/// The id which the request came with, if it is to be taken over
+fn taken_over<'a>(req: &'a ServiceRequest, settings: &Settings) -> Option<&'a str> {
+    // the proxies of the config being served, like for forwarding headers
+    let trusted_proxies = req.app_data::<web::Data<Config>>().map_or(&[][..], |config| &config.trusted_proxies);
+    let trusted = match settings.request_id_trust {
+        RequestIdTrust::Any => true,
+        RequestIdTrust::TrustedProxies => req.peer_addr()
+            .is_some_and(|peer| trusted_proxies.iter().any(|network| network.contains(&peer.ip()))),
+        RequestIdTrust::Never => false,
+    };
+    req.headers().get(&settings.request_id_header)
+        .filter(|_| trusted)
+        .and_then(|value| value.to_str().ok())
+        // ids end up in log lines, so they must not contain whitespace
+        .filter(|value| !value.is_empty() && value.len() <= MAX_REQUEST_ID_LENGTH && value.bytes().all(|b| b.is_ascii_graphic()))
+}
+
+/// Adds the id of the request being handled to the headers of a request to the IDP or a backend
+pub fn inject_request_id(headers: &mut HeaderMap) {
+    let value = request_id().and_then(|id| HeaderValue::from_str(&id).ok());
+    if let (Some(settings), Some(value)) = (settings(), value) {
+        headers.insert(settings.request_id_header.clone(), value);
+    }
+}
+
+/// The id of the request being handled, if any
+pub fn request_id() -> Option<String> {
+    REQUEST_ID.try_with(Clone::clone).ok().flatten()
+}
+
+/// Runs `f` as part of handling the request `id`, so that its lines carry the id even after the handler returned
+pub fn in_request<R>(id: Option<String>, f: impl FnOnce() -> R) -> R {
+    REQUEST_ID.sync_scope(id, f)
+}
+
+/// A request to an endpoint of a bridge or an API, logged once it is answered
+#[derive(Serialize)]
+pub struct Access {
+    #[serde(rename = "type")]
+    r#type: &'static str,
+    timestamp: String,
+    level: &'static str,
+    /// `proxy`, `socket` or `me`
+    pub kind: &'static str,
+    bridge: String,
+    api: Option<String>,
+    pub user: Option<String>,
+    method: String,
+    path: String,
+    status: u16,
+    latency_ms: u64,
+    pub refreshed: bool,
+    request_id: Option<String>,
+    /// The path below the API or bridge, as shown in text lines
+    #[serde(skip)]
+    tail: String,
+    #[serde(skip)]
+    start: Instant,
+}
+
+impl Access {
+    pub fn new(kind: &'static str, req: &HttpRequest, bridge: &str, api: Option<&str>, tail: &str) -> Self {
+        Access {
+            r#type: ACCESS,
+            timestamp: String::new(),
+            level: "INFO",
+            kind,
+            bridge: bridge.into(),
+            api: api.map(String::from),
+            user: None,
+            method: req.method().to_string(),
+            path: path(req),
+            status: 0,
+            latency_ms: 0,
+            refreshed: false,
+            request_id: request_id(),
+            tail: tail.into(),
+            start: Instant::now(),
+        }
+    }
+
+    pub fn log(mut self, status: u16) {
+        self.status = status;
+        self.latency_ms = self.start.elapsed().as_millis() as u64;
+        self.timestamp = timestamp();
+        self.user = self.user.take().map(redact_username);
+        let Some(settings) = settings() else { return };
+        let line = match settings.format {
+            LogFormat::Json => serde_json::to_string(&self).unwrap_or_default(),
+            LogFormat::Text => {
+                let user = self.user.as_deref().unwrap_or("-");
+                match self.api {
+                    Some(ref api) => format!("[{}] {}{} ({}) -- {:>7} {} : {}",
+                        label(settings, &self.bridge, Some(api)),
+                        self.kind,
+                        match (&self.user, self.refreshed) {
+                            (None, _) => "|a",
+                            (Some(_), true) => "|r",
+                            (Some(_), false) => "  ",
+                        },
+                        user,
+                        self.method,
+                        self.tail,
+                        self.status,
+                    ),
+                    None => format!("[{}] {:<7} ({})", label(settings, &self.bridge, None), self.kind, user),
+                }
+            },
+        };
+        log!(target: ACCESS, Level::Info, "{}", line);
+    }
+}
+
+/// A security event
+#[derive(Serialize)]
+pub struct Audit {
+    #[serde(rename = "type")]
+    r#type: &'static str,
+    timestamp: String,
+    level: &'static str,
+    event: &'static str,
+    bridge: String,
+    api: Option<String>,
+    user: Option<String>,
+    method: Option<String>,
+    path: Option<String>,
+    reason: Option<String>,
+    request_id: Option<String>,
+    /// The path below the API, as shown in text lines
+    #[serde(skip)]
+    tail: Option<String>,
+}
+
+impl Audit {
+    /// An event like `login`, `login_failed`, `logout`, `frontchannel_logout`, `refresh`, `refresh_failed`,
+    /// `revocation`, `revocation_failed`, `authorization_denied` or `cookie_decode_failed`
+    pub fn new(event: &'static str, bridge: &str) -> Self {
+        Audit {
+            r#type: AUDIT,
+            timestamp: String::new(),
+            level: match event {
+                "revocation_failed" => "WARN",
+                _ => "INFO",
+            },
+            event,
+            bridge: bridge.into(),
+            api: None,
+            user: None,
+            method: None,
+            path: None,
+            reason: None,
+            request_id: request_id(),
+            tail: None,
+        }
+    }
+
+    pub fn api(mut self, api: &str) -> Self {
+        self.api = Some(api.into());
+        self
+    }
+
+    pub fn user<'a>(mut self, user: impl Into<Option<&'a str>>) -> Self {
+        self.user = user.into().map(|user| redact_username(user.into()));
+        self
+    }
+
+    pub fn request(mut self, req: &HttpRequest) -> Self {
+        self.method = Some(req.method().to_string());
+        self.path = Some(path(req));
+        self.tail = req.match_info().get("tail").map(String::from);
+        self
+    }
+
+    pub fn reason(mut self, reason: impl ToString) -> Self {
+        self.reason = Some(reason.to_string());
+        self
+    }
+
+    pub fn log(mut self) {
+        self.timestamp = timestamp();
+        let Some(settings) = settings() else { return };
+        let level = match self.level {
+            "WARN" => Level::Warn,
+            _ => Level::Info,
+        };
+        let line = match settings.format {
+            LogFormat::Json => serde_json::to_string(&self).unwrap_or_default(),
+            LogFormat::Text => {
+                let label = label(settings, &self.bridge, self.api.as_deref());
+                let user = self.user.as_deref().unwrap_or("-");
+                let reason = self.reason.as_deref().unwrap_or_default();
+                match self.event {
+                    "authorization_denied" => format!("[{label}] denied ({user}) -- {:>7} {} : {reason}",
+                        self.method.as_deref().unwrap_or_default(),
+                        self.tail.as_deref().or(self.path.as_deref()).unwrap_or_default(),
+                    ),
+                    event => {
+                        let short = match event {
+                            "frontchannel_logout" => "fc-lout",
+                            "revocation" | "revocation_failed" => "revoke",
+                            "cookie_decode_failed" => "decode",
+                            event => event.trim_end_matches("_failed"),
+                        };
+                        match self.reason {
+                            Some(_) => format!("[{label}] {short:<7} ({user}) failed: {reason}"),
+                            None => format!("[{label}] {short:<7} ({user})"),
+                        }
+                    },
+                }
+            },
+        };
+        log!(target: AUDIT, level, "{}", line);
+    }
+}
+
+fn settings() -> Option<&'static Settings> {
+    SETTINGS.get()
+}
+
+fn label(settings: &Settings, bridge: &str, api: Option<&str>) -> String {
+    match api {
+        Some(api) => format!("{:<width$}::{}", bridge, api, width = settings.padding.saturating_sub(api.len() + 2)),
+        None => format!("{:<width$}", bridge, width = settings.padding),
+    }
+}
+
+fn redact_username(user: String) -> String {
+    match settings().is_some_and(|settings| settings.redact_usernames) {
+        true => REDACTED.into(),
+        false => user,
+    }
+}
+
+fn path(req: &HttpRequest) -> String {
+    with_query(req.path(), req.uri().query())
+}
+
+/// A URL of a backend or the IDP, as it may be logged
+pub fn url(url: &Url) -> String {
+    with_query(&format!("{}{}", url.origin().ascii_serialization(), url.path()), url.query())
+}
+
+fn with_query(path: &str, query: Option<&str>) -> String {
+    match query {
+        Some(query) if settings().is_some_and(|settings| settings.redact_queries) => format!("{path}?{}", redact_query(query)),
+        Some(query) => format!("{path}?{query}"),
+        None => path.into(),
+    }
+}
+
+/// Keeps the names of query parameters, but not their values
+fn redact_query(query: &str) -> String {
+    query.split('&')
+        .map(|pair| match pair.split_once('=') {
+            Some((name, _)) => format!("{name}={REDACTED}"),
+            None => pair.into(),
+        })
+        .collect::<Vec<_>>()
+        .join("&")
+}
+
+fn timestamp() -> String {
+    chrono::Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+    use actix_web::{App, HttpResponse};
+    use actix_web::test::{call_service, init_service, read_body_json, TestRequest};
+    use serde_json::Value;
+    use crate::components::spec::Spec;
+    use crate::error::ApiError;
+    use super::*;
+
+    fn settings(padding: usize) -> Settings {
+        trusting(RequestIdTrust::Any, padding)
+    }
+
+    fn trusting(request_id_trust: RequestIdTrust, padding: usize) -> Settings {
+        Settings {
+            format: LogFormat::Json,
+            redact_usernames: false,
+            redact_queries: false,
+            padding,
+            request_id_header: HeaderName::from_static("x-request-id"),
+            request_id_trust,
+        }
+    }
+
+    fn config() -> Arc<Config> {
+        let spec = hcl::from_str::<Spec>(r#"
+            trusted_proxies = ["10.0.0.0/8"]
+            key "1" {
+              value = "TnVyIGVpbiBCZWlzcGllbCwgbmljaHQgYmVudXR6ZW4="
+              active = true
+            }
+            bridge "b1" {
+              idp = "http://idp"
+              client = "client"
+              secret = "secret"
+              api "api" {
+                backend = "http://backend"
+              }
+            }
+        "#).unwrap();
+        Arc::<Config>::try_from(&spec).unwrap()
+    }
+
+    /// The id taken over from a request with the header `id` from `peer`
+    fn taken(trust: RequestIdTrust, peer: &str, id: &str) -> Option<String> {
+        let req = TestRequest::get()
+            .insert_header(("x-request-id", id))
+            .peer_addr(peer.parse::<SocketAddr>().unwrap())
+            .app_data(web::Data::from(config()))
+            .to_srv_request();
+        taken_over(&req, &trusting(trust, 0)).map(String::from)
+    }
+
+    #[test]
+    fn request_ids_are_taken_over_as_trusted() {
+        assert_eq!(taken(RequestIdTrust::Any, "203.0.113.7:5000", "abc").as_deref(), Some("abc"));
+        assert_eq!(taken(RequestIdTrust::Any, "10.0.0.2:5000", "abc").as_deref(), Some("abc"));
+        assert_eq!(taken(RequestIdTrust::TrustedProxies, "10.0.0.2:5000", "abc").as_deref(), Some("abc"));
+        assert_eq!(taken(RequestIdTrust::TrustedProxies, "203.0.113.7:5000", "abc"), None);
+        assert_eq!(taken(RequestIdTrust::Never, "10.0.0.2:5000", "abc"), None);
+        assert_eq!(taken(RequestIdTrust::Never, "203.0.113.7:5000", "abc"), None);
+    }
+
+    #[test]
+    fn malformed_request_ids_are_not_taken_over() {
+        assert_eq!(taken(RequestIdTrust::Any, "10.0.0.2:5000", ""), None);
+        assert_eq!(taken(RequestIdTrust::Any, "10.0.0.2:5000", "a b"), None);
+        assert_eq!(taken(RequestIdTrust::Any, "10.0.0.2:5000", "a\tb"), None);
+        assert_eq!(taken(RequestIdTrust::Any, "10.0.0.2:5000", &"a".repeat(129)), None);
+        assert_eq!(taken(RequestIdTrust::Any, "10.0.0.2:5000", &"a".repeat(128)), Some("a".repeat(128)));
+        assert_eq!(taken(RequestIdTrust::Any, "10.0.0.2:5000", "0f3c-ab_12.x:y/z").as_deref(), Some("0f3c-ab_12.x:y/z"));
+    }
+
+    /// The request id echoed in the header and the body of the error response to a request with the header `id`
+    async fn echoed(id: Option<&str>) -> (String, Value) {
+        SETTINGS.get_or_init(|| settings(0));
+        let app = init_service(App::new()
+            .app_data(web::Data::from(config()))
+            .wrap_fn(with_request_id)
+            .default_service(web::to(|| async { Err::<HttpResponse, _>(ApiError::NotFound) }))
+        ).await;
+        let req = id.into_iter()
+            .fold(TestRequest::get().uri("/missing"), |req, id| req.insert_header(("x-request-id", id)))
+            .peer_addr("10.0.0.2:5000".parse().unwrap())
+            .to_request();
+        let res = call_service(&app, req).await;
+        let header = res.headers().get("x-request-id").unwrap().to_str().unwrap().to_string();
+        (header, read_body_json(res).await)
+    }
+
+    #[actix_web::test]
+    async fn trusted_request_ids_are_echoed() {
+        let (header, body) = echoed(Some("abc-123")).await;
+        assert_eq!(header, "abc-123");
+        assert_eq!(body["request_id"], "abc-123");
+    }
+
+    #[actix_web::test]
+    async fn other_requests_get_a_new_id() {
+        let (header, body) = echoed(Some("a b")).await;
+        assert_ne!(header, "a b");
+        assert!(!header.is_empty());
+        assert_eq!(body["request_id"], header.as_str());
+        let (header, body) = echoed(None).await;
+        assert!(!header.is_empty());
+        assert_eq!(body["request_id"], header.as_str());
+    }
+
+    fn fields(value: Value) -> BTreeSet<String> {
+        value.as_object().unwrap().keys().cloned().collect()
+    }
+
+    #[test]
+    fn query_values_are_redacted() {
+        assert_eq!(redact_query("a=1&b=2"), "a=[redacted]&b=[redacted]");
+        assert_eq!(redact_query("flag&a=1"), "flag&a=[redacted]");
+        assert_eq!(redact_query("a=&b"), "a=[redacted]&b");
+        assert_eq!(redact_query("a=1&&b=2&"), "a=[redacted]&&b=[redacted]&");
+        assert_eq!(redact_query("token=x=y==&b=c=d"), "token=[redacted]&b=[redacted]");
+        assert_eq!(redact_query(""), "");
+    }
+
+    #[test]
+    fn access_lines_have_the_documented_fields() {
+        let req = TestRequest::post().uri("/bridge/b1/proxy/api/items?a=1").to_http_request();
+        let mut access = Access::new("proxy", &req, "b1", Some("api"), "/items");
+        access.user = Some("alice".into());
+        let value = serde_json::to_value(&access).unwrap();
+        let expected = ["type", "timestamp", "level", "kind", "bridge", "api", "user", "method", "path", "status", "latency_ms", "refreshed", "request_id"];
+        assert_eq!(fields(value.clone()), expected.into_iter().map(String::from).collect());
+        assert_eq!(value["type"], "access");
+        assert_eq!(value["bridge"], "b1");
+        assert_eq!(value["api"], "api");
+        assert_eq!(value["user"], "alice");
+        assert_eq!(value["method"], "POST");
+        assert_eq!(value["path"], "/bridge/b1/proxy/api/items?a=1");
+        assert_eq!(value["refreshed"], false);
+    }
+
+    #[test]
+    fn audit_lines_have_the_documented_fields() {
+        let req = TestRequest::delete().uri("/bridge/b1/proxy/api/items/1").to_http_request();
+        let audit = Audit::new("authorization_denied", "b1").api("api").user("alice").request(&req).reason("rule 'admins'");
+        let value = serde_json::to_value(&audit).unwrap();
+        let expected = ["type", "timestamp", "level", "event", "bridge", "api", "user", "method", "path", "reason", "request_id"];
+        assert_eq!(fields(value.clone()), expected.into_iter().map(String::from).collect());
+        assert_eq!(value["type"], "audit");
+        assert_eq!(value["event"], "authorization_denied");
+        assert_eq!(value["method"], "DELETE");
+        assert_eq!(value["path"], "/bridge/b1/proxy/api/items/1");
+        assert_eq!(value["reason"], "rule 'admins'");
+        assert_eq!(Audit::new("revocation_failed", "b1").level, "WARN");
+    }
+
+    #[test]
+    fn request_ids_of_lines_are_those_of_their_request() {
+        let req = TestRequest::get().to_http_request();
+        let access = in_request(Some("abc".into()), || Access::new("me", &req, "b1", None, ""));
+        assert_eq!(access.request_id.as_deref(), Some("abc"));
+        assert_eq!(Audit::new("login", "b1").request_id, None);
+    }
+
+    #[test]
+    fn labels_are_padded() {
+        assert_eq!(label(&settings(10), "b1", None), "b1        ");
+        assert_eq!(label(&settings(10), "b1", Some("api")), "b1   ::api");
+        assert_eq!(label(&settings(5), "b1", Some("long-api")), "b1::long-api");
+        assert_eq!(label(&settings(0), "b1", Some("api")), "b1::api");
+        assert_eq!(label(&settings(0), "b1", None), "b1");
+    }
+}
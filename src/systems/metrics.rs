@@ -0,0 +1,110 @@
+//! Prometheus metrics of the token handler
+
+use std::future::Future;
+use std::sync::LazyLock;
+use std::time::Instant;
+use prometheus::{Encoder, HistogramOpts, HistogramVec, IntCounterVec, Opts, Registry, TextEncoder};
+
+static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::new);
+
+/// Cookies beyond 4096 bytes are rejected by browsers
+const COOKIE_SIZE_BUCKETS: [f64; 8] = [256.0, 512.0, 1024.0, 1536.0, 2048.0, 3072.0, 4096.0, 8192.0];
+
+struct Metrics {
+    registry: Registry,
+    logins: IntCounterVec,
+    login_failures: IntCounterVec,
+    logouts: IntCounterVec,
+    refreshes: IntCounterVec,
+    refresh_failures: IntCounterVec,
+    idp_requests: HistogramVec,
+    proxy_requests: HistogramVec,
+    cookie_size: HistogramVec,
+    key_usage: IntCounterVec,
+}
+
+impl Metrics {
+    fn new() -> Self {
+        let registry = Registry::new_custom(Some("token_handler".into()), None).unwrap();
+        let counter = |name: &str, help: &str, labels: &[&str]| {
+            let counter = IntCounterVec::new(Opts::new(name, help), labels).unwrap();
+            registry.register(Box::new(counter.clone())).unwrap();
+            counter
+        };
+        let histogram = |opts: HistogramOpts, labels: &[&str]| {
+            let histogram = HistogramVec::new(opts, labels).unwrap();
+            registry.register(Box::new(histogram.clone())).unwrap();
+            histogram
+        };
+        Metrics {
+            logins: counter("logins_total", "Completed logins", &["bridge"]),
+            login_failures: counter("login_failures_total", "Failed logins by reason", &["bridge", "reason"]),
+            logouts: counter("logouts_total", "Ended sessions by kind of logout", &["bridge", "kind"]),
+            refreshes: counter("token_refreshes_total", "Refreshed sessions", &["bridge"]),
+            refresh_failures: counter("token_refresh_failures_total", "Failed refreshes by reason", &["bridge", "reason"]),
+            idp_requests: histogram(
+                HistogramOpts::new("idp_request_duration_seconds", "Duration of requests to the IDP by endpoint"),
+                &["bridge", "endpoint"],
+            ),
+            proxy_requests: histogram(
+                HistogramOpts::new("proxy_request_duration_seconds", "Time until the response headers of proxied requests by status"),
+                &["bridge", "api", "status"],
+            ),
+            cookie_size: histogram(
+                HistogramOpts::new("cookie_size_bytes", "Size of the cookies set").buckets(COOKIE_SIZE_BUCKETS.into()),
+                &["bridge"],
+            ),
+            key_usage: counter("key_usage_total", "Uses of keys by key id and operation", &["key", "operation"]),
+            registry,
+        }
+    }
+}
+
+pub fn login(bridge: &str) {
+    METRICS.logins.with_label_values(&[bridge]).inc();
+}
+
+pub fn login_failed(bridge: &str, reason: &str) {
+    METRICS.login_failures.with_label_values(&[bridge, reason]).inc();
+}
+
+/// Counts a logout, which is `"redirect"`, `"silent"` or `"frontchannel"`
+pub fn logout(bridge: &str, kind: &str) {
+    METRICS.logouts.with_label_values(&[bridge, kind]).inc();
+}
+
+pub fn refresh(bridge: &str) {
+    METRICS.refreshes.with_label_values(&[bridge]).inc();
+}
+
+pub fn refresh_failed(bridge: &str, reason: &str) {
+    METRICS.refresh_failures.with_label_values(&[bridge, reason]).inc();
+}
+
+/// Times a request to an endpoint of the IDP, failed or not
+pub async fn idp<T>(bridge: &str, endpoint: &str, request: impl Future<Output = T>) -> T {
+    let start = Instant::now();
+    let result = request.await;
+    METRICS.idp_requests.with_label_values(&[bridge, endpoint]).observe(start.elapsed().as_secs_f64());
+    result
+}
+
+pub fn proxied(bridge: &str, api: &str, status: u16, start: Instant) {
+    METRICS.proxy_requests.with_label_values(&[bridge, api, &status.to_string()]).observe(start.elapsed().as_secs_f64());
+}
+
+pub fn cookie_size(bridge: &str, size: usize) {
+    METRICS.cookie_size.with_label_values(&[bridge]).observe(size as f64);
+}
+
+/// Counts a use of a key, i.e. an `"encrypt"` or `"decrypt"` operation
+pub fn key_used(key_id: &str, operation: &str) {
+    METRICS.key_usage.with_label_values(&[key_id, operation]).inc();
+}
+
+/// All metrics in the Prometheus text format
+pub fn render() -> Result<String, prometheus::Error> {
+    let mut buffer = Vec::new();
+    TextEncoder::new().encode(&METRICS.registry.gather(), &mut buffer)?;
+    Ok(String::from_utf8_lossy(&buffer).into_owned())
+}
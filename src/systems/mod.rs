@@ -1,3 +1,15 @@
+pub mod admin;
+pub mod body;
 pub mod cookies;
 pub mod crypto;
+pub mod csrf;
+pub mod headers;
+pub mod health;
+pub mod logging;
+pub mod metrics;
+pub mod readiness;
+pub mod session;
+pub mod streaming;
+pub mod telemetry;
 pub mod token;
+pub mod websocket;
@@ -0,0 +1,138 @@
+//! Readiness checks of the IDPs and backends the token handler depends on
+
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use futures_util::future::join_all;
+use serde_derive::{Deserialize, Serialize};
+use crate::components::config::{Api, Bridge, Config};
+use crate::components::route::join;
+use crate::components::spec::ReadinessCheck;
+use crate::components::types::OpenidConfiguration;
+
+/// The latest report and when it was made
+pub type Cache = tokio::sync::Mutex<Option<(Instant, Arc<Report>)>>;
+
+#[derive(Serialize)]
+pub struct Report {
+    pub ready: bool,
+    /// The checks which have to pass
+    pub gates: Vec<ReadinessCheck>,
+    pub bridges: BTreeMap<String, BridgeReport>,
+}
+
+#[derive(Serialize)]
+pub struct BridgeReport {
+    pub discovery: Check,
+    pub jwks: Check,
+    /// APIs with a `readiness_probe`
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub apis: BTreeMap<String, ApiReport>,
+}
+
+/// An API is up as long as one of its targets is
+#[derive(Serialize)]
+pub struct ApiReport {
+    pub up: bool,
+    pub targets: BTreeMap<String, Check>,
+}
+
+#[derive(Serialize)]
+pub struct Check {
+    pub up: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl Check {
+    fn up() -> Self {
+        Check { up: true, error: None }
+    }
+
+    fn down(error: impl Display) -> Self {
+        Check { up: false, error: Some(error.to_string()) }
+    }
+}
+
+#[derive(Deserialize)]
+struct Jwks {
+    keys: Vec<serde_json::Value>,
+}
+
+/// The latest report, unless it is older than `readiness.cache_ttl`; concurrent callers wait for the same checks
+pub async fn cached(config: &Config) -> Arc<Report> {
+    let mut cache = config.readiness_cache.lock().await;
+    let ttl = Duration::from_secs(config.readiness.cache_ttl as u64);
+    match *cache {
+        Some((made, ref report)) if made.elapsed() < ttl => report.clone(),
+        _ => {
+            let report = Arc::new(check(config).await);
+            *cache = Some((Instant::now(), report.clone()));
+            report
+        },
+    }
+}
+
+/// Runs all checks at once, each under the configured timeout
+pub async fn check(config: &Config) -> Report {
+    let timeout = Duration::from_secs(config.readiness.timeout as u64);
+    let bridges = join_all(config.bridges.values().map(|bridge| async move {
+        (bridge.id.clone(), check_bridge(bridge, timeout).await)
+    })).await;
+    let gates = config.readiness.gates.clone();
+    let ready = bridges.iter().all(|(_, bridge)| gates.iter().all(|gate| match gate {
+        ReadinessCheck::Discovery => bridge.discovery.up,
+        ReadinessCheck::Jwks => bridge.jwks.up,
+        ReadinessCheck::Upstreams => bridge.apis.values().all(|api| api.up),
+    }));
+    Report { ready, gates, bridges: bridges.into_iter().collect() }
+}
+
+async fn check_bridge(bridge: &Bridge, timeout: Duration) -> BridgeReport {
+    let apis = join_all(bridge.apis.values()
+        .filter_map(|api| Some((api, api.readiness_probe.as_deref()?)))
+        .map(|(api, path)| async move { (api.id.clone(), check_api(api, path, timeout).await) }));
+    let idp = async {
+        // the discovery document is cached once loaded, whereas the JWKS is fetched every time
+        match actix_web::rt::time::timeout(timeout, bridge.get_idp_configuration()).await {
+            Ok(Ok(configuration)) => (Check::up(), check_jwks(bridge, &configuration, timeout).await),
+            Ok(Err(e)) => (Check::down(e), Check::down("no discovery document")),
+            Err(_) => (Check::down("timeout"), Check::down("no discovery document")),
+        }
+    };
+    let ((discovery, jwks), apis) = futures_util::future::join(idp, apis).await;
+    BridgeReport { discovery, jwks, apis: apis.into_iter().collect() }
+}
+
+async fn check_jwks(bridge: &Bridge, configuration: &OpenidConfiguration, timeout: Duration) -> Check {
+    let Some(ref uri) = configuration.jwks_uri else { return Check::down("IDP has no jwks_uri") };
+    let response = bridge.http.reqwest.get(uri)
+        .timeout(timeout)
+        .send().await
+        .and_then(|response| response.error_for_status());
+    match response {
+        Ok(response) => match response.json::<Jwks>().await {
+            Ok(jwks) if jwks.keys.is_empty() => Check::down("JWKS has no keys"),
+            Ok(_) => Check::up(),
+            Err(e) => Check::down(e),
+        },
+        Err(e) => Check::down(e),
+    }
+}
+
+async fn check_api(api: &Api, path: &str, timeout: Duration) -> ApiReport {
+    let probes = api.upstream.targets.iter().map(|target| async move {
+        let response = api.http.reqwest.get(join(&target.url, path, None))
+            .timeout(timeout)
+            .send().await;
+        let check = match response {
+            Ok(response) if response.status().is_success() => Check::up(),
+            Ok(response) => Check::down(response.status()),
+            Err(e) => Check::down(e),
+        };
+        (target.url.to_string(), check)
+    });
+    let targets = join_all(probes).await.into_iter().collect::<BTreeMap<_, _>>();
+    ApiReport { up: targets.values().any(|target| target.up), targets }
+}
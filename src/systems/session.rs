@@ -0,0 +1,169 @@
+use actix_web::cookie::Cookie;
+use actix_web::cookie::time::Duration;
+use crate::components::config::Bridge;
+use crate::components::types::SessionCookie;
+use crate::error::{ApiError, Context};
+use crate::systems::cookies::create;
+
+/// Timestamps of sessions from before they were tracked, which count as unknown rather than as 1970
+const UNKNOWN: i64 = 0;
+
+/// Enforces the absolute and idle timeouts of a session, as far as its timestamps are known
+pub fn check(session: &SessionCookie, bridge: &Bridge, now: i64) -> Result<(), ApiError> {
+    if let Some(max_age) = bridge.session_max_age.filter(|_| session.created_at != UNKNOWN) {
+        if now - session.created_at > max_age as i64 {
+            return Err(ApiError::NotLoggedIn).context("Session expired");
+        }
+    }
+    if let Some(idle_timeout) = bridge.session_idle_timeout.filter(|_| session.last_seen != UNKNOWN) {
+        if now - session.last_seen > idle_timeout as i64 {
+            return Err(ApiError::NotLoggedIn).context("Session idle for too long");
+        }
+    }
+    Ok(())
+}
+
+/// Marks a session as used. Returns whether the cookie should be re-issued, which is rate-limited by the refresh
+/// interval to avoid setting a cookie on every call
+///
+/// Sessions with unknown timestamps get the current time instead, from which on their timeouts apply.
+pub fn touch(session: &mut SessionCookie, bridge: &Bridge, now: i64) -> bool {
+    if session.created_at == UNKNOWN || session.last_seen == UNKNOWN {
+        session.created_at = created_at(session, now);
+        session.last_seen = now;
+        return true;
+    }
+    let sliding = bridge.session_idle_timeout.is_some() || session.remember;
+    if !sliding || now - session.last_seen < bridge.session_refresh_interval as i64 {
+        return false;
+    }
+    session.last_seen = now;
+    true
+}
+
+/// When a session was created, counting sessions of unknown age from `now`
+pub fn created_at(session: &SessionCookie, now: i64) -> i64 {
+    match session.created_at {
+        UNKNOWN => now,
+        created_at => created_at,
+    }
+}
+
+/// Bakes a session into a cookie, which is persistent if the user asked to be remembered
+pub fn cookie(session: &SessionCookie, bridge: &Bridge, now: i64) -> Result<Cookie<'static>, ApiError> {
+    let mut cookie = create(session, bridge, bridge.cookie.same_site())?;
+    if let Some(max_age) = max_age(session, bridge, now) {
+        cookie.set_max_age(max_age);
+    }
+    Ok(cookie)
+}
+
+/// How long the cookies of a session last, if the user asked to be remembered; otherwise they end with the browser
+pub fn max_age(session: &SessionCookie, bridge: &Bridge, now: i64) -> Option<Duration> {
+    let max_age = bridge.persistent_session_max_age.filter(|_| session.remember)?;
+    let remaining = bridge.session_max_age
+        .map(|absolute| session.created_at + absolute as i64 - now)
+        .unwrap_or(i64::MAX);
+    Some(Duration::seconds(i64::min(max_age as i64, remaining)))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use crate::components::config::Config;
+    use crate::components::spec::Spec;
+    use super::*;
+
+    const NOW: i64 = 1_700_000_000;
+
+    fn bridge() -> Arc<Bridge> {
+        config_with_cookie("").bridges["b1"].clone()
+    }
+
+    fn config_with_cookie(cookie: &str) -> Arc<Config> {
+        let spec = hcl::from_str::<Spec>(&format!(r#"
+            key "1" {{
+              value = "TnVyIGVpbiBCZWlzcGllbCwgbmljaHQgYmVudXR6ZW4="
+              active = true
+            }}
+            bridge "b1" {{
+              idp = "http://idp"
+              client = "client"
+              secret = "secret"
+              session_max_age = 3600
+              session_idle_timeout = 600
+              session_refresh_interval = 60
+              cookie {{
+                {cookie}
+              }}
+              api "api" {{
+                backend = "http://backend"
+              }}
+            }}
+        "#)).unwrap();
+        Arc::<Config>::try_from(&spec).unwrap()
+    }
+
+    fn session(created_at: i64, last_seen: i64) -> SessionCookie {
+        SessionCookie {
+            access_token: String::new(),
+            refresh_token: String::new(),
+            id_token: String::new(),
+            sid: None,
+            created_at,
+            last_seen,
+            remember: false,
+            csrf_token: None,
+        }
+    }
+
+    #[test]
+    fn timeouts() {
+        let bridge = bridge();
+        assert!(check(&session(NOW - 100, NOW - 100), &bridge, NOW).is_ok());
+        assert!(check(&session(NOW - 3601, NOW - 10), &bridge, NOW).is_err());
+        assert!(check(&session(NOW - 1000, NOW - 601), &bridge, NOW).is_err());
+    }
+
+    #[test]
+    fn unknown_timestamps_dont_expire() {
+        let bridge = bridge();
+        assert!(check(&session(0, 0), &bridge, NOW).is_ok());
+        assert!(check(&session(0, NOW - 601), &bridge, NOW).is_err());
+        assert!(check(&session(NOW - 3601, 0), &bridge, NOW).is_err());
+    }
+
+    #[test]
+    fn touch_stamps_unknown_timestamps() {
+        let bridge = bridge();
+        let mut unknown = session(0, 0);
+        assert!(touch(&mut unknown, &bridge, NOW));
+        assert_eq!((unknown.created_at, unknown.last_seen), (NOW, NOW));
+        assert!(check(&unknown, &bridge, NOW + 3600).is_err());
+    }
+
+    #[test]
+    fn touch_is_rate_limited() {
+        let bridge = bridge();
+        let mut recent = session(NOW - 100, NOW - 30);
+        assert!(!touch(&mut recent, &bridge, NOW));
+        assert_eq!(recent.last_seen, NOW - 30);
+        let mut stale = session(NOW - 100, NOW - 60);
+        assert!(touch(&mut stale, &bridge, NOW));
+        assert_eq!((stale.created_at, stale.last_seen), (NOW - 100, NOW));
+    }
+
+    #[test]
+    fn cookies_are_secure() {
+        let baked = |spec: &str| {
+            let config = config_with_cookie(spec);
+            cookie(&session(NOW, NOW), &config.bridges["b1"], NOW).unwrap()
+        };
+        let plain = baked("");
+        assert_eq!((plain.name(), plain.secure(), plain.path(), plain.domain()), ("bff-session", Some(true), Some("/bridge/b1"), None));
+        let host = baked("host_prefix = true");
+        assert_eq!((host.name(), host.secure(), host.path(), host.domain()), ("__Host-bff-session", Some(true), Some("/"), None));
+        let shared = baked("domain = \"example.com\"");
+        assert_eq!((shared.secure(), shared.domain()), (Some(true), Some("example.com")));
+    }
+}
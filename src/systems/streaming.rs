@@ -0,0 +1,130 @@
+//! Relaying of response bodies, which may be streams like Server-Sent Events
+
+use std::pin::Pin;
+use std::time::Duration;
+use actix_web::rt::time::{sleep_until, Instant};
+use actix_web::web::Bytes;
+use futures_util::{Stream, StreamExt};
+use futures_util::stream::unfold;
+use log::{debug, warn};
+use crate::components::spec::StreamingSpec;
+use crate::components::upstream::Lease;
+use crate::error::ApiError;
+use crate::systems::logging;
+
+const HEARTBEAT: &[u8] = b": heartbeat\n";
+
+struct Relay {
+    chunks: Pin<Box<dyn Stream<Item = Result<Bytes, reqwest::Error>>>>,
+    event_stream: bool,
+    deadline: Option<Instant>,
+    idle_timeout: Option<Duration>,
+    heartbeat: Option<Duration>,
+    last_received: Instant,
+    last_sent: Instant,
+    /// Whether the data sent so far ends with a complete line, so that a heartbeat can't tear an event apart
+    line_start: bool,
+    finished: bool,
+    label: String,
+    /// Bodies are relayed after the handler returned, outside of the request's scope
+    request_id: Option<String>,
+    /// the target counts as busy until the response is through
+    _lease: Lease,
+}
+
+/// Relays the body of a backend's response chunk by chunk as it arrives, subject to the API's streaming settings
+///
+/// Event streams simply end on timeouts, since clients reconnect anyway, whereas other responses are aborted so that
+/// clients can't mistake them for complete. If the client goes away, the request to the backend is cancelled.
+pub fn relay(
+    chunks: impl Stream<Item = Result<Bytes, reqwest::Error>> + 'static,
+    spec: Option<&StreamingSpec>,
+    event_stream: bool,
+    label: String,
+    lease: Lease,
+) -> impl Stream<Item = Result<Bytes, ApiError>> {
+    let now = Instant::now();
+    let seconds = |value: Option<u32>| value.map(|value| Duration::from_secs(value as u64));
+    let relay = Relay {
+        chunks: Box::pin(chunks),
+        event_stream,
+        deadline: seconds(spec.and_then(|spec| spec.max_duration)).map(|duration| now + duration),
+        idle_timeout: seconds(spec.and_then(|spec| spec.idle_timeout)),
+        heartbeat: seconds(spec.and_then(|spec| spec.heartbeat)).filter(|_| event_stream),
+        last_received: now,
+        last_sent: now,
+        line_start: true,
+        finished: false,
+        label,
+        request_id: logging::request_id(),
+        _lease: lease,
+    };
+    unfold(relay, |mut relay| async move {
+        if relay.finished {
+            return None;
+        }
+        let idle_at = relay.idle_timeout.map(|timeout| relay.last_received + timeout);
+        let heartbeat_at = relay.heartbeat.filter(|_| relay.line_start).map(|interval| relay.last_sent + interval);
+        let wake = [relay.deadline, idle_at, heartbeat_at].into_iter().flatten().min();
+        let timer = async {
+            match wake {
+                Some(wake) => sleep_until(wake).await,
+                None => std::future::pending().await,
+            }
+        };
+        tokio::select! {
+            chunk = relay.chunks.next() => match chunk {
+                Some(Ok(bytes)) => {
+                    relay.last_received = Instant::now();
+                    relay.last_sent = relay.last_received;
+                    if let Some(last) = bytes.last() {
+                        relay.line_start = *last == b'\n';
+                    }
+                    Some((Ok(bytes), relay))
+                },
+                Some(Err(e)) => {
+                    // the client sees the response break off as well, rather than end as if it were complete
+                    relay.log(|label| warn!("[{label}] response from backend broke off: {e}"));
+                    relay.finished = true;
+                    Some((Err(e.into()), relay))
+                },
+                None => {
+                    relay.finished = true;
+                    drop(relay);
+                    None
+                },
+            },
+            _ = timer => {
+                let now = Instant::now();
+                let reason = match (relay.deadline, idle_at) {
+                    (Some(deadline), _) if deadline <= now => "maximum duration reached",
+                    (_, Some(idle_at)) if idle_at <= now => "idle timeout",
+                    _ => {
+                        relay.last_sent = now;
+                        return Some((Ok(Bytes::from_static(HEARTBEAT)), relay));
+                    },
+                };
+                relay.log(|label| debug!("[{label}] ending response: {reason}"));
+                relay.finished = true;
+                match relay.event_stream {
+                    true => None,
+                    false => Some((Err(ApiError::Timeout.context(reason)), relay)),
+                }
+            },
+        }
+    })
+}
+
+impl Relay {
+    fn log(&self, f: impl FnOnce(&str)) {
+        logging::in_request(self.request_id.clone(), || f(&self.label));
+    }
+}
+
+impl Drop for Relay {
+    fn drop(&mut self) {
+        if !self.finished {
+            self.log(|label| debug!("[{label}] client went away, cancelling request to backend"));
+        }
+    }
+}
@@ -0,0 +1,153 @@
+//! Distributed tracing with W3C trace context, exported by OTLP
+
+use std::future::Future;
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse};
+use actix_web::http::StatusCode;
+use futures_util::FutureExt as _;
+use opentelemetry::{global, Context, KeyValue};
+use opentelemetry::global::BoxedSpan;
+use opentelemetry::propagation::{Extractor, Injector};
+use opentelemetry::trace::{FutureExt, SpanKind, SpanRef, Status, TraceContextExt, Tracer};
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use opentelemetry_sdk::trace::{config, Sampler};
+use opentelemetry_sdk::{runtime, Resource};
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use crate::components::spec::TracingSpec;
+use crate::error::ConfigError;
+
+const TRACER: &str = "token-handler";
+
+/// Propagates trace context from now on, and exports spans if configured
+///
+/// Without an exporter, the trace context of clients is still passed on to backends and the IDP.
+pub fn init(spec: Option<&TracingSpec>) -> Result<(), ConfigError> {
+    global::set_text_map_propagator(TraceContextPropagator::new());
+    let Some(spec) = spec else { return Ok(()) };
+    opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(opentelemetry_otlp::new_exporter().http().with_endpoint(spec.endpoint.trim_end_matches('/')))
+        .with_trace_config(config()
+            // clients which started a trace already decided whether it is recorded
+            .with_sampler(Sampler::ParentBased(Box::new(Sampler::TraceIdRatioBased(spec.sample_ratio))))
+            .with_resource(Resource::new([KeyValue::new("service.name", spec.service_name.clone())])))
+        .install_batch(runtime::TokioCurrentThread)?;
+    Ok(())
+}
+
+/// Exports the spans which are still pending
+pub fn shutdown() {
+    global::shutdown_tracer_provider();
+}
+
+/// Traces the handling of a request, as part of the client's trace if it sent one
+pub fn trace<S, B>(req: ServiceRequest, srv: &S) -> impl Future<Output = Result<ServiceResponse<B>, actix_web::Error>>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error>,
+{
+    let parent = global::get_text_map_propagator(|propagator| propagator.extract(&Extract(req.headers())));
+    let name = match req.match_pattern() {
+        Some(pattern) => format!("{} {pattern}", req.method()),
+        None => req.method().to_string(),
+    };
+    let tracer = global::tracer(TRACER);
+    let span = tracer.span_builder(name)
+        .with_kind(SpanKind::Server)
+        .with_attributes(vec![
+            KeyValue::new("http.request.method", req.method().to_string()),
+            KeyValue::new("url.path", req.path().to_string()),
+        ])
+        .start_with_context(&tracer, &parent);
+    let cx = parent.with_span(span);
+    srv.call(req).with_context(cx.clone()).map(move |res| {
+        let span = cx.span();
+        match res {
+            Ok(ref res) => record(&span, res.status(), false),
+            Err(ref e) => span.set_status(Status::error(e.to_string())),
+        }
+        span.end();
+        res
+    })
+}
+
+/// Starts the span of a request to a backend or the IDP, and adds its trace context to the request
+pub fn outgoing(request: &mut reqwest::Request, resend_count: u16) -> Context {
+    let url = request.url();
+    let mut attributes = vec![
+        KeyValue::new("http.request.method", request.method().to_string()),
+        // queries may well contain secrets
+        KeyValue::new("url.full", format!("{}{}", url.origin().ascii_serialization(), url.path())),
+    ];
+    if let Some(host) = url.host_str() {
+        attributes.push(KeyValue::new("server.address", host.to_string()));
+    }
+    if let Some(port) = url.port_or_known_default() {
+        attributes.push(KeyValue::new("server.port", port as i64));
+    }
+    if resend_count > 0 {
+        attributes.push(KeyValue::new("http.request.resend_count", resend_count as i64));
+    }
+    let tracer = global::tracer(TRACER);
+    let span = tracer.span_builder(request.method().to_string())
+        .with_kind(SpanKind::Client)
+        .with_attributes(attributes)
+        .start(&tracer);
+    let cx = Context::current_with_span(span);
+    inject(&cx, request.headers_mut());
+    cx
+}
+
+/// Ends the span of a request to a backend or the IDP with its outcome
+pub fn finish(cx: &Context, outcome: Result<StatusCode, String>) {
+    let span = cx.span();
+    match outcome {
+        Ok(status) => record(&span, status, true),
+        Err(e) => span.set_status(Status::error(e)),
+    }
+    span.end();
+}
+
+/// Adds the current trace context to the headers of a request
+pub fn inject_current(headers: &mut HeaderMap) {
+    inject(&Context::current(), headers);
+}
+
+/// Starts a span of some work within the current trace, which ends when it is dropped
+pub fn span(name: &'static str, attributes: Vec<KeyValue>) -> BoxedSpan {
+    let tracer = global::tracer(TRACER);
+    tracer.span_builder(name).with_attributes(attributes).start(&tracer)
+}
+
+/// Servers only fail on 5xx, whereas clients also on 4xx, cf. the semantic conventions for HTTP spans
+fn record(span: &SpanRef, status: StatusCode, client: bool) {
+    span.set_attribute(KeyValue::new("http.response.status_code", status.as_u16() as i64));
+    if status.is_server_error() || (client && status.is_client_error()) {
+        span.set_status(Status::error(status.to_string()));
+    }
+}
+
+fn inject(cx: &Context, headers: &mut HeaderMap) {
+    global::get_text_map_propagator(|propagator| propagator.inject_context(cx, &mut Inject(headers)));
+}
+
+struct Extract<'a>(&'a actix_web::http::header::HeaderMap);
+
+impl Extractor for Extract<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|value| value.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|name| name.as_str()).collect()
+    }
+}
+
+struct Inject<'a>(&'a mut HeaderMap);
+
+impl Injector for Inject<'_> {
+    fn set(&mut self, key: &str, value: String) {
+        if let (Ok(name), Ok(value)) = (HeaderName::from_bytes(key.as_bytes()), HeaderValue::from_str(&value)) {
+            self.0.insert(name, value);
+        }
+    }
+}
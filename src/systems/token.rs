@@ -1,25 +1,89 @@
 use base64::{Engine as _, engine::{general_purpose}};
-use reqwest::header;
+use reqwest::{header, Method};
 use serde::Deserialize;
+use serde_json::Value;
 use crate::components::config::Bridge;
-use crate::components::types::{ClientAuth, TokenRequest, TokenRequestDetails, TokenResponse};
+use crate::components::types::{ClientAuth, ClientTokenResponse, RevocationRequest, SessionCookie, TokenRequest, TokenRequestDetails, TokenResponse};
 use crate::error::{ApiError, Context};
+use crate::systems::metrics;
 
 /// Helper method for JWTs
 pub fn claims<T: for<'a> Deserialize<'a>>(token: &str) -> Result<T, ApiError> {
-    let base64 = token.as_bytes().split(|c| *c == 46).skip(1).next().ok_or(ApiError::BadGateway)?;
+    let base64 = token.as_bytes().split(|c| *c == 46).nth(1).ok_or(ApiError::BadGateway)?;
     let bytes = general_purpose::URL_SAFE_NO_PAD.decode(base64)?;
     Ok(serde_json::from_slice::<T>(&bytes)?)
 }
 
+/// Looks up a claim by its dot-separated path, e.g. `realm_access.roles`
+pub fn claim<'a>(claims: &'a Value, path: &str) -> Option<&'a Value> {
+    path.split('.').try_fold(claims, |value, key| value.get(key))
+}
+
+/// Renders a claim for use in a header: scalars as they are, arrays of scalars comma-separated
+pub fn claim_to_string(value: &Value) -> Option<String> {
+    match value {
+        Value::String(s) => Some(s.clone()),
+        Value::Number(n) => Some(n.to_string()),
+        Value::Bool(b) => Some(b.to_string()),
+        Value::Array(values) => values.iter()
+            .map(|v| match v {
+                Value::Array(_) | Value::Object(_) => None,
+                v => claim_to_string(v),
+            })
+            .collect::<Option<Vec<_>>>()
+            .map(|v| v.join(",")),
+        Value::Null | Value::Object(_) => None,
+    }
+}
+
 /// Retrieve a set of tokens from the IDP
 pub async fn retrieve_token<'a>(bridge: &Bridge, details: TokenRequestDetails<'a>) -> Result<TokenResponse, ApiError> {
-    let response = bridge.config()?.reqwest.post(&bridge.get_idp_configuration().await?.token_endpoint)
+    let refresh = matches!(details, TokenRequestDetails::RefreshToken { .. });
+    let result = request_token(bridge, details).await;
+    match (refresh, &result) {
+        (true, Ok(_)) => metrics::refresh(&bridge.id),
+        (true, Err(_)) => metrics::refresh_failed(&bridge.id, "idp_error"),
+        (false, _) => {},
+    }
+    result
+}
+
+/// Retrieve a token for the client itself by the client credentials grant
+pub async fn retrieve_client_token(bridge: &Bridge, scope: Option<&str>) -> Result<ClientTokenResponse, ApiError> {
+    request_token(bridge, TokenRequestDetails::ClientCredentials { scope }).await
+}
+
+async fn request_token<'a, T: for<'b> Deserialize<'b>>(bridge: &Bridge, details: TokenRequestDetails<'a>) -> Result<T, ApiError> {
+    let endpoint = bridge.get_idp_configuration().await?.token_endpoint.clone();
+    let body = serde_urlencoded::to_string(TokenRequest { auth: ClientAuth::new(bridge), details })?;
+    let response = metrics::idp(&bridge.id, "token", bridge.http.execute(&bridge.label()?, Method::POST, || bridge.http.reqwest.post(&endpoint)
         .header(header::CONTENT_TYPE, mime::APPLICATION_WWW_FORM_URLENCODED.as_ref())
-        .body(serde_urlencoded::to_string(TokenRequest { auth: ClientAuth::new(&bridge), details })?)
-        .send()
+        .body(body.clone())))
         .await?;
     let response = response.bytes().await?;
-    Ok(serde_json::from_slice(response.as_ref())
-        .context(String::from_utf8_lossy(response.as_ref()).to_string())?)
+    serde_json::from_slice(response.as_ref())
+        .context(String::from_utf8_lossy(response.as_ref()).to_string())
+}
+
+/// Revoke the tokens of a session at the IDP, cf. RFC 7009
+pub async fn revoke_tokens(bridge: &Bridge, session: &SessionCookie) -> Result<(), ApiError> {
+    let Some(endpoint) = bridge.get_idp_configuration().await?.revocation_endpoint.clone() else {
+        return Err(ApiError::BadGateway).context("IDP has no revocation endpoint");
+    };
+    let mut tokens = vec![(session.refresh_token.as_str(), "refresh_token")];
+    if bridge.revoke_access_token {
+        tokens.push((session.access_token.as_str(), "access_token"));
+    }
+    for (token, token_type_hint) in tokens {
+        let body = serde_urlencoded::to_string(RevocationRequest { auth: ClientAuth::new(bridge), token, token_type_hint })?;
+        let response = metrics::idp(&bridge.id, "revocation", bridge.http.execute(&bridge.label()?, Method::POST, || bridge.http.reqwest.post(&endpoint)
+            .header(header::CONTENT_TYPE, mime::APPLICATION_WWW_FORM_URLENCODED.as_ref())
+            .body(body.clone())))
+            .await
+            .context("posting token revocation to IDP")?;
+        if !response.status().is_success() {
+            return Err(ApiError::BadGateway).context(format!("IDP refused to revoke {token_type_hint}: {}", response.status()));
+        }
+    }
+    Ok(())
 }
@@ -0,0 +1,157 @@
+//! Proxying of WebSocket connections
+
+use std::time::Duration;
+use actix_web::{HttpRequest, HttpResponse, web};
+use actix_ws::{AggregatedMessage, CloseCode, CloseReason, Session};
+use futures_util::{SinkExt, StreamExt};
+use log::debug;
+use reqwest::header::{self, HeaderMap};
+use tokio::net::TcpStream;
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::protocol::CloseFrame;
+use url::Url;
+use crate::components::config::Api;
+use crate::components::upstream::Lease;
+use crate::error::{ApiError, Context};
+use crate::systems::{logging, telemetry};
+
+type Backend = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+/// Why a relayed connection ended
+enum End {
+    Client(Option<CloseReason>),
+    Backend(Option<CloseFrame<'static>>),
+    Lifetime,
+    Broken,
+}
+
+/// Whether a request asks to be upgraded to a WebSocket
+pub fn is_upgrade(req: &HttpRequest) -> bool {
+    req.headers().get(header::UPGRADE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.split(',').any(|protocol| protocol.trim().eq_ignore_ascii_case("websocket")))
+}
+
+/// Checks the client's handshake, then opens a WebSocket to the backend at `url` and, once the backend accepted it, the
+/// client's one, relaying messages between them until either side closes or `lifetime` is over
+pub async fn proxy(
+    req: &HttpRequest,
+    payload: web::Payload,
+    api: &Api,
+    url: &Url,
+    headers: HeaderMap,
+    lifetime: Option<Duration>,
+    lease: Lease,
+) -> Result<HttpResponse, ApiError> {
+    // malformed upgrades never reach the backend, let alone with the user's token
+    let (mut client_response, session, messages) = actix_ws::handle(req, payload)
+        .map_err(|e| ApiError::BadRequest.context(e.to_string()))?;
+    let mut url = url.clone();
+    let scheme = match url.scheme() {
+        "https" => "wss",
+        _ => "ws",
+    };
+    url.set_scheme(scheme).map_err(|_| ApiError::Internal).context("switching to WebSocket scheme")?;
+    let mut request = url.as_str().into_client_request()
+        .map_err(|e| ApiError::BadGateway.context(e.to_string()))?;
+    request.headers_mut().extend(headers);
+    telemetry::inject_current(request.headers_mut());
+    logging::inject_request_id(request.headers_mut());
+    if let Some(protocol) = req.headers().get(header::SEC_WEBSOCKET_PROTOCOL) {
+        request.headers_mut().insert(header::SEC_WEBSOCKET_PROTOCOL, protocol.clone());
+    }
+    // connecting and the handshake are bounded by the API's timeouts together
+    let timeout = [api.timeouts.connect, api.timeouts.response_headers].into_iter().flatten().map(u64::from).sum();
+    let connect = tokio_tungstenite::connect_async(request);
+    let (backend, response) = match timeout {
+        0 => connect.await,
+        timeout => actix_web::rt::time::timeout(Duration::from_secs(timeout), connect).await
+            .map_err(|_| ApiError::Timeout.context("waiting for WebSocket handshake"))?,
+    }.map_err(|e| ApiError::BadGateway.context(format!("WebSocket handshake with backend failed: {e}")))?;
+
+    if let Some(protocol) = response.headers().get(header::SEC_WEBSOCKET_PROTOCOL) {
+        client_response.headers_mut().insert(header::SEC_WEBSOCKET_PROTOCOL, protocol.clone());
+    }
+    let label = api.label()?;
+    // the connection outlives the handler, and with it the request's scope
+    let request_id = logging::request_id();
+    actix_web::rt::spawn(async move {
+        let end = relay(session, messages.aggregate_continuations(), backend, lifetime).await;
+        logging::in_request(request_id, || debug!("[{}] WebSocket to {} closed: {}", label, lease.url(), match end {
+            End::Client(_) => "by client",
+            End::Backend(_) => "by backend",
+            End::Lifetime => "maximum lifetime reached",
+            End::Broken => "connection lost",
+        }));
+    });
+    Ok(client_response)
+}
+
+async fn relay(mut session: Session, mut messages: actix_ws::AggregatedMessageStream, backend: Backend, lifetime: Option<Duration>) -> End {
+    let (mut sink, mut stream) = backend.split();
+    let expiry = async {
+        match lifetime {
+            Some(lifetime) => actix_web::rt::time::sleep(lifetime).await,
+            None => std::future::pending().await,
+        }
+    };
+    tokio::pin!(expiry);
+    let end = loop {
+        tokio::select! {
+            message = messages.recv() => {
+                let message = match message {
+                    Some(Ok(AggregatedMessage::Text(text))) => Message::Text(text.to_string()),
+                    Some(Ok(AggregatedMessage::Binary(bytes))) => Message::Binary(bytes.to_vec()),
+                    Some(Ok(AggregatedMessage::Ping(bytes))) => Message::Ping(bytes.to_vec()),
+                    Some(Ok(AggregatedMessage::Pong(bytes))) => Message::Pong(bytes.to_vec()),
+                    Some(Ok(AggregatedMessage::Close(reason))) => break End::Client(reason),
+                    Some(Err(_)) | None => break End::Broken,
+                };
+                if sink.send(message).await.is_err() {
+                    break End::Broken;
+                }
+            },
+            message = stream.next() => {
+                let sent = match message {
+                    Some(Ok(Message::Text(text))) => session.text(text).await,
+                    Some(Ok(Message::Binary(bytes))) => session.binary(bytes).await,
+                    Some(Ok(Message::Ping(bytes))) => session.ping(&bytes).await,
+                    Some(Ok(Message::Pong(bytes))) => session.pong(&bytes).await,
+                    Some(Ok(Message::Close(frame))) => break End::Backend(frame.map(CloseFrame::into_owned)),
+                    Some(Ok(Message::Frame(_))) => Ok(()),
+                    Some(Err(_)) | None => break End::Broken,
+                };
+                if sent.is_err() {
+                    break End::Broken;
+                }
+            },
+            _ = &mut expiry => break End::Lifetime,
+        }
+    };
+    let (to_client, to_backend) = match end {
+        End::Client(ref reason) => (reason.clone(), reason.as_ref().map(frame)),
+        End::Backend(ref frame) => (frame.as_ref().map(reason), None),
+        End::Lifetime => {
+            let reason = CloseReason { code: CloseCode::Away, description: Some("maximum lifetime reached".into()) };
+            (Some(reason.clone()), Some(frame(&reason)))
+        },
+        End::Broken => (None, None),
+    };
+    let _ = session.close(to_client).await;
+    // the backend's close frame has already been answered
+    if !matches!(end, End::Backend(_)) {
+        let _ = sink.send(Message::Close(to_backend)).await;
+    }
+    let _ = sink.close().await;
+    end
+}
+
+fn frame(reason: &CloseReason) -> CloseFrame<'static> {
+    CloseFrame { code: u16::from(reason.code).into(), reason: reason.description.clone().unwrap_or_default().into() }
+}
+
+fn reason(frame: &CloseFrame) -> CloseReason {
+    CloseReason { code: u16::from(frame.code).into(), description: Some(frame.reason.to_string()).filter(|r| !r.is_empty()) }
+}
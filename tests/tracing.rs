@@ -0,0 +1,143 @@
+//! Traces of proxied requests, exported to a stand-in collector
+
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::process::{Child, Command, Stdio};
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const TRACE_ID: &str = "4bf92f3577b34da6a3ce929d0e0e4736";
+
+/// A request as received by a stand-in: its request line and headers, with lowercase names
+struct Received {
+    line: String,
+    headers: Vec<(String, String)>,
+}
+
+impl Received {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|(n, _)| n == name).map(|(_, v)| v.as_str())
+    }
+}
+
+/// Answers every request with an empty 200 and passes it on
+fn stand_in() -> (u16, Receiver<Received>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let Ok(stream) = stream else { continue };
+            let tx = tx.clone();
+            thread::spawn(move || serve(stream, tx));
+        }
+    });
+    (port, rx)
+}
+
+fn serve(stream: TcpStream, tx: mpsc::Sender<Received>) {
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    let mut stream = stream;
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line).unwrap_or(0) == 0 {
+            return;
+        }
+        let mut headers = Vec::new();
+        loop {
+            let mut header = String::new();
+            reader.read_line(&mut header).unwrap();
+            match header.trim_end().split_once(':') {
+                Some((name, value)) => headers.push((name.to_lowercase(), value.trim().to_string())),
+                None => break,
+            }
+        }
+        let received = Received { line: line.trim_end().to_string(), headers };
+        let length = received.header("content-length").and_then(|l| l.parse().ok()).unwrap_or(0);
+        reader.by_ref().take(length).read_to_end(&mut Vec::new()).unwrap();
+        let _ = tx.send(received);
+        stream.write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\n\r\n").unwrap();
+    }
+}
+
+fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port()
+}
+
+struct TokenHandler(Child);
+
+impl Drop for TokenHandler {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+fn start(config: &str) -> (TokenHandler, u16) {
+    let port = free_port();
+    let file = std::env::temp_dir().join(format!("token-handler-tracing-{}.hcl", std::process::id()));
+    std::fs::write(&file, format!("port = {port}\n{config}")).unwrap();
+    let mut command = Command::new(env!("CARGO_BIN_EXE_token-handler"));
+    command.arg("-f").arg(&file).stdout(Stdio::null()).stderr(Stdio::null());
+    for (name, _) in std::env::vars().filter(|(name, _)| name.starts_with("OTEL_")) {
+        command.env_remove(name);
+    }
+    let token_handler = TokenHandler(command.spawn().unwrap());
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while TcpStream::connect(("127.0.0.1", port)).is_err() {
+        assert!(Instant::now() < deadline, "token handler didn't start listening");
+        thread::sleep(Duration::from_millis(50));
+    }
+    (token_handler, port)
+}
+
+/// Sends a GET with a trace context and returns the status line of the response
+fn get(port: u16, path: &str, traceparent: &str) -> String {
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    write!(stream, "GET {path} HTTP/1.1\r\nhost: localhost:{port}\r\ntraceparent: {traceparent}\r\nconnection: close\r\n\r\n").unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    response.lines().next().unwrap_or_default().to_string()
+}
+
+#[test]
+fn proxied_requests_are_traced() {
+    let (backend, backend_requests) = stand_in();
+    let (collector, exports) = stand_in();
+    let (_token_handler, port) = start(&format!(r#"
+        tracing {{
+          endpoint = "http://127.0.0.1:{collector}"
+        }}
+        key "1" {{
+          value = "TnVyIGVpbiBCZWlzcGllbCwgbmljaHQgYmVudXR6ZW4="
+          active = true
+        }}
+        bridge "b1" {{
+          idp = "http://127.0.0.1:1"
+          client = "client"
+          secret = "secret"
+          api "anon" {{
+            auth = "none"
+            backend = "http://127.0.0.1:{backend}/api"
+            headers = [ "accept" ]
+          }}
+        }}
+    "#));
+
+    let status = get(port, "/bridge/b1/proxy/anon/items", &format!("00-{TRACE_ID}-00f067aa0ba902b7-01"));
+    assert!(status.starts_with("HTTP/1.1 200"), "{status}");
+
+    let request = backend_requests.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert_eq!(request.line, "GET /api/items HTTP/1.1");
+    let traceparent = request.header("traceparent").expect("backend didn't get a traceparent");
+    let parts = traceparent.split('-').collect::<Vec<_>>();
+    assert_eq!(parts.len(), 4, "{traceparent}");
+    assert_eq!(parts[1], TRACE_ID, "backend request isn't part of the client's trace");
+    assert_ne!(parts[2], "00f067aa0ba902b7", "backend request isn't a span of its own");
+
+    // spans are exported in batches, every five seconds
+    let export = exports.recv_timeout(Duration::from_secs(15)).expect("no traces exported");
+    assert_eq!(export.line, "POST /v1/traces HTTP/1.1");
+    assert_eq!(export.header("content-type"), Some("application/x-protobuf"));
+}